@@ -1,6 +1,49 @@
 use serde::{Deserialize, Serialize};
 
-pub const VERSION: u8 = 5;
+pub const VERSION: u8 = 24;
+
+/// Maximum number of bytes which can be received from a bus in a single request
+pub const MAX_READ_LENGTH: usize = 60;
+
+/// Maximum number of bytes written to a bus in a single request, longer writes have to be split
+pub const MAX_WRITE_LENGTH: usize = 48;
+
+/// Maximum number of bytes of the encoded requests carried by a `Batch`
+pub const MAX_BATCH_LENGTH: usize = 60;
+
+/// Longest wait of a single `Delay` request, the target does not receive requests meanwhile
+pub const MAX_DELAY_US: u32 = 100_000;
+
+/// Longest wait for the edges of the signal measured by a `Measure` request
+pub const MAX_MEASURE_TIMEOUT_MS: u16 = 1_000;
+
+/// Time after which the target discards a partially received request if no further bytes arrive
+pub const INTERFRAME_TIMEOUT_MS: u32 = 50;
+
+/// Addresses probed by an `I2CScan` request, all others are reserved
+pub const I2C_SCAN_ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+/// Address of an I2C device
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum I2CAddress {
+    SevenBit(u8),
+    /// Extended address of up to 0x3ff
+    TenBit(u16),
+}
+
+/// Like in embedded-hal, a `u8` is a 7-bit address
+impl From<u8> for I2CAddress {
+    fn from(address: u8) -> Self {
+        I2CAddress::SevenBit(address)
+    }
+}
+
+/// Like in embedded-hal, a `u16` is a 10-bit address
+impl From<u16> for I2CAddress {
+    fn from(address: u16) -> Self {
+        I2CAddress::TenBit(address)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum Request<'p> {
@@ -21,17 +64,21 @@ pub enum Request<'p> {
     GpioToggle {
         pin: &'p str,
     },
+    /// Set up an I2C bus with an SCL frequency of at most `speed` Hz, replied to by `Frequency`
     I2CInit {
+        ident: &'p str,
         scl_pin: &'p str,
         sda_pin: &'p str,
         speed: u32,
     },
     I2CWrite {
         ident: &'p str,
-        address: u8,
+        address: I2CAddress,
         data: &'p [u8],
     },
+    /// Set up an SPI bus with an SCK frequency of at most `speed` Hz, replied to by `Frequency`
     SPIInit {
+        ident: &'p str,
         sck_pin: &'p str,
         miso_pin: &'p str,
         mosi_pin: &'p str,
@@ -41,6 +88,174 @@ pub enum Request<'p> {
         ident: &'p str,
         data: &'p [u8],
     },
+    /// Set the session identifier, the target starts without one after a reset
+    Session {
+        id: u32,
+    },
+    I2CRead {
+        ident: &'p str,
+        address: I2CAddress,
+        len: u8,
+    },
+    /// Write `data` and read `len` bytes with a repeated start in between
+    I2CWriteRead {
+        ident: &'p str,
+        address: I2CAddress,
+        data: &'p [u8],
+        len: u8,
+    },
+    /// Clock out `data` and return the bytes clocked in at the same time
+    SPITransfer {
+        ident: &'p str,
+        data: &'p [u8],
+    },
+    /// Configure `cs_pin` as deasserted chip select for a device on the SPI bus `ident`
+    SPIDeviceInit {
+        ident: &'p str,
+        cs_pin: &'p str,
+    },
+    /// Like `SPITransfer` but with `cs_pin` asserted and the bus in `mode` (0 to 3) meanwhile
+    SPITransaction {
+        ident: &'p str,
+        cs_pin: &'p str,
+        mode: u8,
+        data: &'p [u8],
+    },
+    /// Probe all addresses in `I2C_SCAN_ADDRESSES`, the reply contains a bitmap of the
+    /// acknowledging ones with address `n` in bit `n % 8` of byte `n / 8`
+    I2CScan {
+        ident: &'p str,
+    },
+    /// Clock a stuck I2C bus until devices release SDA and set up the controller afresh
+    I2CRecover {
+        ident: &'p str,
+    },
+    /// Act as I2C target responding to the 7-bit `address` with a register file, the first
+    /// byte written by the controller selects the register accessed by following bytes
+    I2CTargetInit {
+        ident: &'p str,
+        scl_pin: &'p str,
+        sda_pin: &'p str,
+        address: u8,
+    },
+    /// Fill the register file of a target starting at `register`
+    I2CTargetSet {
+        ident: &'p str,
+        register: u8,
+        data: &'p [u8],
+    },
+    /// Fetch the writes of the controller to a target since the last poll, the reply holds a
+    /// record per write made of the first register, the number of bytes and the bytes written
+    I2CTargetPoll {
+        ident: &'p str,
+    },
+    /// Act as SPI target selected by `cs_pin` in SPI `mode`, capturing the bytes of every frame
+    SPITargetInit {
+        ident: &'p str,
+        sck_pin: &'p str,
+        miso_pin: &'p str,
+        mosi_pin: &'p str,
+        cs_pin: &'p str,
+        mode: u8,
+    },
+    /// Answer the following frames of a target with `data`, followed by 0xff
+    SPITargetLoad {
+        ident: &'p str,
+        data: &'p [u8],
+    },
+    /// Fetch the frames received by a target since the last poll, the reply holds a record per
+    /// frame made of the number of bytes and the bytes received
+    SPITargetPoll {
+        ident: &'p str,
+    },
+    /// Like `I2CWrite` but without ending the write, which is continued by the following
+    /// `I2CWriteMore` or `I2CWrite` to the same address
+    I2CWriteMore {
+        ident: &'p str,
+        address: I2CAddress,
+        data: &'p [u8],
+    },
+    /// Execute the concatenated encoded `requests` back-to-back until one fails, the reply is
+    /// the one of the failing request or `Ok`
+    ///
+    /// Only requests changing GPIO outputs, writing to a bus or delays can be part of a batch.
+    Batch {
+        requests: &'p [u8],
+    },
+    /// Wait for `us` microseconds of at most `MAX_DELAY_US` before replying, mostly useful as
+    /// part of a `Batch`
+    Delay {
+        us: u32,
+    },
+    /// Drive the comma separated `pins`, at most 8, through `steps` timed by a hardware timer,
+    /// each step is made of a byte with the level of pin `n` in bit `n` followed by the time in
+    /// microseconds to hold these levels as little endian `u16`
+    GpioWaveform {
+        pins: &'p str,
+        steps: &'p [u8],
+    },
+    /// Start sampling the comma separated `pins` of a single port, at most 8, `samples` times at
+    /// `rate` Hz at most in the background, replied to by `Frequency` with the actual rate
+    CaptureStart {
+        pins: &'p str,
+        rate: u32,
+        samples: u16,
+    },
+    /// Fetch the samples of the running or finished capture taken so far from `offset` on, the
+    /// reply holds up to `MAX_READ_LENGTH` of them with the level of pin `n` in bit `n` each
+    CaptureRead {
+        offset: u16,
+    },
+    /// Measure the period and the time spent high of the signal at `pin` by timer input capture,
+    /// waiting `timeout_ms` at most for a full period, replied to by `Measurement`
+    Measure {
+        pin: &'p str,
+        timeout_ms: u16,
+    },
+    /// Decode the quadrature encoder with the signals A at `a_pin` and B at `b_pin` with the
+    /// timer `ident`, counting up on every edge of A leading B and down otherwise
+    EncoderInit {
+        ident: &'p str,
+        a_pin: &'p str,
+        b_pin: &'p str,
+    },
+    /// Read the count of an encoder, replied to by `Position`
+    EncoderRead {
+        ident: &'p str,
+    },
+    /// Set the count of an encoder back to zero
+    EncoderReset {
+        ident: &'p str,
+    },
+    /// Send a reset pulse on the 1-Wire bus at `pin`, replied to by `Nack` if no device answers
+    /// with a presence pulse
+    OneWireReset {
+        pin: &'p str,
+    },
+    /// Write `data` to the 1-Wire bus at `pin`, least significant bit first
+    OneWireWrite {
+        pin: &'p str,
+        data: &'p [u8],
+    },
+    /// Read `len` bytes from the 1-Wire bus at `pin`
+    OneWireRead {
+        pin: &'p str,
+        len: u8,
+    },
+    /// Run a single time slot on the 1-Wire bus at `pin` writing `bit`, the reply holds the
+    /// level sampled within the slot, so writing a 1 reads a bit
+    OneWireBit {
+        pin: &'p str,
+        bit: bool,
+    },
+    /// Run a search on the 1-Wire bus at `pin` to find the ROM code following `rom`, taking the
+    /// other branch at the bit `discrepancy` as returned by the previous search or starting
+    /// over with 0, replied to by `Rom`
+    OneWireSearch {
+        pin: &'p str,
+        rom: u64,
+        discrepancy: u8,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
@@ -48,10 +263,116 @@ pub enum Reply<'a> {
     Ok,
     Incomplete,
     NotImplemented,
-    Version { version: u8 },
-    VerboseErr { err: &'a str },
-    ReceiveErr { bytes: u8 },
-    Err { bytes: u8 },
+    Version {
+        version: u8,
+    },
+    VerboseErr {
+        err: &'a str,
+    },
+    ReceiveErr {
+        bytes: u8,
+    },
+    Err {
+        bytes: u8,
+    },
+    /// The session identifier which was active before the `Session` request
+    Session {
+        previous: u32,
+    },
+    /// The target was reset since the last `Session` request and has lost its configuration
+    Rebooted,
+    /// Bytes received from a bus
+    Data {
+        data: &'a [u8],
+    },
+    /// The addressed I2C device did not acknowledge
+    Nack,
+    /// The clock frequency in Hz a bus actually runs at
+    Frequency {
+        hz: u32,
+    },
+    /// A period of a signal and the time it spent high within it, in ticks of a `clock` Hz timer
+    Measurement {
+        clock: u32,
+        period: u32,
+        high: u32,
+    },
+    /// The counter of an encoder, which wraps around after `bits` bits, and whether it last
+    /// counted down
+    Position {
+        count: u32,
+        bits: u8,
+        down: bool,
+    },
+    /// The ROM code found by a 1-Wire search, least significant byte first on the bus, and the
+    /// bit at which the next search has to branch off or 0 if all devices were found
+    Rom {
+        rom: u64,
+        discrepancy: u8,
+    },
+}
+
+impl<'p> Request<'p> {
+    /// Whether executing the request several times has the same effect as executing it once
+    ///
+    /// Only such requests may be resent safely if a reply got lost
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::Version
+            | Request::Clear
+            | Request::GpioInitPP { .. }
+            | Request::GpioSetHigh { .. }
+            | Request::GpioSetLow { .. }
+            | Request::I2CInit { .. }
+            | Request::SPIInit { .. }
+            | Request::SPIDeviceInit { .. }
+            | Request::I2CScan { .. }
+            | Request::I2CRecover { .. }
+            | Request::I2CTargetInit { .. }
+            | Request::I2CTargetSet { .. }
+            | Request::SPITargetInit { .. }
+            | Request::SPITargetLoad { .. }
+            | Request::Delay { .. }
+            | Request::CaptureStart { .. }
+            | Request::CaptureRead { .. }
+            | Request::Measure { .. }
+            | Request::EncoderInit { .. }
+            | Request::EncoderRead { .. }
+            | Request::EncoderReset { .. }
+            | Request::OneWireReset { .. }
+            | Request::OneWireSearch { .. } => true,
+            Request::Reset
+            | Request::GpioToggle { .. }
+            | Request::I2CWrite { .. }
+            | Request::SPIWrite { .. }
+            | Request::Session { .. }
+            | Request::I2CRead { .. }
+            | Request::I2CWriteRead { .. }
+            | Request::SPITransfer { .. }
+            | Request::SPITransaction { .. }
+            | Request::I2CTargetPoll { .. }
+            | Request::SPITargetPoll { .. }
+            | Request::I2CWriteMore { .. }
+            | Request::Batch { .. }
+            | Request::GpioWaveform { .. }
+            | Request::OneWireWrite { .. }
+            | Request::OneWireRead { .. }
+            | Request::OneWireBit { .. } => false,
+        }
+    }
+
+    /// Whether the request sets up state on the target which is lost when the target is reset
+    pub fn is_configuration(&self) -> bool {
+        matches!(
+            self,
+            Request::GpioInitPP { .. }
+                | Request::I2CInit { .. }
+                | Request::SPIInit { .. }
+                | Request::SPIDeviceInit { .. }
+                | Request::I2CTargetInit { .. }
+                | Request::SPITargetInit { .. }
+        )
+    }
 }
 
 pub fn version() -> Request<'static> {
@@ -70,31 +391,32 @@ pub fn reset() -> Request<'static> {
     Request::Reset
 }
 
-pub fn gpio_init_pp(pin: &str) -> Request {
+pub fn gpio_init_pp(pin: &str) -> Request<'_> {
     Request::GpioInitPP { pin }
 }
 
-pub fn gpio_setlow(pin: &str) -> Request {
+pub fn gpio_setlow(pin: &str) -> Request<'_> {
     Request::GpioSetLow { pin }
 }
 
-pub fn gpio_sethigh(pin: &str) -> Request {
+pub fn gpio_sethigh(pin: &str) -> Request<'_> {
     Request::GpioSetHigh { pin }
 }
 
-pub fn gpio_toggle(pin: &str) -> Request {
+pub fn gpio_toggle(pin: &str) -> Request<'_> {
     Request::GpioToggle { pin }
 }
 
-pub fn i2c_init<'p>(scl_pin: &'p str, sda_pin: &'p str, speed: u32) -> Request<'p> {
+pub fn i2c_init<'p>(ident: &'p str, scl_pin: &'p str, sda_pin: &'p str, speed: u32) -> Request<'p> {
     Request::I2CInit {
+        ident,
         scl_pin,
         sda_pin,
         speed,
     }
 }
 
-pub fn i2c_write<'p>(ident: &'p str, address: u8, data: &'p [u8]) -> Request<'p> {
+pub fn i2c_write<'p>(ident: &'p str, address: I2CAddress, data: &'p [u8]) -> Request<'p> {
     Request::I2CWrite {
         ident,
         address,
@@ -102,8 +424,23 @@ pub fn i2c_write<'p>(ident: &'p str, address: u8, data: &'p [u8]) -> Request<'p>
     }
 }
 
-pub fn spi_init<'p>(sck_pin: &'p str, miso_pin: &'p str, mosi_pin: &'p str, speed: u32) -> Request<'p> {
+pub fn i2c_write_more<'p>(ident: &'p str, address: I2CAddress, data: &'p [u8]) -> Request<'p> {
+    Request::I2CWriteMore {
+        ident,
+        address,
+        data,
+    }
+}
+
+pub fn spi_init<'p>(
+    ident: &'p str,
+    sck_pin: &'p str,
+    miso_pin: &'p str,
+    mosi_pin: &'p str,
+    speed: u32,
+) -> Request<'p> {
     Request::SPIInit {
+        ident,
         sck_pin,
         miso_pin,
         mosi_pin,
@@ -112,8 +449,275 @@ pub fn spi_init<'p>(sck_pin: &'p str, miso_pin: &'p str, mosi_pin: &'p str, spee
 }
 
 pub fn spi_write<'p>(ident: &'p str, data: &'p [u8]) -> Request<'p> {
-    Request::SPIWrite {
+    Request::SPIWrite { ident, data }
+}
+
+pub fn session(id: u32) -> Request<'static> {
+    Request::Session { id }
+}
+
+pub fn i2c_read(ident: &str, address: I2CAddress, len: u8) -> Request<'_> {
+    Request::I2CRead {
+        ident,
+        address,
+        len,
+    }
+}
+
+pub fn i2c_write_read<'p>(
+    ident: &'p str,
+    address: I2CAddress,
+    data: &'p [u8],
+    len: u8,
+) -> Request<'p> {
+    Request::I2CWriteRead {
+        ident,
+        address,
+        data,
+        len,
+    }
+}
+
+pub fn spi_transfer<'p>(ident: &'p str, data: &'p [u8]) -> Request<'p> {
+    Request::SPITransfer { ident, data }
+}
+
+pub fn spi_device_init<'p>(ident: &'p str, cs_pin: &'p str) -> Request<'p> {
+    Request::SPIDeviceInit { ident, cs_pin }
+}
+
+pub fn spi_transaction<'p>(
+    ident: &'p str,
+    cs_pin: &'p str,
+    mode: u8,
+    data: &'p [u8],
+) -> Request<'p> {
+    Request::SPITransaction {
+        ident,
+        cs_pin,
+        mode,
+        data,
+    }
+}
+
+pub fn i2c_scan(ident: &str) -> Request<'_> {
+    Request::I2CScan { ident }
+}
+
+pub fn i2c_recover(ident: &str) -> Request<'_> {
+    Request::I2CRecover { ident }
+}
+
+pub fn i2c_target_init<'p>(
+    ident: &'p str,
+    scl_pin: &'p str,
+    sda_pin: &'p str,
+    address: u8,
+) -> Request<'p> {
+    Request::I2CTargetInit {
         ident,
+        scl_pin,
+        sda_pin,
+        address,
+    }
+}
+
+pub fn i2c_target_set<'p>(ident: &'p str, register: u8, data: &'p [u8]) -> Request<'p> {
+    Request::I2CTargetSet {
+        ident,
+        register,
         data,
     }
 }
+
+pub fn i2c_target_poll(ident: &str) -> Request<'_> {
+    Request::I2CTargetPoll { ident }
+}
+
+pub fn spi_target_init<'p>(
+    ident: &'p str,
+    sck_pin: &'p str,
+    miso_pin: &'p str,
+    mosi_pin: &'p str,
+    cs_pin: &'p str,
+    mode: u8,
+) -> Request<'p> {
+    Request::SPITargetInit {
+        ident,
+        sck_pin,
+        miso_pin,
+        mosi_pin,
+        cs_pin,
+        mode,
+    }
+}
+
+pub fn spi_target_load<'p>(ident: &'p str, data: &'p [u8]) -> Request<'p> {
+    Request::SPITargetLoad { ident, data }
+}
+
+pub fn spi_target_poll(ident: &str) -> Request<'_> {
+    Request::SPITargetPoll { ident }
+}
+
+pub fn batch(requests: &[u8]) -> Request<'_> {
+    Request::Batch { requests }
+}
+
+pub fn delay(us: u32) -> Request<'static> {
+    Request::Delay { us }
+}
+
+pub fn gpio_waveform<'p>(pins: &'p str, steps: &'p [u8]) -> Request<'p> {
+    Request::GpioWaveform { pins, steps }
+}
+
+pub fn capture_start(pins: &str, rate: u32, samples: u16) -> Request<'_> {
+    Request::CaptureStart {
+        pins,
+        rate,
+        samples,
+    }
+}
+
+pub fn capture_read(offset: u16) -> Request<'static> {
+    Request::CaptureRead { offset }
+}
+
+pub fn measure(pin: &str, timeout_ms: u16) -> Request<'_> {
+    Request::Measure { pin, timeout_ms }
+}
+
+pub fn encoder_init<'p>(ident: &'p str, a_pin: &'p str, b_pin: &'p str) -> Request<'p> {
+    Request::EncoderInit {
+        ident,
+        a_pin,
+        b_pin,
+    }
+}
+
+pub fn encoder_read(ident: &str) -> Request<'_> {
+    Request::EncoderRead { ident }
+}
+
+pub fn encoder_reset(ident: &str) -> Request<'_> {
+    Request::EncoderReset { ident }
+}
+
+pub fn onewire_reset(pin: &str) -> Request<'_> {
+    Request::OneWireReset { pin }
+}
+
+pub fn onewire_write<'p>(pin: &'p str, data: &'p [u8]) -> Request<'p> {
+    Request::OneWireWrite { pin, data }
+}
+
+pub fn onewire_read(pin: &str, len: u8) -> Request<'_> {
+    Request::OneWireRead { pin, len }
+}
+
+pub fn onewire_bit(pin: &str, bit: bool) -> Request<'_> {
+    Request::OneWireBit { pin, bit }
+}
+
+pub fn onewire_search(pin: &str, rom: u64, discrepancy: u8) -> Request<'_> {
+    Request::OneWireSearch {
+        pin,
+        rom,
+        discrepancy,
+    }
+}
+
+/// The value a search as described by `Request::OneWireSearch` takes at `bit`, counted from 1
+///
+/// `id` and `complement` are the levels read from the devices left in the search for this bit,
+/// `None` is returned if no device answered.
+pub fn onewire_search_branch(
+    bit: u8,
+    id: bool,
+    complement: bool,
+    rom: u64,
+    discrepancy: u8,
+) -> Option<bool> {
+    match (id, complement) {
+        (true, true) => None,
+        // All remaining devices have the same bit here
+        (id, complement) if id != complement => Some(id),
+        // Devices with both values left, follow the previous search up to its branch
+        _ if bit < discrepancy => Some(rom & (1 << (bit - 1)) != 0),
+        _ => Some(bit == discrepancy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::onewire_search_branch;
+    use std::vec::Vec;
+
+    /// Run a search over the devices `roms` like the target does, see `Request::OneWireSearch`
+    fn search(roms: &[u64], rom: u64, discrepancy: u8) -> Option<(u64, u8)> {
+        let mut left = roms.to_vec();
+        let (mut found, mut last_zero) = (0, 0);
+        for bit in 1..=64 {
+            let mask = 1 << (bit - 1);
+            // Any device sending a 0 pulls the bus low
+            let id = left.iter().all(|rom| rom & mask != 0);
+            let complement = left.iter().all(|rom| rom & mask == 0);
+
+            let direction = onewire_search_branch(bit, id, complement, rom, discrepancy)?;
+            if !id && !complement && !direction {
+                last_zero = bit;
+            }
+
+            found |= u64::from(direction) << (bit - 1);
+            left.retain(|rom| (rom & mask != 0) == direction);
+        }
+
+        Some((found, last_zero))
+    }
+
+    fn search_all(roms: &[u64]) -> Vec<u64> {
+        let mut found = Vec::new();
+        let (mut rom, mut discrepancy) = (0, 0);
+        loop {
+            let (next, branch) = search(roms, rom, discrepancy).unwrap();
+            found.push(next);
+            if branch == 0 {
+                return found;
+            }
+            rom = next;
+            discrepancy = branch;
+        }
+    }
+
+    #[test]
+    fn search_finds_single_device() {
+        assert_eq!(
+            search(&[0xa200_0000_01b8_1c02], 0, 0),
+            Some((0xa200_0000_01b8_1c02, 0))
+        );
+    }
+
+    #[test]
+    fn search_finds_all_devices_in_order() {
+        let mut roms = std::vec![
+            0xa200_0000_01b8_1c02,
+            0x003c_8f79_a216_0328,
+            0x003c_8f79_a216_0329,
+            0x0000_0000_0000_0001,
+            0x0080_0000_0000_0000,
+        ];
+        let found = search_all(&roms);
+
+        // Devices with a 0 at the lowest bit where they differ come first
+        roms.sort_by_key(|rom| rom.reverse_bits());
+        assert_eq!(found, roms);
+    }
+
+    #[test]
+    fn search_fails_without_device() {
+        assert_eq!(search(&[], 0, 0), None);
+    }
+}
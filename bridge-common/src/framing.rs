@@ -0,0 +1,153 @@
+use heapless::{consts::U64, Vec};
+
+use crate::encoding::INTERFRAME_TIMEOUT_MS;
+
+/// The bytes of the request the target is receiving
+///
+/// A request the host stopped sending half way is dropped once no byte arrived for
+/// `INTERFRAME_TIMEOUT_MS`, so the next request starts afresh. After a receive error the rest of
+/// the broken request is discarded until then.
+#[derive(Default)]
+pub struct RequestBuffer {
+    buffer: Vec<u8, U64>,
+    /// Set after a receive error to drop the rest of the broken request
+    discarding: bool,
+    idle_ms: u32,
+}
+
+impl RequestBuffer {
+    pub fn new() -> Self {
+        RequestBuffer::default()
+    }
+
+    /// Add a received byte, fails with the number of bytes received before if it doesn't fit
+    pub fn push(&mut self, byte: u8) -> Result<(), u8> {
+        self.idle_ms = 0;
+
+        if self.discarding {
+            return Ok(());
+        }
+
+        self.buffer.push(byte).map_err(|_| self.fail())
+    }
+
+    /// Note that a byte got lost, returns the number of bytes received before unless the
+    /// request was already broken
+    pub fn lost(&mut self) -> Option<u8> {
+        if self.discarding {
+            None
+        } else {
+            Some(self.fail())
+        }
+    }
+
+    /// Account for `ms` milliseconds without a received byte
+    pub fn idle(&mut self, ms: u32) {
+        if !self.discarding && self.buffer.is_empty() {
+            return;
+        }
+
+        self.idle_ms += ms;
+
+        /* The host stopped sending in the middle of a request, start afresh */
+        if self.idle_ms >= INTERFRAME_TIMEOUT_MS {
+            self.clear();
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Start over with the next request
+    pub fn clear(&mut self) {
+        self.reset();
+        self.discarding = false;
+        self.idle_ms = 0;
+    }
+
+    fn fail(&mut self) -> u8 {
+        let bytes = self.buffer.len() as u8;
+        self.reset();
+        self.discarding = true;
+        bytes
+    }
+
+    /// Drop the received bytes, `Vec::clear` of heapless 0.5 indexes past the end of the slice
+    fn reset(&mut self) {
+        self.buffer = Vec::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestBuffer;
+    use crate::encoding::INTERFRAME_TIMEOUT_MS;
+
+    fn filled(bytes: &[u8]) -> RequestBuffer {
+        let mut buffer = RequestBuffer::new();
+        for &byte in bytes {
+            buffer.push(byte).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn partial_request_is_dropped_after_timeout() {
+        let mut buffer = filled(&[1, 2, 3]);
+
+        buffer.idle(INTERFRAME_TIMEOUT_MS - 1);
+        assert_eq!(buffer.bytes(), [1, 2, 3]);
+
+        buffer.idle(1);
+        assert!(buffer.bytes().is_empty());
+    }
+
+    #[test]
+    fn received_byte_restarts_timeout() {
+        let mut buffer = filled(&[1]);
+
+        buffer.idle(INTERFRAME_TIMEOUT_MS - 1);
+        buffer.push(2).unwrap();
+        buffer.idle(INTERFRAME_TIMEOUT_MS - 1);
+
+        assert_eq!(buffer.bytes(), [1, 2]);
+    }
+
+    #[test]
+    fn idle_time_without_request_is_not_counted() {
+        let mut buffer = RequestBuffer::new();
+
+        buffer.idle(INTERFRAME_TIMEOUT_MS - 1);
+        buffer.push(1).unwrap();
+        buffer.idle(1);
+
+        assert_eq!(buffer.bytes(), [1]);
+    }
+
+    #[test]
+    fn overflow_discards_rest_of_request() {
+        let mut buffer = filled(&[0; 64]);
+        assert_eq!(buffer.push(0), Err(64));
+
+        // The rest of the broken request is ignored until the host pauses
+        buffer.push(1).unwrap();
+        assert!(buffer.bytes().is_empty());
+        assert_eq!(buffer.lost(), None);
+
+        buffer.idle(INTERFRAME_TIMEOUT_MS);
+        buffer.push(2).unwrap();
+        assert_eq!(buffer.bytes(), [2]);
+    }
+
+    #[test]
+    fn lost_byte_is_reported_once() {
+        let mut buffer = filled(&[1, 2]);
+
+        assert_eq!(buffer.lost(), Some(2));
+        assert_eq!(buffer.lost(), None);
+
+        buffer.idle(INTERFRAME_TIMEOUT_MS);
+        assert_eq!(buffer.lost(), Some(0));
+    }
+}
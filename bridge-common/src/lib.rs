@@ -1,3 +1,4 @@
 #![no_std]
 
 pub mod encoding;
+pub mod framing;
@@ -0,0 +1,145 @@
+use crate::hal::stm32;
+use crate::pins::Pin;
+
+use core::ptr;
+
+use heapless::{consts::*, Vec};
+
+/// Number of samples which fit into the RAM set aside for captures
+///
+/// The linker script only provides the RAM of the STM32F042 for both chips.
+pub const CAPACITY: usize = 1024;
+
+/// Fastest sample rate at which the DMA reliably keeps up
+const MAX_RATE: u32 = 1_000_000;
+
+/// The input data register of the sampled port at every sample, written by the DMA
+static mut SAMPLES: [u16; CAPACITY] = [0; CAPACITY];
+
+/// A capture of the levels of a set of pins, taken in the background
+///
+/// The update event of TIM17 triggers DMA channel 1 to copy the input data register of the port
+/// into RAM, so the pins keep their function and e.g. a bus driven by the bridge itself can be
+/// observed.
+pub struct Capture {
+    /// Index within the port of each sampled pin
+    pins: Vec<u8, U8>,
+    samples: u16,
+    rate: u32,
+}
+
+impl Capture {
+    /// Start sampling the comma separated `pins`, which have to be on the same port, `samples`
+    /// times at `rate` Hz at most
+    pub fn start(pins: &str, rate: u32, samples: u16, pclk: u32) -> Result<Capture, &'static str> {
+        let mut port = None;
+        let mut indices: Vec<u8, U8> = Vec::new();
+
+        for name in pins.split(',') {
+            let pin = Pin::from_name(name).ok_or("invalid capture pin")?;
+            if *port.get_or_insert(pin.idr_address()) != pin.idr_address() {
+                return Err("capture pins on different ports");
+            }
+            indices
+                .push(pin.index())
+                .map_err(|_| "too many capture pins")?;
+        }
+
+        if samples == 0 || usize::from(samples) > CAPACITY {
+            return Err("unsupported number of samples");
+        }
+        if rate > MAX_RATE {
+            return Err("capture rate too high");
+        }
+        if rate == 0 {
+            return Err("capture rate too low");
+        }
+
+        let tim = unsafe { &*stm32::TIM17::ptr() };
+        let dma = unsafe { &*stm32::DMA1::ptr() };
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+
+        // Stop a capture still in progress
+        tim.cr1.modify(|_, w| w.cen().clear_bit());
+        dma.ch1.cr.modify(|_, w| w.en().clear_bit());
+
+        rcc.ahbenr.modify(|_, w| w.dmaen().set_bit());
+        rcc.apb2enr.modify(|_, w| w.tim17en().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.tim17rst().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.tim17rst().clear_bit());
+
+        // Split the period into prescaler and reload value of the 16 bit timer, rounding up so
+        // the samples are never taken faster than requested
+        let ticks = pclk.div_ceil(rate);
+        let psc = (ticks - 1) / 0x1_0000;
+        let arr = ticks.div_ceil(psc + 1) - 1;
+
+        tim.psc.write(|w| w.psc().bits(psc as u16));
+        tim.arr.write(|w| unsafe { w.arr().bits(arr as u16) });
+
+        // Load the prescaler before update events trigger any transfers
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.sr.reset();
+
+        // TIM17 is mapped to channel 1 unless remapped in SYSCFG
+        dma.ch1.par.write(|w| w.pa().bits(port.unwrap_or_default()));
+        dma.ch1
+            .mar
+            .write(|w| w.ma().bits(ptr::addr_of!(SAMPLES) as u32));
+        dma.ch1.ndtr.write(|w| w.ndt().bits(samples));
+        dma.ch1.cr.write(|w| {
+            w.psize()
+                .bits16()
+                .msize()
+                .bits16()
+                .minc()
+                .set_bit()
+                .pl()
+                .very_high()
+                .en()
+                .set_bit()
+        });
+
+        tim.dier.write(|w| w.ude().set_bit());
+        tim.cr1.modify(|_, w| w.cen().set_bit());
+
+        Ok(Capture {
+            pins: indices,
+            samples,
+            rate: pclk / ((psc + 1) * (arr + 1)),
+        })
+    }
+
+    /// The sample rate in Hz actually used
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Number of samples taken so far
+    fn taken(&self) -> usize {
+        let dma = unsafe { &*stm32::DMA1::ptr() };
+        usize::from(self.samples - dma.ch1.ndtr.read().ndt().bits())
+    }
+
+    /// Copy the samples taken so far from `offset` on into `buffer` and return their number
+    ///
+    /// Every sample is packed into a byte with the level of pin `n` in bit `n`.
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let taken = self.taken();
+        let len = taken.saturating_sub(offset).min(buffer.len());
+
+        for (i, byte) in buffer[..len].iter_mut().enumerate() {
+            let sample = unsafe { ptr::read_volatile(ptr::addr_of!(SAMPLES[offset + i])) };
+
+            *byte = self
+                .pins
+                .iter()
+                .enumerate()
+                .fold(0, |packed, (bit, &index)| {
+                    packed | (((sample >> index) & 1) as u8) << bit
+                });
+        }
+
+        len
+    }
+}
@@ -0,0 +1,72 @@
+use crate::pins::lookup;
+use crate::timer::INSTANCES;
+
+/// Value of the slave mode selection counting on both edges of both inputs
+const ENCODER_MODE: u32 = 0b011;
+
+/// Input filter setting requiring 8 consecutive samples at the timer clock for a level change
+const FILTER: u8 = 0b0011;
+
+/// Set up the timer `ident` to decode the quadrature encoder at `a_pin` and `b_pin`
+///
+/// The counter counts every edge of both signals, so four times per cycle of the encoder. The
+/// inputs are filtered to ignore glitches shorter than 8 cycles of the timer clock and pulled
+/// up for encoders with open collector outputs.
+pub fn init(ident: &str, a_pin: &str, b_pin: &str) -> Result<(), &'static str> {
+    let instance = lookup(INSTANCES, ident)?;
+    let a = instance.ch1(a_pin).ok_or("invalid encoder a pin")?;
+    let b = instance.ch2(b_pin).ok_or("invalid encoder b pin")?;
+
+    instance.reset();
+    for &(pin, af) in &[a, b] {
+        pin.set_pull_up(true);
+        pin.into_alternate(af, false);
+    }
+
+    let tim = instance.regs();
+    tim.ccmr1_input_mut().write(|w| {
+        w.cc1s()
+            .ti1()
+            .ic1f()
+            .bits(FILTER)
+            .cc2s()
+            .ti2()
+            .ic2f()
+            .bits(FILTER)
+    });
+    tim.smcr.write(|w| w.sms().encoder_mode_3());
+    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+    Ok(())
+}
+
+/// The counter of the encoder of timer `ident`, its width in bits and whether it last counted
+/// down
+pub fn read(ident: &str) -> Result<(u32, u8, bool), &'static str> {
+    let instance = lookup(INSTANCES, ident)?;
+    let tim = instance.regs();
+
+    // The timer may have been used to measure a signal since
+    if tim.smcr.read().bits() & 0b111 != ENCODER_MODE {
+        return Err("no encoder on timer");
+    }
+
+    let bits = if instance.is_wide() { 32 } else { 16 };
+
+    Ok((
+        tim.cnt.read().bits(),
+        bits,
+        tim.cr1.read().dir().bit_is_set(),
+    ))
+}
+
+/// Set the count of the encoder of timer `ident` back to zero
+pub fn reset(ident: &str) -> Result<(), &'static str> {
+    read(ident)?;
+    lookup(INSTANCES, ident)?
+        .regs()
+        .cnt
+        .write(|w| unsafe { w.bits(0) });
+
+    Ok(())
+}
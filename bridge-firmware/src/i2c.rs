@@ -0,0 +1,717 @@
+use crate::hal::stm32;
+use crate::hal::stm32::i2c1::RegisterBlock;
+use crate::hal::stm32::{interrupt, Interrupt};
+use crate::pins::{lookup, route, Named, Pin, Route};
+use crate::record::RecordLog;
+use crate::Timeout;
+
+use bridge_common::encoding::{I2CAddress, Reply};
+
+use core::cell::RefCell;
+use core::ops::RangeInclusive;
+
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+
+use heapless::{consts::*, Vec};
+
+/// An I2C peripheral of the chip and the pins its signals can be routed to
+pub struct Instance {
+    pub ident: &'static str,
+    regs: *const RegisterBlock,
+    enable: fn(&stm32::rcc::RegisterBlock),
+    /// Interrupt of the events and errors of the peripheral
+    interrupt: Interrupt,
+    /// Frequency the peripheral is clocked with given the frequency of the APB clock
+    clock: fn(u32) -> u32,
+    scl: &'static [Route],
+    sda: &'static [Route],
+}
+
+impl Named for Instance {
+    const UNKNOWN: &'static str = "unknown i2c instance";
+
+    fn ident(&self) -> &'static str {
+        self.ident
+    }
+}
+
+#[cfg(any(feature = "stm32f042",))]
+pub const INSTANCES: &[Instance] = &[Instance {
+    ident: "i2c1",
+    regs: stm32::I2C1::ptr(),
+    enable: |rcc| rcc.apb1enr.modify(|_, w| w.i2c1en().set_bit()),
+    interrupt: Interrupt::I2C1,
+    clock: |_| HSI,
+    scl: &[
+        Route { pin: "f1", af: 1 },
+        Route { pin: "a9", af: 4 },
+        Route { pin: "a11", af: 5 },
+        Route { pin: "b6", af: 1 },
+        Route { pin: "b8", af: 1 },
+        Route { pin: "b10", af: 1 },
+        Route { pin: "b13", af: 5 },
+    ],
+    sda: &[
+        Route { pin: "f0", af: 1 },
+        Route { pin: "a10", af: 4 },
+        Route { pin: "a12", af: 5 },
+        Route { pin: "b7", af: 1 },
+        Route { pin: "b9", af: 1 },
+        Route { pin: "b11", af: 1 },
+        Route { pin: "b14", af: 5 },
+    ],
+}];
+
+#[cfg(any(feature = "stm32f072",))]
+pub const INSTANCES: &[Instance] = &[
+    Instance {
+        ident: "i2c1",
+        regs: stm32::I2C1::ptr(),
+        enable: |rcc| rcc.apb1enr.modify(|_, w| w.i2c1en().set_bit()),
+        interrupt: Interrupt::I2C1,
+        clock: |_| HSI,
+        scl: &[Route { pin: "b6", af: 1 }, Route { pin: "b8", af: 1 }],
+        sda: &[Route { pin: "b7", af: 1 }, Route { pin: "b9", af: 1 }],
+    },
+    Instance {
+        ident: "i2c2",
+        regs: stm32::I2C2::ptr(),
+        enable: |rcc| rcc.apb1enr.modify(|_, w| w.i2c2en().set_bit()),
+        interrupt: Interrupt::I2C2,
+        // Unlike I2C1 this instance has no choice of clock source
+        clock: |pclk| pclk,
+        scl: &[Route { pin: "b10", af: 1 }, Route { pin: "b13", af: 5 }],
+        sda: &[Route { pin: "b11", af: 1 }, Route { pin: "b14", af: 5 }],
+    },
+];
+
+/// I2C1 is clocked by the HSI after reset which runs at a fixed 8MHz
+const HSI: u32 = 8_000_000;
+
+/// Half a clock period at 100 kHz in cycles of the 48 MHz system clock
+const HALF_PERIOD_CYCLES: u32 = 240;
+
+/// How long to wait for each byte or condition on the bus before giving up, covers devices
+/// stretching the clock up to the 25 ms the SMBus allows
+const BYTE_TIMEOUT_US: u32 = 25_000;
+
+/// How long to wait for a probed device, which is long enough to send the address and STOP
+/// condition at the lowest supported speed
+const PROBE_TIMEOUT_US: u32 = 2_000;
+
+/// Supported SCL frequencies, fast mode plus needs an increased drive strength of the pins
+const SPEEDS: RangeInclusive<u32> = 10_000..=400_000;
+
+/// How a transfer continues once the number of bytes announced when starting it is transferred
+#[derive(Clone, Copy, PartialEq)]
+enum End {
+    /// Send a STOP condition
+    Stop,
+    /// Wait for a repeated start
+    Pause,
+    /// Hold SCL low until the number of following bytes is announced
+    Reload,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The addressed device did not acknowledge
+    Nack,
+    /// A 10-bit address exceeding 0x3ff
+    Address,
+    /// The bus did not progress in time, e.g. because a device holds SCL low
+    Timeout,
+    /// Arbitration was lost or a misplaced START or STOP condition was seen
+    Bus,
+}
+
+/// An I2C peripheral set up as bus controller
+///
+/// The HAL ties the type of a bus to its pins, so the peripheral is driven directly to be able
+/// to handle any instance and pin mapping the host asks for.
+pub struct I2c {
+    pub ident: &'static str,
+    regs: &'static RegisterBlock,
+    scl: (Pin, u8),
+    sda: (Pin, u8),
+    timing: Timing,
+    /// Device of a write left open by `write_more`
+    open: Option<I2CAddress>,
+    sysclk: u32,
+}
+
+impl I2c {
+    /// Set up the instance `ident` on pins `scl` and `sda` running at `speed` Hz at most
+    ///
+    /// Fails if the chip has no such instance, its signals cannot be routed to the given pins or
+    /// `speed` is not supported.
+    pub fn new(
+        ident: &str,
+        scl: &str,
+        sda: &str,
+        speed: u32,
+        pclk: u32,
+        sysclk: u32,
+    ) -> Result<I2c, &'static str> {
+        let instance = lookup(INSTANCES, ident)?;
+
+        let i2c = I2c {
+            ident: instance.ident,
+            regs: unsafe { &*instance.regs },
+            scl: route(instance.scl, scl).ok_or("scl not available on this pin")?,
+            sda: route(instance.sda, sda).ok_or("sda not available on this pin")?,
+            timing: Timing::new((instance.clock)(pclk), speed)?,
+            open: None,
+            sysclk,
+        };
+
+        (instance.enable)(unsafe { &*stm32::RCC::ptr() });
+        i2c.connect();
+        i2c.configure();
+
+        Ok(i2c)
+    }
+
+    fn connect(&self) {
+        connect(&[self.scl, self.sda]);
+    }
+
+    /// The SCL frequency in Hz the bus runs at
+    pub fn frequency(&self) -> u32 {
+        self.timing.frequency
+    }
+
+    fn configure(&self) {
+        // Make sure the I2C unit is disabled so we can configure it, this also disables the
+        // interrupts in case the instance was used as target before
+        self.regs.cr1.reset();
+
+        // Stop responding as a target in case the instance was used as one before
+        self.regs.oar1.reset();
+        self.timing.apply(self.regs);
+
+        // Enable the I2C processing
+        self.regs.cr1.modify(|_, w| w.pe().set_bit());
+    }
+
+    /// Read the status, turning a NACK or bus error into an error
+    fn status(&self, timeout: &mut Timeout) -> Result<stm32::i2c1::isr::R, Error> {
+        let isr = self.regs.isr.read();
+
+        if isr.arlo().bit_is_set() || isr.berr().bit_is_set() {
+            self.abort();
+            return Err(Error::Bus);
+        }
+
+        if isr.nackf().bit_is_set() {
+            // A NACK ends the transfer with a STOP condition which has to be cleared as well
+            while self.regs.isr.read().stopf().bit_is_clear() {
+                if timeout.expired() {
+                    self.abort();
+                    return Err(Error::Timeout);
+                }
+            }
+            self.regs
+                .icr
+                .write(|w| w.stopcf().set_bit().nackcf().set_bit());
+            return Err(Error::Nack);
+        }
+
+        Ok(isr)
+    }
+
+    /// Wait up to `us` microseconds for the status to become `ready`
+    fn wait(&self, us: u32, ready: fn(&stm32::i2c1::isr::R) -> bool) -> Result<(), Error> {
+        let mut timeout = Timeout::us(us, self.sysclk);
+
+        while !ready(&self.status(&mut timeout)?) {
+            if timeout.expired() {
+                self.abort();
+                return Err(Error::Timeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give up the transfer in progress and return to idle
+    ///
+    /// Disabling the peripheral releases the bus lines and resets the state machine, it has to
+    /// stay disabled for a few clock cycles which reading back the state ensures.
+    fn abort(&self) {
+        self.regs.icr.write(|w| {
+            w.arlocf()
+                .set_bit()
+                .berrcf()
+                .set_bit()
+                .nackcf()
+                .set_bit()
+                .stopcf()
+                .set_bit()
+        });
+        self.regs.cr1.modify(|_, w| w.pe().clear_bit());
+        while self.regs.cr1.read().pe().bit_is_set() {}
+        self.regs.cr1.modify(|_, w| w.pe().set_bit());
+    }
+
+    fn start(&mut self, addr: I2CAddress, len: usize, read: bool, end: End) -> Result<(), Error> {
+        if self.open.take().is_some() {
+            // There is no way to end an open write with a STOP condition, so reset the peripheral
+            self.configure();
+        }
+
+        // A 10-bit address is used as is while a 7-bit one is expected in bits 1 to 7
+        let (sadd, add10) = match addr {
+            I2CAddress::SevenBit(addr) => (u16::from(addr) << 1, false),
+            I2CAddress::TenBit(addr) if addr <= 0x3ff => (addr, true),
+            I2CAddress::TenBit(_) => return Err(Error::Address),
+        };
+
+        // Always send the complete 10-bit address also when reading after a write
+        self.regs.cr2.modify(|_, w| {
+            w.sadd()
+                .bits(sadd)
+                .add10()
+                .bit(add10)
+                .head10r()
+                .clear_bit()
+                .nbytes()
+                .bits(len as u8)
+                .rd_wrn()
+                .bit(read)
+                .autoend()
+                .bit(end == End::Stop)
+                .reload()
+                .bit(end == End::Reload)
+        });
+
+        self.regs.cr2.modify(|_, w| w.start().set_bit());
+
+        Ok(())
+    }
+
+    fn send(&self, bytes: &[u8]) -> Result<(), Error> {
+        for c in bytes {
+            // Wait until we're ready for sending
+            self.wait(BYTE_TIMEOUT_US, |isr| isr.txis().bit_is_set())?;
+
+            self.regs.txdr.write(|w| unsafe { w.bits(u32::from(*c)) });
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to `us` microseconds for the STOP condition ending a transfer started with
+    /// `autoend`
+    fn finish(&self, us: u32) -> Result<(), Error> {
+        self.wait(us, |isr| isr.stopf().bit_is_set())?;
+        self.regs.icr.write(|w| w.stopcf().set_bit());
+
+        Ok(())
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        for c in buffer.iter_mut() {
+            self.wait(BYTE_TIMEOUT_US, |isr| isr.rxne().bit_is_set())?;
+
+            *c = self.regs.rxdr.read().bits() as u8;
+        }
+
+        Ok(())
+    }
+
+    /// Start a write of `len` bytes or continue the write to `addr` left open by `write_more`
+    fn start_write(&mut self, addr: I2CAddress, len: usize, end: End) -> Result<(), Error> {
+        if self.open != Some(addr) {
+            return self.start(addr, len, false, end);
+        }
+
+        // Announcing the number of following bytes releases SCL
+        self.open = None;
+        self.regs.cr2.modify(|_, w| {
+            w.nbytes()
+                .bits(len as u8)
+                .autoend()
+                .bit(end == End::Stop)
+                .reload()
+                .bit(end == End::Reload)
+        });
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, addr: I2CAddress, bytes: &[u8]) -> Result<(), Error> {
+        self.start_write(addr, bytes.len(), End::Stop)?;
+        self.send(bytes)?;
+        self.finish(BYTE_TIMEOUT_US)
+    }
+
+    /// Write `bytes` without ending the write, which is continued by the next `write_more` or
+    /// `write` for `addr`
+    ///
+    /// SCL is held low in between, so the device waits for the following bytes however long it
+    /// takes them to arrive. Any other transfer gives up the open write.
+    pub fn write_more(&mut self, addr: I2CAddress, bytes: &[u8]) -> Result<(), Error> {
+        self.start_write(addr, bytes.len(), End::Reload)?;
+        self.send(bytes)?;
+
+        self.wait(BYTE_TIMEOUT_US, |isr| isr.tcr().bit_is_set())?;
+        self.open = Some(addr);
+
+        Ok(())
+    }
+
+    /// Receive `buffer.len()` bytes from the device at `addr`
+    pub fn read(&mut self, addr: I2CAddress, buffer: &mut [u8]) -> Result<(), Error> {
+        self.start(addr, buffer.len(), true, End::Stop)?;
+        self.receive(buffer)?;
+        self.finish(BYTE_TIMEOUT_US)
+    }
+
+    /// Write `bytes` and read into `buffer` with a repeated start in between
+    pub fn write_read(
+        &mut self,
+        addr: I2CAddress,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.start(addr, bytes.len(), false, End::Pause)?;
+        self.send(bytes)?;
+
+        // Wait until data was sent
+        self.wait(BYTE_TIMEOUT_US, |isr| isr.tc().bit_is_set())?;
+
+        // Only request the STOP condition after the repeated start to not end the write early
+        self.start(addr, buffer.len(), true, End::Pause)?;
+        self.regs.cr2.modify(|_, w| w.autoend().set_bit());
+        self.receive(buffer)?;
+        self.finish(BYTE_TIMEOUT_US)
+    }
+
+    /// Address `addr` without transferring any data and report whether a device acknowledged
+    ///
+    /// A probe not completing in time counts as no device, so a scan of a stuck bus ends soon.
+    pub fn probe(&mut self, addr: u8) -> bool {
+        // The STOP condition follows the address automatically
+        self.start(I2CAddress::SevenBit(addr), 0, false, End::Stop)
+            .and_then(|()| self.finish(PROBE_TIMEOUT_US))
+            .is_ok()
+    }
+
+    /// Free the bus from a device holding SDA low after an aborted transfer
+    ///
+    /// Clocks SCL until the device releases SDA, at most nine times, finishes with a STOP
+    /// condition and sets up the peripheral afresh. Returns whether SDA is released afterwards.
+    pub fn recover(&mut self) -> bool {
+        let (scl, sda) = (self.scl.0, self.sda.0);
+        let half_period = || cortex_m::asm::delay(HALF_PERIOD_CYCLES);
+
+        self.regs.cr1.modify(|_, w| w.pe().clear_bit());
+        self.open = None;
+
+        sda.set_high();
+        scl.set_high();
+        sda.into_output(true);
+        scl.into_output(true);
+        half_period();
+
+        for _ in 0..9 {
+            if sda.is_high() {
+                break;
+            }
+
+            scl.set_low();
+            half_period();
+            scl.set_high();
+            half_period();
+        }
+
+        // A rising edge on SDA while SCL is high is a STOP condition
+        scl.set_low();
+        half_period();
+        sda.set_low();
+        half_period();
+        scl.set_high();
+        half_period();
+        sda.set_high();
+        half_period();
+
+        let released = sda.is_high();
+
+        self.connect();
+        self.configure();
+
+        released
+    }
+}
+
+/// Hand the pins to the peripheral as open drain outputs with pull ups
+fn connect(pins: &[(Pin, u8)]) {
+    for &(pin, af) in pins {
+        pin.set_pull_up(true);
+        pin.into_alternate(af, true);
+    }
+}
+
+/// Number of registers of an emulated target
+const TARGET_REGISTERS: usize = 256;
+
+/// The I2C peripherals set up as target, shared with the interrupt handlers servicing them
+static TARGETS: Mutex<RefCell<Vec<Target, U2>>> =
+    Mutex::new(RefCell::new(Vec(heapless::i::Vec::new())));
+
+/// Run `f` on the targets without being interrupted by their events
+pub fn with_targets<R>(f: impl FnOnce(&mut Vec<Target, U2>) -> R) -> R {
+    cortex_m::interrupt::free(|cs| f(&mut TARGETS.borrow(cs).borrow_mut()))
+}
+
+#[interrupt]
+fn I2C1() {
+    with_targets(|targets| targets.iter_mut().for_each(Target::poll));
+}
+
+#[cfg(any(feature = "stm32f072",))]
+#[interrupt]
+fn I2C2() {
+    with_targets(|targets| targets.iter_mut().for_each(Target::poll));
+}
+
+/// An I2C peripheral set up as target emulating a device with a register file
+///
+/// The first byte written by the controller selects the register, further bytes written or
+/// read access the following registers. The controller is held off by clock stretching while
+/// an event waits to be handled by `poll`, which runs in the interrupt of the peripheral.
+///
+/// The interrupt is enabled as soon as the target is set up, so that needs to happen within
+/// `with_targets` for the interrupt to find the target.
+pub struct Target {
+    pub ident: &'static str,
+    regs: &'static RegisterBlock,
+    registers: [u8; TARGET_REGISTERS],
+    pointer: u8,
+    /// Whether the next byte received selects the register
+    expect_pointer: bool,
+    /// Writes of the controller not fetched by the host yet, see `Request::I2CTargetPoll`
+    log: RecordLog,
+}
+
+// The registers of the peripheral are only accessed through the target, which is only used
+// within the critical section of `with_targets`
+unsafe impl Send for Target {}
+
+impl Target {
+    /// Set up the instance `ident` on pins `scl` and `sda` to respond to the 7-bit `address`
+    pub fn new(
+        ident: &str,
+        scl: &str,
+        sda: &str,
+        address: u8,
+        pclk: u32,
+    ) -> Result<Target, &'static str> {
+        let instance = lookup(INSTANCES, ident)?;
+        let pins = [
+            route(instance.scl, scl).ok_or("scl not available on this pin")?,
+            route(instance.sda, sda).ok_or("sda not available on this pin")?,
+        ];
+
+        if address > 0x7f {
+            return Err("invalid i2c address");
+        }
+
+        // Only the data setup and hold times matter as the controller generates the clock
+        let timing = Timing::new((instance.clock)(pclk), *SPEEDS.end())?;
+
+        let target = Target {
+            ident: instance.ident,
+            regs: unsafe { &*instance.regs },
+            registers: [0; TARGET_REGISTERS],
+            pointer: 0,
+            expect_pointer: false,
+            log: RecordLog::default(),
+        };
+
+        (instance.enable)(unsafe { &*stm32::RCC::ptr() });
+        connect(&pins);
+
+        let regs = target.regs;
+        regs.cr1.modify(|_, w| w.pe().clear_bit());
+        timing.apply(regs);
+        regs.oar1
+            .write(|w| w.oa1().bits(u16::from(address) << 1).oa1en().set_bit());
+        regs.cr1.modify(|_, w| {
+            w.addrie()
+                .set_bit()
+                .rxie()
+                .set_bit()
+                .txie()
+                .set_bit()
+                .nackie()
+                .set_bit()
+                .stopie()
+                .set_bit()
+                .errie()
+                .set_bit()
+                .pe()
+                .set_bit()
+        });
+        unsafe { NVIC::unmask(instance.interrupt) };
+
+        Ok(target)
+    }
+
+    /// Fill the register file starting at `register`, wrapping around at the end
+    pub fn set_registers(&mut self, register: u8, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.registers[(usize::from(register) + i) % TARGET_REGISTERS] = *byte;
+        }
+    }
+
+    /// Move the records of completed writes into `buffer` and return their length
+    pub fn take_writes(&mut self, buffer: &mut [u8]) -> usize {
+        self.log.take(buffer)
+    }
+
+    /// Handle pending bus events
+    fn poll(&mut self) {
+        let isr = self.regs.isr.read();
+
+        if isr.berr().bit_is_set() || isr.arlo().bit_is_set() || isr.ovr().bit_is_set() {
+            // A misplaced START or STOP condition or lost arbitration aborts the transfer and an
+            // overrun loses a byte, so close the record of what was received so far
+            self.log.finish();
+            self.regs
+                .icr
+                .write(|w| w.berrcf().set_bit().arlocf().set_bit().ovrcf().set_bit());
+        }
+
+        if isr.addr().bit_is_set() {
+            // A write just selecting a register is not recorded
+            self.log.finish();
+
+            if isr.dir().bit_is_set() {
+                // Drop a byte prepared for an earlier read of the controller
+                self.regs.isr.write(|w| w.txe().set_bit());
+            } else {
+                self.expect_pointer = true;
+            }
+
+            self.regs.icr.write(|w| w.addrcf().set_bit());
+        }
+
+        if isr.rxne().bit_is_set() {
+            let byte = self.regs.rxdr.read().bits() as u8;
+
+            if self.expect_pointer {
+                self.expect_pointer = false;
+                self.pointer = byte;
+                self.log.start(&[byte]);
+            } else {
+                self.registers[usize::from(self.pointer)] = byte;
+                self.pointer = self.pointer.wrapping_add(1);
+                self.log.push(byte);
+            }
+        }
+
+        if isr.txis().bit_is_set() {
+            let byte = self.registers[usize::from(self.pointer)];
+            self.regs.txdr.write(|w| unsafe { w.bits(u32::from(byte)) });
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+
+        if isr.nackf().bit_is_set() {
+            // The controller ends a read with a NACK, so the byte prepared last was not sent
+            self.pointer = self.pointer.wrapping_sub(1);
+            self.regs.isr.write(|w| w.txe().set_bit());
+            self.regs.icr.write(|w| w.nackcf().set_bit());
+        }
+
+        if isr.stopf().bit_is_set() {
+            self.log.finish();
+            self.regs.icr.write(|w| w.stopcf().set_bit());
+        }
+    }
+}
+
+/// Register values generating an SCL frequency and the frequency they result in
+struct Timing {
+    presc: u8,
+    scldel: u8,
+    sdadel: u8,
+    sclh: u8,
+    scll: u8,
+    frequency: u32,
+}
+
+impl Timing {
+    fn apply(&self, regs: &RegisterBlock) {
+        regs.timingr.write(|w| {
+            w.presc()
+                .bits(self.presc)
+                .scldel()
+                .bits(self.scldel)
+                .sdadel()
+                .bits(self.sdadel)
+                .sclh()
+                .bits(self.sclh)
+                .scll()
+                .bits(self.scll)
+        });
+    }
+
+    /// Work out the timing for the fastest SCL frequency not exceeding `speed` from the
+    /// peripheral clock `clock`
+    fn new(clock: u32, speed: u32) -> Result<Timing, &'static str> {
+        if !SPEEDS.contains(&speed) {
+            return Err("unsupported i2c speed");
+        }
+
+        // Standard mode uses a symmetric clock while fast mode needs a longer low period, the
+        // data setup and hold times follow the examples of the reference manual
+        let (low_share, setup_ns, hold_ns) = if speed <= 100_000 {
+            ((1, 2), 1250, 500)
+        } else {
+            ((2, 3), 500, 125)
+        };
+
+        for presc in 0..16 {
+            let tick = clock / (presc + 1);
+            let period = tick.div_ceil(speed);
+            let low = period * low_share.0 / low_share.1;
+            let high = period - low;
+
+            if high > 256 || low > 256 {
+                continue;
+            }
+
+            let ticks = |ns: u32| (tick / 1000 * ns).div_ceil(1_000_000).clamp(1, 16);
+
+            return Ok(Timing {
+                presc: presc as u8,
+                scldel: (ticks(setup_ns) - 1) as u8,
+                sdadel: ticks(hold_ns).min(15) as u8,
+                sclh: (high - 1) as u8,
+                scll: (low - 1) as u8,
+                frequency: tick / period,
+            });
+        }
+
+        Err("unsupported i2c speed")
+    }
+}
+
+/// Turn the outcome of an I2C operation into the matching reply
+pub fn reply<'a>(res: Result<(), Error>, data: &'a [u8]) -> Reply<'a> {
+    match res {
+        Ok(()) if data.is_empty() => Reply::Ok,
+        Ok(()) => Reply::Data { data },
+        Err(Error::Nack) => Reply::Nack,
+        Err(Error::Address) => Reply::VerboseErr {
+            err: "invalid i2c address",
+        },
+        Err(Error::Timeout) => Reply::VerboseErr { err: "i2c timeout" },
+        Err(Error::Bus) => Reply::VerboseErr {
+            err: "i2c bus error",
+        },
+    }
+}
@@ -4,21 +4,34 @@
 
 use panic_halt as _;
 
+mod capture;
+mod encoder;
+mod i2c;
+mod measure;
+mod onewire;
+mod pins;
+mod record;
+mod spi;
+mod timer;
+mod waveform;
+
 use stm32f0xx_hal as hal;
 
 use cortex_m_rt::entry;
 
-use crate::hal::{i2c::I2c, prelude::*, serial::Serial, spi::Spi, stm32};
+use crate::hal::{prelude::*, serial::Serial, stm32, timers::Timer};
 
-use cortex_m::peripheral::Peripherals;
-use nb::block;
+use cortex_m::peripheral::{Peripherals, SYST};
 
 use core::mem::transmute_copy;
 
 use heapless::{consts::*, Vec};
-use postcard::{from_bytes, to_vec};
+use postcard::{from_bytes, take_from_bytes, to_vec};
 
-use bridge_common::encoding::{Reply, Request};
+use bridge_common::encoding::{
+    Reply, Request, I2C_SCAN_ADDRESSES, MAX_DELAY_US, MAX_MEASURE_TIMEOUT_MS, MAX_READ_LENGTH,
+};
+use bridge_common::framing::RequestBuffer;
 
 use stm32f0xx_hal::gpio::{gpioa, gpiob, gpiof};
 
@@ -30,6 +43,9 @@ use stm32f0xx_hal::gpio::{Input, Output};
 
 type BufferLength = U64;
 
+/// Rate of the SysTick timer used to detect idle periods on the serial line
+const TICK_HZ: u32 = 1000;
+
 trait PORTExt {
     fn clone(&self) -> Self;
 }
@@ -91,9 +107,51 @@ fn send_serial_reply<T: embedded_hal::serial::Write<u8>>(serial: &mut T, reply:
     serial.flush().ok();
 }
 
+/// A deadline for busy waiting, e.g. on a peripheral
+///
+/// Timed by the counter of the SysTick timer, which runs at the system clock and keeps running
+/// while the ticker waits for its next tick. The timeout has to be checked at least once per
+/// tick to not miss a wrap around of the counter.
+pub struct Timeout {
+    remaining: u32,
+    last: u32,
+    reload: u32,
+}
+
+impl Timeout {
+    pub fn us(us: u32, sysclk: u32) -> Self {
+        Timeout {
+            remaining: us * (sysclk / 1_000_000),
+            last: SYST::get_current(),
+            reload: SYST::get_reload() + 1,
+        }
+    }
+
+    pub fn expired(&mut self) -> bool {
+        // The counter counts down and wraps around to the reload value
+        let now = SYST::get_current();
+        let elapsed = if now <= self.last {
+            self.last - now
+        } else {
+            self.last + self.reload - now
+        };
+        self.remaining = self.remaining.saturating_sub(elapsed);
+        self.last = now;
+
+        self.remaining == 0
+    }
+}
+
+/// Busy wait for `us` microseconds
+#[inline(never)]
+fn delay_us(us: u32, sysclk: u32) {
+    let mut timeout = Timeout::us(us, sysclk);
+    while !timeout.expired() {}
+}
+
 #[entry]
 fn main() -> ! {
-    if let (Some(mut p), Some(_cp)) = (stm32::Peripherals::take(), Peripherals::take()) {
+    if let (Some(mut p), Some(cp)) = (stm32::Peripherals::take(), Peripherals::take()) {
         let mut rcc = p.RCC.configure().sysclk(48.mhz()).freeze(&mut p.FLASH);
 
         // Obtain resources from GPIO ports A, B, C and F
@@ -103,18 +161,10 @@ fn main() -> ! {
         let gpioc = p.GPIOC.split(&mut rcc);
         let gpiof = p.GPIOF.split(&mut rcc);
 
-        #[cfg(any(feature = "stm32f042",))]
-        const HAS_I2C_ON_PORT_F: bool = true;
-        #[cfg(not(feature = "stm32f042",))]
-        const HAS_I2C_ON_PORT_F: bool = false;
-
-        #[cfg(any(feature = "stm32f042",))]
-        let mut i2c: Option<hal::i2c::I2c<_, _, _>> = None;
-
-        let mut spi: Option<hal::spi::Spi<_, _, _, _>> = None;
-
-        #[cfg(not(feature = "stm32f042",))]
-        let i2c: Option<bool> = None;
+        /* Buses set up by the host, at most one per instance */
+        let mut i2cs: Vec<i2c::I2c, U2> = Vec::new();
+        let mut spis: Vec<spi::Spi, U2> = Vec::new();
+        let mut capture: Option<capture::Capture> = None;
 
         /* Set up serial port */
         let (tx, rx) = cortex_m::interrupt::free(|cs| {
@@ -124,12 +174,18 @@ fn main() -> ! {
             (pa2.into_alternate_af1(cs), pa15.into_alternate_af1(cs))
         });
 
-        let gpioa_clone = gpioa.clone();
-        let gpiof_clone = gpiof.clone();
-
         let mut serial = Serial::usart2(p.USART2, (tx, rx), 115_200.bps(), &mut rcc);
 
-        let mut buffer: Vec<u8, BufferLength> = Default::default();
+        let mut buffer = RequestBuffer::new();
+
+        /* Identifier handed out by the host, lets it detect that we've been reset */
+        let mut session: u32 = 0;
+
+        /* Bytes received from a bus, to be sent back with the reply */
+        let mut read_buffer = [0_u8; MAX_READ_LENGTH];
+
+        /* Count idle time between received bytes to be able to discard incomplete requests */
+        let mut ticker = Timer::syst(cp.SYST, TICK_HZ.hz(), &rcc);
 
         GPIO!(
             [gpioa :
@@ -189,153 +245,474 @@ fn main() -> ! {
             Reply::Ok
         };
 
-        loop {
-            if let Ok(received) = block!(serial.read()) {
-                if buffer.push(received).is_err() {
-                    send_serial_reply(
-                        &mut serial,
-                        &Reply::ReceiveErr {
-                            bytes: buffer.len() as u8,
+        let sysclk = rcc.clocks.sysclk().0;
+        let pclk = rcc.clocks.pclk().0;
+
+        /* Execute a request changing a GPIO output, writing to a bus or waiting, these can be batched */
+        let output = |request: Request,
+                      i2cs: &mut Vec<i2c::I2c, U2>,
+                      spis: &mut Vec<spi::Spi, U2>|
+         -> Reply<'static> {
+            match request {
+                Request::GpioToggle { pin } => apply_gpio(pin, &|p: &dyn GPIOExt| p.toggle()),
+                Request::GpioSetLow { pin } => apply_gpio(pin, &|p: &dyn GPIOExt| p.set_low()),
+                Request::GpioSetHigh { pin } => apply_gpio(pin, &|p: &dyn GPIOExt| p.set_high()),
+                Request::I2CWrite {
+                    ident,
+                    address,
+                    data,
+                } => match i2cs.iter_mut().find(|bus| bus.ident == ident) {
+                    Some(bus) => i2c::reply(bus.write(address, data), &[]),
+                    None => Reply::NotImplemented {},
+                },
+                Request::I2CWriteMore {
+                    ident,
+                    address,
+                    data,
+                } => match i2cs.iter_mut().find(|bus| bus.ident == ident) {
+                    Some(bus) => i2c::reply(bus.write_more(address, data), &[]),
+                    None => Reply::NotImplemented {},
+                },
+                Request::SPIWrite { ident, data } => {
+                    match spis.iter_mut().find(|bus| bus.ident == ident) {
+                        Some(bus) => match bus.write(data) {
+                            Ok(()) => Reply::Ok {},
+                            Err(_) => Reply::VerboseErr { err: "spi error" },
                         },
-                    );
-                    buffer.clear();
+                        None => Reply::NotImplemented {},
+                    }
+                }
+                Request::GpioWaveform { pins, steps } => match waveform::play(pins, steps, pclk) {
+                    Ok(()) => Reply::Ok {},
+                    Err(err) => Reply::VerboseErr { err },
+                },
+                Request::Delay { us } if us <= MAX_DELAY_US => {
+                    delay_us(us, sysclk);
+                    Reply::Ok {}
+                }
+                _ => Reply::NotImplemented {},
+            }
+        };
+
+        loop {
+            match serial.read() {
+                Ok(received) => {
+                    if let Err(bytes) = buffer.push(received) {
+                        send_serial_reply(&mut serial, &Reply::ReceiveErr { bytes });
+                        continue;
+                    }
+                }
+                Err(nb::Error::WouldBlock) => {
+                    if ticker.wait().is_ok() {
+                        buffer.idle(1000 / TICK_HZ);
+                    }
+                    continue;
+                }
+                Err(nb::Error::Other(_)) => {
+                    /* A byte got lost so the rest of the request is useless */
+                    if let Some(bytes) = buffer.lost() {
+                        send_serial_reply(&mut serial, &Reply::ReceiveErr { bytes });
+                    }
                     continue;
                 }
             }
 
-            let reply = match from_bytes::<Request>(&buffer) {
+            let reply = match from_bytes::<Request>(buffer.bytes()) {
                 Ok(msg) => {
                     match msg {
                         Request::Version => bridge_common::encoding::current_version(),
                         Request::Clear => Reply::Ok {},
                         Request::Reset => Reply::NotImplemented {},
+                        Request::Session { id } => {
+                            let previous = session;
+                            session = id;
+                            Reply::Session { previous }
+                        }
+
+                        /* Refuse to work before the host knows that our configuration is gone */
+                        _ if session == 0 => Reply::Rebooted {},
+
                         Request::GpioInitPP { pin } => {
                             apply_gpio(pin, &|p: &dyn GPIOExt| p.to_output_push_pull())
                         }
 
-                        Request::GpioToggle { pin } => {
-                            apply_gpio(pin, &|p: &dyn GPIOExt| p.toggle())
+                        request @ (Request::GpioToggle { .. }
+                        | Request::GpioSetLow { .. }
+                        | Request::GpioSetHigh { .. }
+                        | Request::I2CWrite { .. }
+                        | Request::I2CWriteMore { .. }
+                        | Request::SPIWrite { .. }
+                        | Request::Delay { .. }
+                        | Request::GpioWaveform { .. }) => output(request, &mut i2cs, &mut spis),
+
+                        Request::CaptureStart {
+                            pins,
+                            rate,
+                            samples,
+                        } => match capture::Capture::start(pins, rate, samples, pclk) {
+                            Ok(started) => {
+                                let hz = started.rate();
+                                capture = Some(started);
+                                Reply::Frequency { hz }
+                            }
+                            Err(err) => Reply::VerboseErr { err },
+                        },
+
+                        Request::CaptureRead { offset } => match capture {
+                            Some(ref capture) => {
+                                let len = capture.read(usize::from(offset), &mut read_buffer);
+                                Reply::Data {
+                                    data: &read_buffer[..len],
+                                }
+                            }
+                            None => Reply::NotImplemented {},
+                        },
+
+                        Request::Measure { pin, timeout_ms }
+                            if timeout_ms <= MAX_MEASURE_TIMEOUT_MS =>
+                        {
+                            let mut timeout = Timeout::us(u32::from(timeout_ms) * 1_000, sysclk);
+                            match measure::measure(pin, pclk, &mut timeout) {
+                                Ok(measurement) => Reply::Measurement {
+                                    clock: measurement.clock,
+                                    period: measurement.period,
+                                    high: measurement.high,
+                                },
+                                Err(err) => Reply::VerboseErr { err },
+                            }
                         }
+                        Request::Measure { .. } => Reply::NotImplemented {},
+
+                        Request::EncoderInit {
+                            ident,
+                            a_pin,
+                            b_pin,
+                        } => match encoder::init(ident, a_pin, b_pin) {
+                            Ok(()) => Reply::Ok {},
+                            Err(err) => Reply::VerboseErr { err },
+                        },
+
+                        Request::EncoderRead { ident } => match encoder::read(ident) {
+                            Ok((count, bits, down)) => Reply::Position { count, bits, down },
+                            Err(err) => Reply::VerboseErr { err },
+                        },
+
+                        Request::EncoderReset { ident } => match encoder::reset(ident) {
+                            Ok(()) => Reply::Ok {},
+                            Err(err) => Reply::VerboseErr { err },
+                        },
 
-                        Request::GpioSetLow { pin } => {
-                            apply_gpio(pin, &|p: &dyn GPIOExt| p.set_low())
+                        request @ (Request::OneWireReset { .. }
+                        | Request::OneWireWrite { .. }
+                        | Request::OneWireRead { .. }
+                        | Request::OneWireBit { .. }
+                        | Request::OneWireSearch { .. }) => {
+                            onewire::execute(request, sysclk, &mut read_buffer)
                         }
 
-                        Request::GpioSetHigh { pin } => {
-                            apply_gpio(pin, &|p: &dyn GPIOExt| p.set_high())
+                        Request::Batch { mut requests } => {
+                            let mut reply = Reply::Ok {};
+                            while !requests.is_empty() && reply == Reply::Ok {
+                                reply = match take_from_bytes::<Request>(requests) {
+                                    Ok((request, rest)) => {
+                                        requests = rest;
+                                        output(request, &mut i2cs, &mut spis)
+                                    }
+                                    Err(_) => Reply::VerboseErr {
+                                        err: "invalid batch",
+                                    },
+                                };
+                            }
+                            reply
                         }
 
                         Request::I2CInit {
+                            ident,
                             scl_pin,
                             sda_pin,
                             speed,
                         } => {
-                            if HAS_I2C_ON_PORT_F
-                                && scl_pin == "f1"
-                                && sda_pin == "f0"
-                                && (speed >= 10 || speed <= 400)
-                            {
-                                #[cfg(any(feature = "stm32f042",))]
-                                {
-                                    let gpiof = gpiof_clone.clone();
-                                    let (scl, sda) = cortex_m::interrupt::free(|cs| {
-                                        let scl = gpiof
-                                            .pf1
-                                            .into_alternate_af1(cs)
-                                            .internal_pull_up(cs, true)
-                                            .set_open_drain(cs);
-                                        let sda = gpiof
-                                            .pf0
-                                            .into_alternate_af1(cs)
-                                            .internal_pull_up(cs, true)
-                                            .set_open_drain(cs);
-                                        (scl, sda)
+                            let pclk = rcc.clocks.pclk().0;
+                            let sysclk = rcc.clocks.sysclk().0;
+                            match i2c::I2c::new(ident, scl_pin, sda_pin, speed, pclk, sysclk) {
+                                Ok(bus) => {
+                                    let hz = bus.frequency();
+                                    i2c::with_targets(|targets| {
+                                        if let Some(i) =
+                                            targets.iter().position(|t| t.ident == bus.ident)
+                                        {
+                                            targets.swap_remove(i);
+                                        }
                                     });
+                                    match i2cs.iter_mut().find(|b| b.ident == bus.ident) {
+                                        Some(previous) => *previous = bus,
+                                        None => i2cs.push(bus).ok().unwrap_or_default(),
+                                    }
+                                    Reply::Frequency { hz }
+                                }
+                                Err(err) => Reply::VerboseErr { err },
+                            }
+                        }
 
-                                    let i2c1 = unsafe { transmute_copy(&p.I2C1) };
+                        Request::I2CRead {
+                            ident,
+                            address,
+                            len,
+                        } => match i2cs.iter_mut().find(|bus| bus.ident == ident) {
+                            Some(bus) if usize::from(len) <= MAX_READ_LENGTH => {
+                                let buffer = &mut read_buffer[..usize::from(len)];
+                                let res = bus.read(address, buffer);
+                                i2c::reply(res, buffer)
+                            }
+                            _ => Reply::NotImplemented {},
+                        },
+
+                        Request::I2CWriteRead {
+                            ident,
+                            address,
+                            data,
+                            len,
+                        } => match i2cs.iter_mut().find(|bus| bus.ident == ident) {
+                            Some(bus) if usize::from(len) <= MAX_READ_LENGTH => {
+                                let buffer = &mut read_buffer[..usize::from(len)];
+                                let res = bus.write_read(address, data, buffer);
+                                i2c::reply(res, buffer)
+                            }
+                            _ => Reply::NotImplemented {},
+                        },
+
+                        Request::I2CScan { ident } => {
+                            match i2cs.iter_mut().find(|bus| bus.ident == ident) {
+                                Some(bus) => {
+                                    let found = &mut read_buffer[..16];
+                                    found.iter_mut().for_each(|b| *b = 0);
 
-                                    // Setup I2C1
-                                    i2c = Some(I2c::i2c1(i2c1, (scl, sda), speed.khz(), &mut rcc));
+                                    for addr in I2C_SCAN_ADDRESSES {
+                                        if bus.probe(addr) {
+                                            found[usize::from(addr / 8)] |= 1 << (addr % 8);
+                                        }
+                                    }
+
+                                    Reply::Data { data: found }
                                 }
+                                None => Reply::NotImplemented {},
+                            }
+                        }
 
-                                Reply::Ok {}
-                            } else {
-                                Reply::NotImplemented {}
+                        Request::I2CRecover { ident } => {
+                            match i2cs.iter_mut().find(|bus| bus.ident == ident) {
+                                Some(bus) => {
+                                    if bus.recover() {
+                                        Reply::Ok {}
+                                    } else {
+                                        Reply::VerboseErr {
+                                            err: "sda stuck low",
+                                        }
+                                    }
+                                }
+                                None => Reply::NotImplemented {},
                             }
                         }
 
-                        Request::I2CWrite {
+                        Request::I2CTargetInit {
                             ident,
+                            scl_pin,
+                            sda_pin,
                             address,
-                            data,
                         } => {
-                            if HAS_I2C_ON_PORT_F && ident == "i2c1" && i2c.is_some() {
-                                #[cfg(any(feature = "stm32f042",))]
-                                {
-                                    i2c.as_mut().map(|i2c| i2c.write(address, data));
+                            let pclk = rcc.clocks.pclk().0;
+                            let res = i2c::with_targets(|targets| {
+                                let target =
+                                    i2c::Target::new(ident, scl_pin, sda_pin, address, pclk)?;
+                                let ident = target.ident;
+                                match targets.iter_mut().find(|t| t.ident == ident) {
+                                    Some(previous) => *previous = target,
+                                    None => targets.push(target).ok().unwrap_or_default(),
+                                }
+                                Ok(ident)
+                            });
+                            match res {
+                                Ok(ident) => {
+                                    if let Some(i) = i2cs.iter().position(|b| b.ident == ident) {
+                                        i2cs.swap_remove(i);
+                                    }
+                                    Reply::Ok {}
                                 }
+                                Err(err) => Reply::VerboseErr { err },
+                            }
+                        }
 
-                                Reply::Ok {}
-                            } else {
-                                Reply::NotImplemented {}
+                        Request::I2CTargetSet {
+                            ident,
+                            register,
+                            data,
+                        } => {
+                            let found = i2c::with_targets(|targets| {
+                                let target = targets.iter_mut().find(|t| t.ident == ident)?;
+                                target.set_registers(register, data);
+                                Some(())
+                            });
+                            match found {
+                                Some(()) => Reply::Ok {},
+                                None => Reply::NotImplemented {},
+                            }
+                        }
+
+                        Request::I2CTargetPoll { ident } => {
+                            let taken = i2c::with_targets(|targets| {
+                                let target = targets.iter_mut().find(|t| t.ident == ident)?;
+                                Some(target.take_writes(&mut read_buffer))
+                            });
+                            match taken {
+                                Some(len) => Reply::Data {
+                                    data: &read_buffer[..len],
+                                },
+                                None => Reply::NotImplemented {},
                             }
                         }
 
                         Request::SPIInit {
+                            ident,
                             sck_pin,
                             miso_pin,
                             mosi_pin,
                             speed,
                         } => {
-                            if sck_pin == "a5"
-                                && miso_pin == "a6"
-                                && mosi_pin == "a7"
-                                && (speed >= 10 || speed <= 400)
-                            {
-                                let gpioa = gpioa_clone.clone();
-                                let (sck, miso, mosi) = cortex_m::interrupt::free(move |cs| {
-                                    (
-                                        gpioa.pa5.into_alternate_af0(cs),
-                                        gpioa.pa6.into_alternate_af0(cs),
-                                        gpioa.pa7.into_alternate_af0(cs),
-                                    )
-                                });
-
-                                let spi1 = unsafe { transmute_copy(&p.SPI1) };
-
-                                use embedded_hal::spi::{Mode, Phase, Polarity};
-
-                                /// SPI mode that is needed for this crate
-                                ///
-                                /// Provided for convenience
-                                const MODE: Mode = Mode {
-                                    polarity: Polarity::IdleHigh,
-                                    phase: Phase::CaptureOnSecondTransition,
-                                };
+                            let pclk = rcc.clocks.pclk().0;
+                            match spi::Spi::new(ident, sck_pin, miso_pin, mosi_pin, speed, pclk) {
+                                Ok(bus) => {
+                                    let hz = bus.frequency();
+                                    spi::with_targets(|targets| {
+                                        if let Some(i) =
+                                            targets.iter().position(|t| t.ident == bus.ident)
+                                        {
+                                            targets.swap_remove(i);
+                                        }
+                                    });
+                                    match spis.iter_mut().find(|b| b.ident == bus.ident) {
+                                        Some(previous) => *previous = bus,
+                                        None => spis.push(bus).ok().unwrap_or_default(),
+                                    }
+                                    Reply::Frequency { hz }
+                                }
+                                Err(err) => Reply::VerboseErr { err },
+                            }
+                        }
 
-                                // Setup SPI1
-                                spi = Some(Spi::spi1(
-                                    spi1,
-                                    (sck, miso, mosi),
-                                    MODE,
-                                    speed.khz(),
-                                    &mut rcc,
-                                ));
+                        Request::SPITargetInit {
+                            ident,
+                            sck_pin,
+                            miso_pin,
+                            mosi_pin,
+                            cs_pin,
+                            mode,
+                        } => {
+                            let res = spi::with_targets(|targets| {
+                                let target = spi::Target::new(
+                                    ident, sck_pin, miso_pin, mosi_pin, cs_pin, mode,
+                                )?;
+                                let ident = target.ident;
+                                match targets.iter_mut().find(|t| t.ident == ident) {
+                                    Some(previous) => *previous = target,
+                                    None => targets.push(target).ok().unwrap_or_default(),
+                                }
+                                Ok(ident)
+                            });
+                            match res {
+                                Ok(ident) => {
+                                    if let Some(i) = spis.iter().position(|b| b.ident == ident) {
+                                        spis.swap_remove(i);
+                                    }
+                                    Reply::Ok {}
+                                }
+                                Err(err) => Reply::VerboseErr { err },
+                            }
+                        }
 
-                                Reply::Ok {}
-                            } else {
-                                Reply::NotImplemented {}
+                        Request::SPITargetLoad { ident, data } => {
+                            let loaded = spi::with_targets(|targets| {
+                                let target = targets.iter_mut().find(|t| t.ident == ident)?;
+                                Some(target.load(data))
+                            });
+                            match loaded {
+                                Some(Ok(())) => Reply::Ok {},
+                                Some(Err(err)) => Reply::VerboseErr { err },
+                                None => Reply::NotImplemented {},
                             }
                         }
 
-                        Request::SPIWrite { ident, data } => {
-                            if ident == "spi1" && spi.is_some() {
-                                spi.as_mut().map(|spi| spi.write(data));
-                                Reply::Ok {}
+                        Request::SPITargetPoll { ident } => {
+                            let taken = spi::with_targets(|targets| {
+                                let target = targets.iter_mut().find(|t| t.ident == ident)?;
+                                Some(target.take_frames(&mut read_buffer))
+                            });
+                            match taken {
+                                Some(len) => Reply::Data {
+                                    data: &read_buffer[..len],
+                                },
+                                None => Reply::NotImplemented {},
+                            }
+                        }
+
+                        Request::SPITransfer { ident, data } => {
+                            match spis.iter_mut().find(|bus| bus.ident == ident) {
+                                Some(bus) if data.len() <= MAX_READ_LENGTH => {
+                                    let words = &mut read_buffer[..data.len()];
+                                    words.copy_from_slice(data);
+
+                                    match bus.transfer(words) {
+                                        Ok(data) => Reply::Data { data },
+                                        Err(_) => Reply::VerboseErr { err: "spi error" },
+                                    }
+                                }
+                                _ => Reply::NotImplemented {},
+                            }
+                        }
+
+                        Request::SPIDeviceInit { ident, cs_pin } => {
+                            if spis.iter().any(|bus| bus.ident == ident) {
+                                apply_gpio(cs_pin, &|p: &dyn GPIOExt| {
+                                    p.set_high();
+                                    p.to_output_push_pull()
+                                })
                             } else {
                                 Reply::NotImplemented {}
                             }
                         }
+
+                        Request::SPITransaction {
+                            ident,
+                            cs_pin,
+                            mode,
+                            data,
+                        } => match spis.iter_mut().find(|bus| bus.ident == ident) {
+                            Some(bus) if mode <= 3 && data.len() <= MAX_READ_LENGTH => {
+                                let words = &mut read_buffer[..data.len()];
+                                words.copy_from_slice(data);
+
+                                /* The clock has to idle at the level of the mode before the
+                                 * device is selected, and until after it is released */
+                                let previous = bus.set_mode(mode);
+
+                                let selected = apply_gpio(cs_pin, &|p: &dyn GPIOExt| p.set_low());
+                                let reply = match selected {
+                                    Reply::Ok => {
+                                        let res = bus.transfer(words);
+                                        apply_gpio(cs_pin, &|p: &dyn GPIOExt| p.set_high());
+
+                                        match res {
+                                            Ok(data) => Reply::Data { data },
+                                            Err(_) => Reply::VerboseErr { err: "spi error" },
+                                        }
+                                    }
+                                    reply => reply,
+                                };
+
+                                bus.set_mode(previous);
+                                reply
+                            }
+                            _ => Reply::NotImplemented {},
+                        },
                     }
                 }
                 Err(err) => match err {
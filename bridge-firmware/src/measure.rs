@@ -0,0 +1,129 @@
+use crate::timer::{CC1IF, CC2IF, INSTANCES, UIF};
+use crate::Timeout;
+
+use crate::hal::stm32::tim2::RegisterBlock;
+
+/// A period of a signal and the time it spent high, in ticks of a `clock` Hz counter
+pub struct Measurement {
+    pub clock: u32,
+    pub period: u32,
+    pub high: u32,
+}
+
+/// Measure the signal at the pin `name` until `timeout` expires
+///
+/// The timer runs in PWM input mode: the channel of the pin captures the counter and resets it
+/// at each rising edge, the other channel of the pair captures it at each falling edge. The
+/// counter starts at the full peripheral clock for the best resolution and is slowed down
+/// whenever it overflows before a full period was seen, which only happens for the 16 bit
+/// TIM3. Signals faster than about a quarter of the peripheral clock cannot be measured.
+pub fn measure(name: &str, pclk: u32, timeout: &mut Timeout) -> Result<Measurement, &'static str> {
+    let (instance, (pin, af), second) = INSTANCES
+        .iter()
+        .find_map(|instance| {
+            instance
+                .ch1(name)
+                .map(|route| (instance, route, false))
+                .or_else(|| instance.ch2(name).map(|route| (instance, route, true)))
+        })
+        .ok_or("invalid measure pin")?;
+
+    instance.reset();
+    pin.into_alternate(af, false);
+
+    let tim = instance.regs();
+    let mut prescaler: u32 = 1;
+    let result = loop {
+        match capture(tim, second, prescaler, timeout) {
+            Some((period, high)) => {
+                break Ok(Measurement {
+                    clock: pclk / prescaler,
+                    period,
+                    high,
+                })
+            }
+            None if timeout.expired() => break Err("no signal within timeout"),
+            None if prescaler < 0x1_0000 => prescaler *= 16,
+            None => break Err("signal too slow"),
+        }
+    };
+
+    tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+    result
+}
+
+/// Capture a single period with the counter running at a `prescaler`th of the timer clock,
+/// returns `None` if the timeout expires or the counter overflows first
+///
+/// With `second` set the signal is connected to the second channel of the pair.
+fn capture(
+    tim: &RegisterBlock,
+    second: bool,
+    prescaler: u32,
+    timeout: &mut Timeout,
+) -> Option<(u32, u32)> {
+    // Capture flag of the channel of the pin in the status register
+    let rising = if second { CC2IF } else { CC1IF };
+
+    tim.cr1.write(|w| w.cen().clear_bit().urs().set_bit());
+
+    // Both channels capture the input of the pin, the channel of the pin on rising edges
+    tim.ccer.write(|w| {
+        w.cc1e()
+            .set_bit()
+            .cc1p()
+            .bit(second)
+            .cc2e()
+            .set_bit()
+            .cc2p()
+            .bit(!second)
+    });
+    tim.ccmr1_input_mut().write(|w| {
+        if second {
+            w.cc1s().ti2().cc2s().ti2()
+        } else {
+            w.cc1s().ti1().cc2s().ti1()
+        }
+    });
+
+    // Reset the counter at every rising edge
+    tim.smcr.write(|w| {
+        if second {
+            w.ts().ti2fp2().sms().reset_mode()
+        } else {
+            w.ts().ti1fp1().sms().reset_mode()
+        }
+    });
+
+    tim.psc.write(|w| w.psc().bits((prescaler - 1) as u16));
+    tim.egr.write(|w| w.ug().set_bit());
+    tim.sr.reset();
+    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+    // The counter is only reset at a rising edge, so the first capture is not a full period
+    while tim.sr.read().bits() & rising == 0 {
+        if timeout.expired() {
+            return None;
+        }
+    }
+    tim.sr.reset();
+
+    loop {
+        let sr = tim.sr.read().bits();
+        if sr & UIF != 0 || timeout.expired() {
+            return None;
+        }
+        if sr & rising != 0 {
+            break;
+        }
+    }
+
+    let (period, high) = if second {
+        (tim.ccr2.read().bits(), tim.ccr1.read().bits())
+    } else {
+        (tim.ccr1.read().bits(), tim.ccr2.read().bits())
+    };
+
+    Some((period, high))
+}
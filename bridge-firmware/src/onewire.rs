@@ -0,0 +1,151 @@
+use crate::delay_us;
+use crate::pins::{Pin, RESERVED};
+
+use bridge_common::encoding::{onewire_search_branch, Reply, Request, MAX_READ_LENGTH};
+
+/// Command starting a search for the ROM codes of all devices on the bus
+const SEARCH_ROM: u8 = 0xf0;
+
+/// A 1-Wire bus master bit-banging an open drain pin at standard speed
+///
+/// The bus needs an external pull up resistor of about 4.7 kOhm, the internal pull up is
+/// enabled in addition but much too weak on its own. Interrupts are disabled for each time slot
+/// to keep the timing.
+pub struct OneWire {
+    pin: Pin,
+    sysclk: u32,
+}
+
+impl OneWire {
+    /// Take over the pin `name`, releasing the bus
+    pub fn new(name: &str, sysclk: u32) -> Result<Self, &'static str> {
+        let pin = match Pin::from_name(name) {
+            Some(pin) if !RESERVED.contains(&name) => pin,
+            _ => return Err("invalid 1-wire pin"),
+        };
+
+        pin.set_high();
+        pin.set_pull_up(true);
+        pin.into_output(true);
+
+        Ok(OneWire { pin, sysclk })
+    }
+
+    /// Send a reset pulse and return whether any device answered with a presence pulse
+    pub fn reset(&self) -> bool {
+        self.pin.set_low();
+        delay_us(480, self.sysclk);
+
+        let present = cortex_m::interrupt::free(|_| {
+            self.pin.set_high();
+            delay_us(70, self.sysclk);
+            !self.pin.is_high()
+        });
+
+        // Let the presence pulse end and the bus recover
+        delay_us(410, self.sysclk);
+
+        present
+    }
+
+    /// Run a time slot writing `bit` and return the level of the bus sampled within it
+    pub fn touch_bit(&self, bit: bool) -> bool {
+        cortex_m::interrupt::free(|_| {
+            self.pin.set_low();
+            if bit {
+                delay_us(6, self.sysclk);
+                self.pin.set_high();
+                delay_us(9, self.sysclk);
+                let level = self.pin.is_high();
+                delay_us(55, self.sysclk);
+                level
+            } else {
+                delay_us(60, self.sysclk);
+                self.pin.set_high();
+                delay_us(10, self.sysclk);
+                false
+            }
+        })
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        for i in 0..8 {
+            self.touch_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | u8::from(self.touch_bit(true)) << i)
+    }
+
+    /// Find the ROM code following `rom` in a search, see `Request::OneWireSearch`
+    ///
+    /// Returns the ROM code found and the bit at which the next search has to take the other
+    /// branch, counted from 1, or 0 if there are no more devices.
+    pub fn search(&self, rom: u64, discrepancy: u8) -> Result<(u64, u8), &'static str> {
+        if !self.reset() {
+            return Err("no 1-wire device present");
+        }
+        self.write_byte(SEARCH_ROM);
+
+        let mut found = 0;
+        let mut last_zero = 0;
+        for bit in 1..=64 {
+            let id = self.touch_bit(true);
+            let complement = self.touch_bit(true);
+
+            let direction = onewire_search_branch(bit, id, complement, rom, discrepancy)
+                .ok_or("1-wire devices vanished during search")?;
+
+            if !id && !complement && !direction {
+                last_zero = bit;
+            }
+
+            found |= u64::from(direction) << (bit - 1);
+            self.touch_bit(direction);
+        }
+
+        Ok((found, last_zero))
+    }
+}
+
+/// Execute one of the 1-Wire requests, reading into `buffer`
+pub fn execute<'a>(request: Request, sysclk: u32, buffer: &'a mut [u8]) -> Reply<'a> {
+    let bus = match request {
+        Request::OneWireReset { pin }
+        | Request::OneWireWrite { pin, .. }
+        | Request::OneWireRead { pin, .. }
+        | Request::OneWireBit { pin, .. }
+        | Request::OneWireSearch { pin, .. } => OneWire::new(pin, sysclk),
+        _ => return Reply::NotImplemented {},
+    };
+    let bus = match bus {
+        Ok(bus) => bus,
+        Err(err) => return Reply::VerboseErr { err },
+    };
+
+    match request {
+        Request::OneWireReset { .. } if bus.reset() => Reply::Ok {},
+        Request::OneWireReset { .. } => Reply::Nack {},
+        Request::OneWireWrite { data, .. } => {
+            data.iter().for_each(|&byte| bus.write_byte(byte));
+            Reply::Ok {}
+        }
+        Request::OneWireRead { len, .. } if usize::from(len) <= MAX_READ_LENGTH => {
+            let data = &mut buffer[..usize::from(len)];
+            data.iter_mut().for_each(|byte| *byte = bus.read_byte());
+            Reply::Data { data }
+        }
+        Request::OneWireBit { bit, .. } => {
+            buffer[0] = u8::from(bus.touch_bit(bit));
+            Reply::Data { data: &buffer[..1] }
+        }
+        Request::OneWireSearch {
+            rom, discrepancy, ..
+        } => match bus.search(rom, discrepancy) {
+            Ok((rom, discrepancy)) => Reply::Rom { rom, discrepancy },
+            Err(err) => Reply::VerboseErr { err },
+        },
+        _ => Reply::NotImplemented {},
+    }
+}
@@ -0,0 +1,233 @@
+use crate::hal::stm32;
+
+/// Pins of the serial link to the host, which must never be driven
+pub const RESERVED: [&str; 2] = ["a2", "a15"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Port {
+    A,
+    B,
+    #[cfg(any(feature = "stm32f072",))]
+    C,
+    F,
+}
+
+/// A pin which is configured by writing the GPIO registers directly
+///
+/// Used for pins handed to peripherals, which need to be set up at runtime according to the
+/// requests of the host.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Pin {
+    port: Port,
+    index: u8,
+}
+
+/// A pin a peripheral signal can be routed to together with the alternate function selecting it
+pub struct Route {
+    pub pin: &'static str,
+    pub af: u8,
+}
+
+/// A peripheral instance of the chip which the host refers to by name, e.g. "i2c1"
+pub trait Named: 'static {
+    /// Error reported for a name the chip has no instance of
+    const UNKNOWN: &'static str;
+
+    fn ident(&self) -> &'static str;
+}
+
+/// Look up the instance `ident` among `instances`
+pub fn lookup<N: Named>(instances: &'static [N], ident: &str) -> Result<&'static N, &'static str> {
+    instances
+        .iter()
+        .find(|i| i.ident() == ident)
+        .ok_or(N::UNKNOWN)
+}
+
+/// Find the alternate function routing a signal to the pin `name`
+pub fn route(routes: &[Route], name: &str) -> Option<(Pin, u8)> {
+    let route = routes.iter().find(|route| route.pin == name)?;
+    Some((Pin::from_name(route.pin)?, route.af))
+}
+
+/// Run `$body` with `$regs` bound to the register block of the port of `$pin`
+macro_rules! with_port {
+    ($pin:expr, |$regs:ident| $body:expr) => {
+        match $pin.port {
+            Port::A => {
+                let $regs = unsafe { &*stm32::GPIOA::ptr() };
+                $body
+            }
+            Port::B => {
+                let $regs = unsafe { &*stm32::GPIOB::ptr() };
+                $body
+            }
+            #[cfg(any(feature = "stm32f072",))]
+            Port::C => {
+                let $regs = unsafe { &*stm32::GPIOC::ptr() };
+                $body
+            }
+            Port::F => {
+                let $regs = unsafe { &*stm32::GPIOF::ptr() };
+                $body
+            }
+        }
+    };
+}
+
+impl Pin {
+    /// Look up a pin by the name used in requests, e.g. "a5"
+    pub fn from_name(name: &str) -> Option<Pin> {
+        let port = match name.get(..1)? {
+            "a" => Port::A,
+            "b" => Port::B,
+            #[cfg(any(feature = "stm32f072",))]
+            "c" => Port::C,
+            "f" => Port::F,
+            _ => return None,
+        };
+
+        match name.get(1..)?.parse() {
+            Ok(index) if index < 16 => Some(Pin { port, index }),
+            _ => None,
+        }
+    }
+
+    /// Hand the pin to the peripheral behind alternate function `af`
+    pub fn into_alternate(self, af: u8, open_drain: bool) {
+        let i = u32::from(self.index);
+
+        cortex_m::interrupt::free(|_| {
+            with_port!(self, |regs| {
+                let afr = (i % 8) * 4;
+                if i < 8 {
+                    regs.afrl.modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(0xf << afr) | u32::from(af) << afr)
+                    });
+                } else {
+                    regs.afrh.modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(0xf << afr) | u32::from(af) << afr)
+                    });
+                }
+
+                self.set_open_drain(open_drain);
+                regs.moder.modify(|r, w| unsafe {
+                    w.bits(r.bits() & !(0b11 << (2 * i)) | 0b10 << (2 * i))
+                });
+            })
+        });
+    }
+
+    /// Turn the pin into an output, driven low only if `open_drain` is set
+    pub fn into_output(self, open_drain: bool) {
+        let i = u32::from(self.index);
+
+        cortex_m::interrupt::free(|_| {
+            self.set_open_drain(open_drain);
+            with_port!(self, |regs| {
+                regs.moder.modify(|r, w| unsafe {
+                    w.bits(r.bits() & !(0b11 << (2 * i)) | 0b01 << (2 * i))
+                })
+            });
+        });
+    }
+
+    /// Enable or disable the internal pull up resistor
+    pub fn set_pull_up(self, on: bool) {
+        let i = u32::from(self.index);
+        let value = if on { 0b01 } else { 0b00 };
+
+        cortex_m::interrupt::free(|_| {
+            with_port!(self, |regs| {
+                regs.pupdr.modify(|r, w| unsafe {
+                    w.bits(r.bits() & !(0b11 << (2 * i)) | value << (2 * i))
+                })
+            });
+        });
+    }
+
+    fn set_open_drain(self, on: bool) {
+        let i = u32::from(self.index);
+
+        with_port!(self, |regs| {
+            regs.otyper
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << i) | u32::from(on) << i) })
+        });
+    }
+
+    pub fn set_high(self) {
+        with_port!(self, |regs| regs
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << self.index) }));
+    }
+
+    pub fn set_low(self) {
+        with_port!(self, |regs| regs
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << (self.index + 16)) }));
+    }
+
+    /// The index of the pin within its port
+    pub fn index(self) -> u8 {
+        self.index
+    }
+
+    /// The address of the input data register of the port, e.g. for DMA transfers
+    pub fn idr_address(self) -> u32 {
+        with_port!(self, |regs| &regs.idr as *const _ as u32)
+    }
+
+    pub fn is_high(self) -> bool {
+        with_port!(self, |regs| regs.idr.read().bits() & (1 << self.index) != 0)
+    }
+
+    /// Raise the interrupt of the EXTI line of the pin on rising edges
+    ///
+    /// The line has the index of the pin, so only one port can use it at a time.
+    pub fn listen_rising(self) {
+        let i = u32::from(self.index);
+        let port = match self.port {
+            Port::A => 0,
+            Port::B => 1,
+            #[cfg(any(feature = "stm32f072",))]
+            Port::C => 2,
+            Port::F => 5,
+        };
+        let shift = (i % 4) * 4;
+        let select = |bits: u32| bits & !(0xf << shift) | port << shift;
+
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        let syscfg = unsafe { &*stm32::SYSCFG::ptr() };
+        let exti = unsafe { &*stm32::EXTI::ptr() };
+
+        cortex_m::interrupt::free(|_| {
+            rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+            match i / 4 {
+                0 => syscfg
+                    .exticr1
+                    .modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+                1 => syscfg
+                    .exticr2
+                    .modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+                2 => syscfg
+                    .exticr3
+                    .modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+                _ => syscfg
+                    .exticr4
+                    .modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+            }
+            exti.rtsr
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << i) });
+            exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | 1 << i) });
+        });
+    }
+}
+
+/// Clear the pending edges of all EXTI lines and return them with line `n` in bit `n`
+pub fn take_edges() -> u32 {
+    let exti = unsafe { &*stm32::EXTI::ptr() };
+    let pending = exti.pr.read().bits();
+    exti.pr.write(|w| unsafe { w.bits(pending) });
+
+    pending
+}
@@ -0,0 +1,62 @@
+use heapless::{consts::*, Vec};
+
+/// Records of the transfers seen by a bus target which the host has not fetched yet
+///
+/// Each record consists of a header ending in the number of data bytes, followed by the data
+/// bytes. The log holds `MAX_READ_LENGTH` bytes so it always fits into a single reply, bytes
+/// not fitting are dropped.
+#[derive(Default)]
+pub struct RecordLog {
+    log: Vec<u8, U60>,
+    /// Start of the record in progress and position of its length in `log`
+    record: Option<(usize, usize)>,
+}
+
+impl RecordLog {
+    /// Begin a record with `header`, skipped if the log has no room for the header
+    pub fn start(&mut self, header: &[u8]) {
+        if self.log.len() + header.len() < self.log.capacity() {
+            let start = self.log.len();
+            self.log.extend_from_slice(header).ok();
+            self.record = Some((start, self.log.len()));
+            self.log.push(0).ok();
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.record.is_some()
+    }
+
+    /// Add a data byte to the record in progress
+    pub fn push(&mut self, byte: u8) {
+        if let Some((_, length)) = self.record {
+            if self.log.push(byte).is_ok() {
+                self.log[length] += 1;
+            }
+        }
+    }
+
+    /// Complete the record in progress, a record without any data bytes is dropped
+    pub fn finish(&mut self) {
+        if let Some((start, length)) = self.record.take() {
+            if self.log[length] == 0 {
+                self.log.truncate(start);
+            }
+        }
+    }
+
+    /// Move the completed records into `buffer` and return their length
+    pub fn take(&mut self, buffer: &mut [u8]) -> usize {
+        let end = self.record.map_or(self.log.len(), |(start, _)| start);
+        buffer[..end].copy_from_slice(&self.log[..end]);
+
+        // Keep the record in progress
+        let rest: Vec<u8, U60> = Vec::from_slice(&self.log[end..]).unwrap_or_default();
+        self.log = rest;
+        self.record = self
+            .record
+            .map(|(start, length)| (start - end, length - end));
+
+        end
+    }
+}
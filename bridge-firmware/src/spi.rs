@@ -0,0 +1,436 @@
+use crate::hal::stm32;
+use crate::hal::stm32::spi1::RegisterBlock;
+use crate::hal::stm32::{interrupt, Interrupt};
+use crate::pins::{lookup, route, take_edges, Named, Pin, Route};
+use crate::record::RecordLog;
+
+use core::cell::RefCell;
+use core::ptr;
+
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+
+use heapless::{consts::*, Vec};
+
+/// An SPI peripheral of the chip and the pins its signals can be routed to
+pub struct Instance {
+    pub ident: &'static str,
+    regs: *const RegisterBlock,
+    enable: fn(&stm32::rcc::RegisterBlock),
+    /// Pulse the reset of the peripheral, the only way to flush its transmit FIFO
+    reset: fn(&stm32::rcc::RegisterBlock),
+    interrupt: Interrupt,
+    sck: &'static [Route],
+    miso: &'static [Route],
+    mosi: &'static [Route],
+    /// Chip select input used in target mode
+    nss: &'static [Route],
+}
+
+impl Named for Instance {
+    const UNKNOWN: &'static str = "unknown spi instance";
+
+    fn ident(&self) -> &'static str {
+        self.ident
+    }
+}
+
+pub const INSTANCES: &[Instance] = &[
+    Instance {
+        ident: "spi1",
+        regs: stm32::SPI1::ptr(),
+        enable: |rcc| rcc.apb2enr.modify(|_, w| w.spi1en().set_bit()),
+        reset: |rcc| {
+            rcc.apb2rstr.modify(|_, w| w.spi1rst().set_bit());
+            rcc.apb2rstr.modify(|_, w| w.spi1rst().clear_bit());
+        },
+        interrupt: Interrupt::SPI1,
+        sck: &[Route { pin: "a5", af: 0 }, Route { pin: "b3", af: 0 }],
+        miso: &[Route { pin: "a6", af: 0 }, Route { pin: "b4", af: 0 }],
+        mosi: &[Route { pin: "a7", af: 0 }, Route { pin: "b5", af: 0 }],
+        // PA15 is taken by the serial link to the host, the other NSS pins share the EXTI
+        // interrupt of lines 4 to 15
+        nss: &[Route { pin: "a4", af: 0 }],
+    },
+    Instance {
+        ident: "spi2",
+        regs: stm32::SPI2::ptr(),
+        enable: |rcc| rcc.apb1enr.modify(|_, w| w.spi2en().set_bit()),
+        reset: |rcc| {
+            rcc.apb1rstr.modify(|_, w| w.spi2rst().set_bit());
+            rcc.apb1rstr.modify(|_, w| w.spi2rst().clear_bit());
+        },
+        interrupt: Interrupt::SPI2,
+        nss: &[Route { pin: "b12", af: 0 }],
+        #[cfg(any(feature = "stm32f042",))]
+        sck: &[Route { pin: "b13", af: 0 }],
+        #[cfg(any(feature = "stm32f072",))]
+        sck: &[Route { pin: "b13", af: 0 }, Route { pin: "b10", af: 5 }],
+        #[cfg(any(feature = "stm32f042",))]
+        miso: &[Route { pin: "b14", af: 0 }],
+        #[cfg(any(feature = "stm32f072",))]
+        miso: &[Route { pin: "b14", af: 0 }, Route { pin: "c2", af: 1 }],
+        #[cfg(any(feature = "stm32f042",))]
+        mosi: &[Route { pin: "b15", af: 0 }],
+        #[cfg(any(feature = "stm32f072",))]
+        mosi: &[Route { pin: "b15", af: 0 }, Route { pin: "c3", af: 1 }],
+    },
+];
+
+/// SPI mode used when setting up a bus, a device can ask for a different one per transaction
+const DEFAULT_MODE: u8 = 3;
+
+/// Fastest SCK frequency the chip supports as a master according to its datasheet
+const MAX_SPEED: u32 = 18_000_000;
+
+#[derive(Debug)]
+pub struct Error;
+
+/// An SPI peripheral set up as bus master
+///
+/// Like `i2c::I2c` this drives the peripheral directly to be independent of the pins in use.
+pub struct Spi {
+    pub ident: &'static str,
+    regs: &'static RegisterBlock,
+    frequency: u32,
+}
+
+impl Spi {
+    /// Set up the instance `ident` on the given pins running at `speed` Hz at most
+    ///
+    /// Fails if the chip has no such instance, its signals cannot be routed to the given pins or
+    /// no prescaler of `pclk` gets close enough to `speed`.
+    pub fn new(
+        ident: &str,
+        sck: &str,
+        miso: &str,
+        mosi: &str,
+        speed: u32,
+        pclk: u32,
+    ) -> Result<Spi, &'static str> {
+        let instance = lookup(INSTANCES, ident)?;
+
+        let (br, frequency) = prescaler(pclk, speed)?;
+
+        let pins = [
+            route(instance.sck, sck).ok_or("sck not available on this pin")?,
+            route(instance.miso, miso).ok_or("miso not available on this pin")?,
+            route(instance.mosi, mosi).ok_or("mosi not available on this pin")?,
+        ];
+
+        for &(pin, af) in &pins {
+            pin.into_alternate(af, false);
+        }
+
+        let spi = Spi {
+            ident: instance.ident,
+            regs: unsafe { &*instance.regs },
+            frequency,
+        };
+
+        (instance.enable)(unsafe { &*stm32::RCC::ptr() });
+        spi.configure(br);
+
+        Ok(spi)
+    }
+
+    /// The SCK frequency in Hz the bus runs at
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    fn configure(&self, br: u8) {
+        // Make sure the SPI unit is disabled so we can configure it
+        self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+
+        // 8 bit frames with an 8 bit threshold on the receive FIFO
+        self.regs
+            .cr2
+            .write(|w| unsafe { w.frxth().set_bit().ds().bits(0b0111).ssoe().clear_bit() });
+
+        // Master with software slave management, MSB first, full duplex
+        self.regs.cr1.write(|w| {
+            w.cpha()
+                .bit(DEFAULT_MODE & 0b01 != 0)
+                .cpol()
+                .bit(DEFAULT_MODE & 0b10 != 0)
+                .mstr()
+                .set_bit()
+                .br()
+                .bits(br)
+                .ssm()
+                .set_bit()
+                .ssi()
+                .set_bit()
+                .spe()
+                .set_bit()
+        });
+    }
+
+    fn exchange(&mut self, word: u8) -> Result<u8, Error> {
+        while self.regs.sr.read().txe().bit_is_clear() {}
+
+        // Access the data register with 8 bits to not send two frames at once
+        unsafe { ptr::write_volatile(ptr::addr_of!(self.regs.dr) as *mut u8, word) };
+
+        loop {
+            let sr = self.regs.sr.read();
+            if sr.ovr().bit_is_set() || sr.modf().bit_is_set() {
+                return Err(Error);
+            }
+            if sr.rxne().bit_is_set() {
+                return Ok(unsafe { ptr::read_volatile(ptr::addr_of!(self.regs.dr) as *const u8) });
+            }
+        }
+    }
+
+    pub fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        for word in words {
+            self.exchange(*word)?;
+        }
+
+        Ok(())
+    }
+
+    /// Clock out `words` and replace them by the words clocked in at the same time
+    pub fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+        for word in words.iter_mut() {
+            *word = self.exchange(*word)?;
+        }
+
+        Ok(words)
+    }
+
+    /// Switch the clock polarity and phase to SPI `mode` (0 to 3) and return the previous mode
+    pub fn set_mode(&mut self, mode: u8) -> u8 {
+        let cr1 = self.regs.cr1.read();
+        let previous = (cr1.cpol().bit() as u8) << 1 | cr1.cpha().bit() as u8;
+
+        if mode != previous {
+            // The configuration must not be changed while the bus is busy or enabled
+            while self.regs.sr.read().bsy().bit_is_set() {}
+            self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+            self.regs.cr1.modify(|_, w| {
+                w.cpol()
+                    .bit(mode & 0b10 != 0)
+                    .cpha()
+                    .bit(mode & 0b01 != 0)
+                    .spe()
+                    .set_bit()
+            });
+        }
+
+        previous
+    }
+}
+
+/// Find the smallest prescaler of `pclk` not exceeding `speed` and the resulting frequency
+fn prescaler(pclk: u32, speed: u32) -> Result<(u8, u32), &'static str> {
+    if speed > MAX_SPEED {
+        return Err("spi speed too high");
+    }
+
+    // The prescaler divides by 2 to 256 in powers of two
+    (0..8)
+        .map(|br| (br, pclk >> (br + 1)))
+        .find(|&(_, frequency)| frequency <= speed)
+        .ok_or("spi speed too low")
+}
+
+/// The SPI peripherals set up as target, shared with the interrupt handlers servicing them
+static TARGETS: Mutex<RefCell<Vec<Target, U2>>> =
+    Mutex::new(RefCell::new(Vec(heapless::i::Vec::new())));
+
+/// Run `f` on the targets without being interrupted by their events
+pub fn with_targets<R>(f: impl FnOnce(&mut Vec<Target, U2>) -> R) -> R {
+    cortex_m::interrupt::free(|cs| f(&mut TARGETS.borrow(cs).borrow_mut()))
+}
+
+#[interrupt]
+fn SPI1() {
+    with_targets(|targets| targets.iter_mut().for_each(Target::service));
+}
+
+#[interrupt]
+fn SPI2() {
+    with_targets(|targets| targets.iter_mut().for_each(Target::service));
+}
+
+/// Handle the rising edges of the chip selects, also clearing those of targets given up since
+#[interrupt]
+fn EXTI4_15() {
+    let edges = take_edges();
+
+    with_targets(|targets| {
+        for target in targets.iter_mut() {
+            if edges & 1 << target.nss.index() != 0 {
+                target.end_frame();
+            }
+        }
+    });
+}
+
+/// An SPI peripheral set up as target capturing the frames of a bus controller
+///
+/// The bytes received while the chip select is asserted are recorded per frame. Every frame is
+/// answered with the bytes loaded by the host, followed by 0xff once they run out. The
+/// peripheral is serviced by its interrupt, so the controller has to clock slowly enough for
+/// the interrupt to keep up with the four byte receive FIFO. A frame ends with the interrupt
+/// of the rising edge of the chip select, so frames following each other closely are kept
+/// apart.
+///
+/// The interrupts are enabled as soon as the target is set up, so that needs to happen within
+/// `with_targets` for the interrupts to find the target.
+pub struct Target {
+    pub ident: &'static str,
+    regs: &'static RegisterBlock,
+    instance: &'static Instance,
+    nss: Pin,
+    mode: u8,
+    /// Bytes to answer every frame with
+    response: Vec<u8, U60>,
+    /// Number of bytes of the response handed to the peripheral in the current frame
+    sent: usize,
+    /// Frames not fetched by the host yet, see `Request::SPITargetPoll`
+    log: RecordLog,
+}
+
+// The registers of the peripheral are only accessed through the target, which is only used
+// within the critical section of `with_targets`
+unsafe impl Send for Target {}
+
+impl Target {
+    /// Set up the instance `ident` on the given pins to act as target in SPI `mode`
+    pub fn new(
+        ident: &str,
+        sck: &str,
+        miso: &str,
+        mosi: &str,
+        nss: &str,
+        mode: u8,
+    ) -> Result<Target, &'static str> {
+        let instance = lookup(INSTANCES, ident)?;
+
+        let pins = [
+            route(instance.sck, sck).ok_or("sck not available on this pin")?,
+            route(instance.miso, miso).ok_or("miso not available on this pin")?,
+            route(instance.mosi, mosi).ok_or("mosi not available on this pin")?,
+            route(instance.nss, nss).ok_or("nss not available on this pin")?,
+        ];
+
+        if mode > 3 {
+            return Err("invalid spi mode");
+        }
+
+        for &(pin, af) in &pins {
+            pin.into_alternate(af, false);
+        }
+
+        let mut target = Target {
+            ident: instance.ident,
+            regs: unsafe { &*instance.regs },
+            instance,
+            nss: pins[3].0,
+            mode,
+            response: Vec::new(),
+            sent: 0,
+            log: RecordLog::default(),
+        };
+
+        (instance.enable)(unsafe { &*stm32::RCC::ptr() });
+        target.restart();
+        target.nss.listen_rising();
+        unsafe {
+            NVIC::unmask(instance.interrupt);
+            NVIC::unmask(Interrupt::EXTI4_15);
+        }
+
+        Ok(target)
+    }
+
+    /// Answer the following frames with `data`
+    pub fn load(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.response = Vec::from_slice(data).map_err(|()| "spi target response too long")?;
+
+        if self.nss.is_high() {
+            self.restart();
+        }
+
+        Ok(())
+    }
+
+    /// Move the records of completed frames into `buffer` and return their length
+    ///
+    /// Each record consists of the number of bytes received followed by the bytes.
+    pub fn take_frames(&mut self, buffer: &mut [u8]) -> usize {
+        self.log.take(buffer)
+    }
+
+    /// Record the received bytes and refill the transmit FIFO
+    fn service(&mut self) {
+        while self.regs.sr.read().rxne().bit_is_set() {
+            let byte = unsafe { ptr::read_volatile(ptr::addr_of!(self.regs.dr) as *const u8) };
+
+            if !self.log.is_recording() {
+                self.log.start(&[]);
+            }
+            self.log.push(byte);
+        }
+
+        self.feed();
+    }
+
+    /// Complete the frame once the chip select is released
+    fn end_frame(&mut self) {
+        self.service();
+
+        // Bytes which didn't fit into the log are lost
+        self.log.finish();
+        self.restart();
+    }
+
+    /// Set the peripheral up afresh, dropping the remains of the previous frame
+    fn restart(&mut self) {
+        (self.instance.reset)(unsafe { &*stm32::RCC::ptr() });
+
+        // 8 bit frames with an 8 bit threshold on the receive FIFO, interrupts once a byte is
+        // received or the transmit FIFO is half empty
+        self.regs.cr2.write(|w| unsafe {
+            w.frxth()
+                .set_bit()
+                .ds()
+                .bits(0b0111)
+                .rxneie()
+                .set_bit()
+                .txeie()
+                .set_bit()
+        });
+
+        // Target driven by the hardware chip select, MSB first, full duplex
+        self.regs.cr1.write(|w| {
+            w.cpha()
+                .bit(self.mode & 0b01 != 0)
+                .cpol()
+                .bit(self.mode & 0b10 != 0)
+                .mstr()
+                .clear_bit()
+                .ssm()
+                .clear_bit()
+                .spe()
+                .set_bit()
+        });
+
+        self.sent = 0;
+        self.feed();
+    }
+
+    /// Keep the transmit FIFO filled with the following bytes of the response
+    fn feed(&mut self) {
+        // The FIFO holds four bytes
+        while self.regs.sr.read().ftlvl().bits() != 0b11 {
+            let byte = self.response.get(self.sent).copied().unwrap_or(0xff);
+            unsafe { ptr::write_volatile(ptr::addr_of!(self.regs.dr) as *mut u8, byte) };
+            self.sent += 1;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::hal::stm32;
+use crate::hal::stm32::tim2::RegisterBlock;
+use crate::pins::{route, Named, Pin, Route};
+
+/// A general purpose timer of the chip and the pins routed to its first two channels, which
+/// can be paired up to capture both edges of a signal or to decode a quadrature encoder
+pub struct Instance {
+    pub ident: &'static str,
+    regs: *const RegisterBlock,
+    /// Enable the peripheral and pulse its reset to start from a clean state
+    reset: fn(&stm32::rcc::RegisterBlock),
+    ch1: &'static [Route],
+    ch2: &'static [Route],
+}
+
+impl Named for Instance {
+    const UNKNOWN: &'static str = "unknown timer instance";
+
+    fn ident(&self) -> &'static str {
+        self.ident
+    }
+}
+
+pub const INSTANCES: &[Instance] = &[
+    Instance {
+        ident: "tim2",
+        regs: stm32::TIM2::ptr(),
+        reset: |rcc| {
+            rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+            rcc.apb1rstr.modify(|_, w| w.tim2rst().set_bit());
+            rcc.apb1rstr.modify(|_, w| w.tim2rst().clear_bit());
+        },
+        // PA15 is taken by the serial link to the host
+        ch1: &[Route { pin: "a0", af: 2 }, Route { pin: "a5", af: 2 }],
+        ch2: &[Route { pin: "a1", af: 2 }, Route { pin: "b3", af: 2 }],
+    },
+    Instance {
+        ident: "tim3",
+        // TIM3 has the registers of TIM2, only its counter is limited to 16 bits
+        regs: stm32::TIM3::ptr() as *const RegisterBlock,
+        reset: |rcc| {
+            rcc.apb1enr.modify(|_, w| w.tim3en().set_bit());
+            rcc.apb1rstr.modify(|_, w| w.tim3rst().set_bit());
+            rcc.apb1rstr.modify(|_, w| w.tim3rst().clear_bit());
+        },
+        ch1: &[Route { pin: "a6", af: 1 }, Route { pin: "b4", af: 1 }],
+        ch2: &[Route { pin: "a7", af: 1 }, Route { pin: "b5", af: 1 }],
+    },
+];
+
+/// Flags of the status register
+pub const UIF: u32 = 1 << 0;
+pub const CC1IF: u32 = 1 << 1;
+pub const CC2IF: u32 = 1 << 2;
+
+impl Instance {
+    pub fn regs(&self) -> &'static RegisterBlock {
+        unsafe { &*self.regs }
+    }
+
+    /// Enable and reset the timer, stopping whatever it was used for before
+    pub fn reset(&self) {
+        (self.reset)(unsafe { &*stm32::RCC::ptr() });
+    }
+
+    /// Whether the counter has 32 instead of 16 bits
+    pub fn is_wide(&self) -> bool {
+        self.ident == "tim2"
+    }
+
+    pub fn ch1(&self, name: &str) -> Option<(Pin, u8)> {
+        route(self.ch1, name)
+    }
+
+    pub fn ch2(&self, name: &str) -> Option<(Pin, u8)> {
+        route(self.ch2, name)
+    }
+}
@@ -0,0 +1,73 @@
+use crate::hal::stm32;
+use crate::pins::{Pin, RESERVED};
+
+use heapless::{consts::*, Vec};
+
+/// Rate of the timer counter, i.e. the resolution of the step durations
+const COUNTER_HZ: u32 = 1_000_000;
+
+/// Size of a step, the levels of the pins followed by the duration as little endian `u16`
+const STEP_LENGTH: usize = 3;
+
+/// Drive the comma separated `pins` through `steps` as described by `Request::GpioWaveform`
+///
+/// The steps are timed by TIM14 counting microseconds, the pins are left as push pull outputs
+/// at the levels of the last step.
+pub fn play(pins: &str, steps: &[u8], pclk: u32) -> Result<(), &'static str> {
+    let mut outputs: Vec<Pin, U8> = Vec::new();
+    for name in pins.split(',') {
+        let pin = match Pin::from_name(name) {
+            Some(pin) if !RESERVED.contains(&name) => pin,
+            _ => return Err("invalid waveform pin"),
+        };
+        outputs.push(pin).map_err(|_| "too many waveform pins")?;
+    }
+
+    if steps.is_empty() || !steps.len().is_multiple_of(STEP_LENGTH) {
+        return Err("invalid waveform steps");
+    }
+
+    let tim = unsafe { &*stm32::TIM14::ptr() };
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+
+    rcc.apb1enr.modify(|_, w| w.tim14en().set_bit());
+    tim.cr1.modify(|_, w| w.cen().clear_bit());
+    tim.psc
+        .write(|w| w.psc().bits((pclk / COUNTER_HZ - 1) as u16));
+    tim.arr.write(|w| unsafe { w.arr().bits(0xffff) });
+
+    // Load the prescaler which only takes effect at the next update event
+    tim.egr.write(|w| w.ug().set_bit());
+    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+    // Only turn the pins into outputs once they are set to the first levels to avoid glitches
+    set_levels(&outputs, steps[0]);
+    for pin in &outputs {
+        pin.into_output(false);
+    }
+
+    // Measure each step from the planned end of the previous one to not accumulate any error
+    let mut start = tim.cnt.read().cnt().bits();
+    for step in steps.chunks(STEP_LENGTH) {
+        set_levels(&outputs, step[0]);
+
+        let duration = u16::from_le_bytes([step[1], step[2]]);
+        while tim.cnt.read().cnt().bits().wrapping_sub(start) < duration {}
+        start = start.wrapping_add(duration);
+    }
+
+    tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+    Ok(())
+}
+
+/// Set pin `n` of `outputs` according to bit `n` of `levels`
+fn set_levels(outputs: &[Pin], levels: u8) {
+    for (i, pin) in outputs.iter().enumerate() {
+        if levels & (1 << i) != 0 {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+    }
+}
@@ -30,11 +30,11 @@ fn interact<T: SerialPort>(port: &mut T) -> io::Result<()> {
         Ok(())
     })?;
 
-    port.set_timeout(Duration::from_millis(2000))?;
+    port.set_timeout(Duration::from_millis(100))?;
 
-    let port = Arc::new(Mutex::new(Box::from(port)));
+    let port = Arc::new(Mutex::new(Box::from(bridge_host::io::Link::new(port))));
 
-    bridge_host::common::assert_version(port.clone());
+    bridge_host::common::assert_version(port.clone())?;
 
     let mut pin = bridge_host::gpio::PushPullPin::new("b3".into(), port.clone())
         .expect("Could not initialise GPIO");
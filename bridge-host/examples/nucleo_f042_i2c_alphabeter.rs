@@ -14,6 +14,8 @@ use core::fmt::Write;
 
 use simplelog::*;
 
+use bridge_host::time::U32Ext;
+
 fn main() -> io::Result<()> {
     TermLogger::init(
         LevelFilter::Debug,
@@ -40,17 +42,23 @@ fn interact<T: SerialPort>(port: &mut T) -> io::Result<()> {
         Ok(())
     })?;
 
-    port.set_timeout(Duration::from_millis(2000))?;
+    port.set_timeout(Duration::from_millis(100))?;
 
-    let port = Arc::new(Mutex::new(Box::from(port)));
+    let port = Arc::new(Mutex::new(Box::from(bridge_host::io::Link::new(port))));
 
-    bridge_host::common::assert_version(port.clone());
+    bridge_host::common::assert_version(port.clone())?;
 
     let mut pin = bridge_host::gpio::PushPullPin::new("b3".into(), port.clone())
         .expect("Could initialiase GPIO");
 
-    let i2c =
-        bridge_host::i2c::I2C::new("i2c1".into(), "f1".into(), "f0".into(), 400, port.clone());
+    let i2c = bridge_host::i2c::I2C::new(
+        "i2c1".into(),
+        "f1".into(),
+        "f0".into(),
+        400.khz(),
+        port.clone(),
+    )
+    .expect("Could not initialise I2C bus");
 
     use ssd1306::displayrotation::DisplayRotation;
     let mut disp: TerminalMode<_> = Builder::new().with_i2c_addr(0x3c).connect_i2c(i2c).into();
@@ -10,6 +10,8 @@ use serial::prelude::*;
 
 use simplelog::*;
 
+use bridge_host::time::U32Ext;
+
 fn main() -> io::Result<()> {
     TermLogger::init(
         LevelFilter::Debug,
@@ -36,20 +38,21 @@ fn interact<T: SerialPort>(port: &mut T) -> io::Result<()> {
         Ok(())
     })?;
 
-    port.set_timeout(Duration::from_millis(2000))?;
+    port.set_timeout(Duration::from_millis(100))?;
 
-    let port = Arc::new(Mutex::new(Box::from(port)));
+    let port = Arc::new(Mutex::new(Box::from(bridge_host::io::Link::new(port))));
 
-    bridge_host::common::assert_version(port.clone());
+    bridge_host::common::assert_version(port.clone())?;
 
     let spi = bridge_host::spi::SPI::new(
         "spi1".into(),
         "a5".into(),
         "a6".into(),
         "a7".into(),
-        1000,
+        1.mhz(),
         port.clone(),
-    );
+    )
+    .expect("Could not initialise SPI bus");
 
     let mut apa = Apa102::new(spi);
     let data: [RGB<u8>; 8] = [
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::asynch::io::{send_capture_read, send_capture_start, Link};
+use crate::capture::{Capture, POLL_INTERVAL};
+use crate::io::Error;
+use crate::time::Hertz;
+
+/// A logic analyser sampling pins in the background, see `bridge_host::capture::Analyser`
+pub struct Analyser<T> {
+    pins: Vec<String>,
+    running: Option<(Hertz, u16)>,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> Analyser<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(pins: &[String], channel: Arc<Mutex<Link<T>>>) -> Self {
+        Analyser {
+            pins: pins.to_vec(),
+            running: None,
+            channel,
+        }
+    }
+
+    pub async fn start(&mut self, rate: Hertz, samples: u16) -> Result<Hertz, Error> {
+        let hz = send_capture_start(
+            &mut *self.channel.lock().await,
+            &self.pins.join(","),
+            rate.0,
+            samples,
+        )
+        .await?;
+
+        self.running = Some((Hertz(hz), samples));
+        Ok(Hertz(hz))
+    }
+
+    pub async fn finish(&mut self) -> Result<Capture, Error> {
+        let (rate, count) = self.running.take().ok_or(Error::NotStarted)?;
+        let mut samples = Vec::with_capacity(usize::from(count));
+
+        while samples.len() < usize::from(count) {
+            let data =
+                send_capture_read(&mut *self.channel.lock().await, samples.len() as u16).await?;
+            if data.is_empty() {
+                sleep(POLL_INTERVAL).await;
+            }
+            samples.extend_from_slice(&data);
+        }
+
+        Ok(Capture {
+            pins: self.pins.clone(),
+            rate,
+            samples,
+        })
+    }
+}
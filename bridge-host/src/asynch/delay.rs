@@ -0,0 +1,27 @@
+use embedded_hal_async::delay::DelayNs;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Delays for drivers of devices attached to the bridge, see `bridge_host::delay::Delay`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Delay;
+
+impl Delay {
+    pub fn new() -> Self {
+        Delay
+    }
+}
+
+impl DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        sleep(Duration::from_nanos(u64::from(ns))).await;
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        sleep(Duration::from_micros(u64::from(us))).await;
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        sleep(Duration::from_millis(u64::from(ms))).await;
+    }
+}
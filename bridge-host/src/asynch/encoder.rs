@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+
+use crate::asynch::io::{send_encoder_init, send_encoder_read, send_encoder_reset, Link};
+use crate::encoder::Position;
+use crate::io::Error;
+
+/// A quadrature encoder decoded by the target, see `bridge_host::encoder::Encoder`
+pub struct Encoder<T> {
+    ident: String,
+    last: u32,
+    count: i64,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> Encoder<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn new(
+        ident: String,
+        a_pin: String,
+        b_pin: String,
+        channel: Arc<Mutex<Link<T>>>,
+    ) -> Result<Self, Error> {
+        send_encoder_init(&mut *channel.lock().await, &ident, &a_pin, &b_pin).await?;
+
+        Ok(Encoder {
+            ident,
+            last: 0,
+            count: 0,
+            channel,
+        })
+    }
+
+    pub async fn position(&mut self) -> Result<Position, Error> {
+        let counter = send_encoder_read(&mut *self.channel.lock().await, &self.ident).await?;
+        self.count += counter.since(self.last);
+        self.last = counter.value;
+
+        Ok(Position {
+            count: self.count,
+            direction: counter.direction,
+        })
+    }
+
+    pub async fn count(&mut self) -> Result<i64, Error> {
+        Ok(self.position().await?.count)
+    }
+
+    pub async fn reset(&mut self) -> Result<(), Error> {
+        send_encoder_reset(&mut *self.channel.lock().await, &self.ident).await?;
+        self.last = 0;
+        self.count = 0;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+
+use crate::asynch::io::{
+    send_gpio_high, send_gpio_init_pp, send_gpio_low, send_gpio_waveform, Link,
+};
+use crate::gpio::{encode_steps, Step};
+use crate::io::Error;
+
+/// Asynchronous push pull output pin
+///
+/// embedded-hal-async does not define output pin traits, so the pin offers the operations of
+/// `embedded_hal::digital::v2::OutputPin` as async methods instead.
+pub struct PushPullPin<T> {
+    pinname: String,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> PushPullPin<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn new(pinname: String, channel: Arc<Mutex<Link<T>>>) -> Result<Self, Error> {
+        send_gpio_init_pp(&mut *channel.lock().await, &pinname).await?;
+        Ok(PushPullPin { channel, pinname })
+    }
+
+    pub async fn set_high(&mut self) -> Result<(), Error> {
+        send_gpio_high(&mut *self.channel.lock().await, &self.pinname).await
+    }
+
+    pub async fn set_low(&mut self) -> Result<(), Error> {
+        send_gpio_low(&mut *self.channel.lock().await, &self.pinname).await
+    }
+
+    /// Drive the pin to `high` for exactly `us` microseconds, see `bridge_host::gpio::PushPullPin`
+    pub async fn pulse(&mut self, high: bool, us: u16) -> Result<(), Error> {
+        let steps = [
+            Step {
+                levels: u8::from(high),
+                us,
+            },
+            Step {
+                levels: u8::from(!high),
+                us: 0,
+            },
+        ];
+
+        send_gpio_waveform(
+            &mut *self.channel.lock().await,
+            &self.pinname,
+            &encode_steps(&steps),
+        )
+        .await
+    }
+}
+
+/// Pins driven through waveforms timed by the target, see `bridge_host::gpio::Waveform`
+pub struct Waveform<T> {
+    pins: String,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> Waveform<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(pins: &[String], channel: Arc<Mutex<Link<T>>>) -> Self {
+        Waveform {
+            pins: pins.join(","),
+            channel,
+        }
+    }
+
+    pub async fn play(&mut self, steps: &[Step]) -> Result<(), Error> {
+        send_gpio_waveform(
+            &mut *self.channel.lock().await,
+            &self.pins,
+            &encode_steps(steps),
+        )
+        .await
+    }
+}
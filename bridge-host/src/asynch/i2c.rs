@@ -0,0 +1,124 @@
+use bridge_common::encoding::I2CAddress;
+use embedded_hal_async::i2c::{self, AddressMode, ErrorType, Operation};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+
+use crate::asynch::io::{
+    send_i2c_init, send_i2c_read, send_i2c_recover, send_i2c_scan, send_i2c_target_init,
+    send_i2c_target_poll, send_i2c_target_set, send_i2c_write, send_i2c_write_read, Link,
+};
+use crate::i2c::{split_transaction, target_chunks, TargetWrite};
+use crate::io::Error;
+use crate::time::Hertz;
+
+pub struct I2C<T> {
+    ident: String,
+    frequency: Hertz,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> I2C<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn new(
+        ident: String,
+        scl: String,
+        sda: String,
+        speed: Hertz,
+        channel: Arc<Mutex<Link<T>>>,
+    ) -> Result<Self, Error> {
+        let hz = send_i2c_init(&mut *channel.lock().await, &ident, &scl, &sda, speed.0).await?;
+
+        Ok(I2C {
+            ident,
+            frequency: Hertz(hz),
+            channel,
+        })
+    }
+
+    /// The SCL frequency the target actually runs the bus at
+    pub fn frequency(&self) -> Hertz {
+        self.frequency
+    }
+
+    /// Find out which addresses on the bus are acknowledged by a device
+    pub async fn scan(&mut self) -> Result<Vec<u8>, Error> {
+        send_i2c_scan(&mut *self.channel.lock().await, &self.ident).await
+    }
+
+    /// Free the bus from a device holding SDA low, see `bridge_host::i2c::I2C::recover_bus`
+    pub async fn recover_bus(&mut self) -> Result<(), Error> {
+        send_i2c_recover(&mut *self.channel.lock().await, &self.ident).await
+    }
+}
+
+/// An I2C bus on which the bridge impersonates a device, see `bridge_host::i2c::I2CTarget`
+pub struct I2CTarget<T> {
+    ident: String,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> I2CTarget<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn new(
+        ident: String,
+        scl: String,
+        sda: String,
+        address: u8,
+        channel: Arc<Mutex<Link<T>>>,
+    ) -> Result<Self, Error> {
+        send_i2c_target_init(&mut *channel.lock().await, &ident, &scl, &sda, address).await?;
+
+        Ok(I2CTarget { ident, channel })
+    }
+
+    /// Set the registers starting at `register`, wrapping around after the last one
+    pub async fn set_registers(&mut self, register: u8, data: &[u8]) -> Result<(), Error> {
+        let link = &mut *self.channel.lock().await;
+
+        for (offset, chunk) in target_chunks(register, data) {
+            send_i2c_target_set(link, &self.ident, offset, chunk).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the writes of the controller since the last call
+    pub async fn writes(&mut self) -> Result<Vec<TargetWrite>, Error> {
+        send_i2c_target_poll(&mut *self.channel.lock().await, &self.ident).await
+    }
+}
+
+impl<T> ErrorType for I2C<T> {
+    type Error = Error;
+}
+
+impl<T, A> i2c::I2c<A> for I2C<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    A: AddressMode + Into<I2CAddress>,
+{
+    /// See `bridge_host::i2c::I2C` for the supported sequences of operations
+    async fn transaction(
+        &mut self,
+        address: A,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let link = &mut *self.channel.lock().await;
+        let address = address.into();
+
+        match split_transaction(operations)? {
+            (data, None) => send_i2c_write(link, &self.ident, address, &data).await,
+            (data, Some(buffer)) if data.is_empty() => {
+                send_i2c_read(link, &self.ident, address, buffer).await
+            }
+            (data, Some(buffer)) => {
+                send_i2c_write_read(link, &self.ident, address, &data, buffer).await
+            }
+        }
+    }
+}
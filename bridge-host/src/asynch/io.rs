@@ -0,0 +1,748 @@
+use bridge_common::encoding::{
+    batch, capture_read, capture_start, clear, delay, encoder_init, encoder_read, encoder_reset,
+    gpio_init_pp, gpio_sethigh, gpio_setlow, gpio_toggle, gpio_waveform, i2c_init, i2c_read,
+    i2c_recover, i2c_scan, i2c_target_init, i2c_target_poll, i2c_target_set, i2c_write,
+    i2c_write_more, i2c_write_read, measure, onewire_bit, onewire_read, onewire_reset,
+    onewire_search, onewire_write, reset, spi_device_init, spi_init, spi_target_init,
+    spi_target_load, spi_target_poll, spi_transaction, spi_transfer, spi_write, version,
+    I2CAddress, Reply, Request, INTERFRAME_TIMEOUT_MS, MAX_READ_LENGTH, MAX_WRITE_LENGTH,
+};
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, timeout};
+
+use crate::batch::Batch;
+use crate::encoder::Counter;
+use crate::i2c::TargetWrite;
+use crate::io::{
+    check_read_length, check_write_length, decode, expect_bytes, expect_counter, expect_data,
+    expect_frames, expect_frequency, expect_measurement, expect_ok, expect_presence, expect_rom,
+    expect_scan, expect_target_writes, Error, Result, DEFAULT_RECONNECT_TIMEOUT,
+    RECONNECT_INTERVAL,
+};
+use crate::measure::Measurement;
+use crate::protocol::{is_complete, Outcome, Protocol, Resync, Step};
+
+/// Asynchronous connection to the bridge firmware over any `AsyncRead + AsyncWrite` port
+///
+/// Behaves like the blocking `bridge_host::io::Link`. Dropping the future of a request half way,
+/// e.g. in `tokio::select!` or `tokio::time::timeout`, is safe: the next request first brings
+/// the link back into a known state, so it never sees a reply meant for the abandoned one.
+pub struct Link<T> {
+    port: T,
+    protocol: Protocol,
+    reopen: Option<Box<dyn FnMut() -> io::Result<T> + Send>>,
+    reconnect_timeout: Duration,
+}
+
+impl<T> Link<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(port: T) -> Self {
+        Link {
+            port,
+            protocol: Protocol::new(),
+            reopen: None,
+            reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+        }
+    }
+
+    /// Set the time to wait for a complete reply to a request
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.protocol.timeout = timeout;
+    }
+
+    /// Set how often idempotent requests are resent after a timeout or a garbled reply
+    pub fn set_retries(&mut self, retries: u8) {
+        self.protocol.retries = retries;
+    }
+
+    /// Set up reopening the port if it vanishes, see `bridge_host::io::Link::set_reconnect`
+    pub fn set_reconnect<F>(&mut self, timeout: Duration, open: F)
+    where
+        F: FnMut() -> io::Result<T> + Send + 'static,
+    {
+        self.reconnect_timeout = timeout;
+        self.reopen = Some(Box::new(open));
+        self.protocol.reconnect = true;
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Send a request and hand the decoded reply to `handle`
+    pub async fn transfer<R, F>(&mut self, request: &Request<'_>, handle: F) -> Result<R>
+    where
+        F: FnMut(Reply) -> Result<R>,
+    {
+        self.transfer_part(request, handle, true).await
+    }
+
+    /// Send a request forming part of a longer operation, see
+    /// `bridge_host::io::Link::transfer_part`
+    pub async fn transfer_part<R, F>(
+        &mut self,
+        request: &Request<'_>,
+        handle: F,
+        first: bool,
+    ) -> Result<R>
+    where
+        F: FnMut(Reply) -> Result<R>,
+    {
+        let mut transfer = self.protocol.transfer(request, first)?;
+        self.drive(|protocol, outcome| transfer.step(protocol, outcome), handle)
+            .await?
+            .ok_or(Error::UnexpectedReply)
+    }
+
+    /// Bring host and target back into a known state, see `bridge_host::io::Link::resync`
+    pub async fn resync(&mut self) -> Result<()> {
+        let mut resync = Resync::new();
+        self.drive(
+            |protocol, outcome| resync.step(protocol, outcome),
+            |_| Ok(()),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Carry out the steps of a transfer or resynchronisation until it is done
+    async fn drive<R, S, F>(&mut self, mut step: S, mut handle: F) -> Result<Option<R>>
+    where
+        S: FnMut(&mut Protocol, Outcome) -> Step,
+        F: FnMut(Reply) -> Result<R>,
+    {
+        let mut output = None;
+        let mut outcome = Ok(None);
+
+        loop {
+            outcome = match step(&mut self.protocol, outcome) {
+                Step::Request(req) => match self.exchange(&req).await {
+                    Ok(buf) => decode(&buf)
+                        .and_then(&mut handle)
+                        .map(|res| output = Some(res))
+                        .map(|()| None),
+                    Err(err) => Err(err),
+                },
+                Step::Exchange(req) => self.exchange(&req).await.map(Some),
+                Step::Sleep(duration) => {
+                    sleep(duration).await;
+                    Ok(None)
+                }
+                Step::Flush => self.flush_input().await.map(|()| None),
+                Step::Reopen => self.reopen().await.map(|()| None),
+                Step::Done(res) => return res.map(|()| output),
+            };
+        }
+    }
+
+    /// Reopen the port after it vanished
+    async fn reopen(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.reconnect_timeout;
+
+        if let Some(open) = self.reopen.as_mut() {
+            log::warn!("Lost connection to target, trying to reconnect");
+
+            loop {
+                match open() {
+                    Ok(port) => {
+                        self.port = port;
+                        break;
+                    }
+                    Err(err) if Instant::now() < deadline => {
+                        log::debug!("Could not reopen port: {}", err);
+                        sleep(RECONNECT_INTERVAL).await;
+                    }
+                    Err(err) => return Err(Error::Io(err)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop everything received so far until the port runs dry
+    async fn flush_input(&mut self) -> Result<()> {
+        let mut chunk = [0; 64];
+        let quiet = Duration::from_millis(u64::from(INTERFRAME_TIMEOUT_MS));
+
+        loop {
+            match timeout(quiet, self.port.read(&mut chunk)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(bytes)) => {
+                    log::debug!("Dropping {} stale bytes: {:?}", bytes, &chunk[..bytes])
+                }
+                Ok(Err(err)) => return Err(Error::Io(err)),
+            }
+        }
+    }
+
+    async fn exchange(&mut self, req: &[u8]) -> Result<Vec<u8>> {
+        self.port.write_all(req).await?;
+        self.port.flush().await?;
+
+        match timeout(self.protocol.timeout, self.receive()).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    /// Read until the received bytes form a complete reply
+    async fn receive(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 64];
+
+        loop {
+            match self.port.read(&mut chunk).await? {
+                0 => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+                bytes => buf.extend_from_slice(&chunk[..bytes]),
+            }
+
+            if is_complete(&buf)? {
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+pub async fn send_version<T>(link: &mut Link<T>) -> Result<u8>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&version(), |reply| match reply {
+        Reply::Version { version } => Ok(version),
+        reply => Err(Error::from(reply)),
+    })
+    .await
+}
+
+pub async fn send_clear<T>(link: &mut Link<T>) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&clear(), expect_ok).await
+}
+
+pub async fn send_reset<T>(link: &mut Link<T>) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&reset(), expect_ok).await
+}
+
+pub async fn send_gpio_init_pp<T>(link: &mut Link<T>, pin: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&gpio_init_pp(pin), expect_ok).await
+}
+
+pub async fn send_gpio_toggle<T>(link: &mut Link<T>, pin: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&gpio_toggle(pin), expect_ok).await
+}
+
+pub async fn send_gpio_high<T>(link: &mut Link<T>, pin: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&gpio_sethigh(pin), expect_ok).await
+}
+
+pub async fn send_gpio_low<T>(link: &mut Link<T>, pin: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&gpio_setlow(pin), expect_ok).await
+}
+
+pub async fn send_i2c_init<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    scl_pin: &str,
+    sda_pin: &str,
+    speed: u32,
+) -> Result<u32>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&i2c_init(ident, scl_pin, sda_pin, speed), expect_frequency)
+        .await
+}
+
+pub async fn send_i2c_write<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    addr: I2CAddress,
+    data: &[u8],
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut chunks = data.chunks(MAX_WRITE_LENGTH);
+    let last = chunks.next_back().unwrap_or_default();
+
+    let mut first = true;
+
+    // The device has seen the earlier chunks, so only the first one may be resent after a reset
+    for chunk in chunks {
+        link.transfer_part(&i2c_write_more(ident, addr, chunk), expect_ok, first)
+            .await?;
+        first = false;
+    }
+
+    link.transfer_part(&i2c_write(ident, addr, last), expect_ok, first)
+        .await
+}
+
+pub async fn send_spi_init<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    sck_pin: &str,
+    miso_pin: &str,
+    mosi_pin: &str,
+    speed: u32,
+) -> Result<u32>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(
+        &spi_init(ident, sck_pin, miso_pin, mosi_pin, speed),
+        expect_frequency,
+    )
+    .await
+}
+
+pub async fn send_spi_write<T>(link: &mut Link<T>, ident: &str, data: &[u8]) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // A reset lost the earlier chunks, so only the first one may be resent
+    for (i, chunk) in data.chunks(MAX_WRITE_LENGTH).enumerate() {
+        link.transfer_part(&spi_write(ident, chunk), expect_ok, i == 0)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn send_i2c_read<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    addr: I2CAddress,
+    buffer: &mut [u8],
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let len = check_read_length(buffer.len())?;
+    link.transfer(&i2c_read(ident, addr, len), |reply| {
+        expect_data(reply, buffer)
+    })
+    .await
+}
+
+pub async fn send_i2c_write_read<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    addr: I2CAddress,
+    data: &[u8],
+    buffer: &mut [u8],
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    check_write_length(data.len())?;
+    let len = check_read_length(buffer.len())?;
+    link.transfer(&i2c_write_read(ident, addr, data, len), |reply| {
+        expect_data(reply, buffer)
+    })
+    .await
+}
+
+/// Exchange `words` of any length, split into chunks of up to `MAX_WRITE_LENGTH` bytes
+pub async fn send_spi_transfer<T>(link: &mut Link<T>, ident: &str, words: &mut [u8]) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // A reset lost the earlier chunks, so only the first one may be resent
+    for (i, chunk) in words.chunks_mut(MAX_WRITE_LENGTH).enumerate() {
+        let data = chunk.to_vec();
+        link.transfer_part(
+            &spi_transfer(ident, &data),
+            |reply| expect_data(reply, chunk),
+            i == 0,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn send_spi_device_init<T>(link: &mut Link<T>, ident: &str, cs_pin: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&spi_device_init(ident, cs_pin), expect_ok)
+        .await
+}
+
+pub async fn send_spi_transaction<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    cs_pin: &str,
+    mode: u8,
+    words: &mut [u8],
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    check_write_length(words.len())?;
+    let data = words.to_vec();
+    link.transfer(&spi_transaction(ident, cs_pin, mode, &data), |reply| {
+        expect_data(reply, words)
+    })
+    .await
+}
+
+pub async fn send_i2c_scan<T>(link: &mut Link<T>, ident: &str) -> Result<Vec<u8>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&i2c_scan(ident), expect_scan).await
+}
+
+pub async fn send_i2c_recover<T>(link: &mut Link<T>, ident: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&i2c_recover(ident), expect_ok).await
+}
+
+pub async fn send_i2c_target_init<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    scl_pin: &str,
+    sda_pin: &str,
+    address: u8,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(
+        &i2c_target_init(ident, scl_pin, sda_pin, address),
+        expect_ok,
+    )
+    .await
+}
+
+pub async fn send_i2c_target_set<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    register: u8,
+    data: &[u8],
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&i2c_target_set(ident, register, data), expect_ok)
+        .await
+}
+
+pub async fn send_i2c_target_poll<T>(link: &mut Link<T>, ident: &str) -> Result<Vec<TargetWrite>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&i2c_target_poll(ident), expect_target_writes)
+        .await
+}
+
+pub async fn send_spi_target_init<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    sck_pin: &str,
+    miso_pin: &str,
+    mosi_pin: &str,
+    cs_pin: &str,
+    mode: u8,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(
+        &spi_target_init(ident, sck_pin, miso_pin, mosi_pin, cs_pin, mode),
+        expect_ok,
+    )
+    .await
+}
+
+pub async fn send_spi_target_load<T>(link: &mut Link<T>, ident: &str, data: &[u8]) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    check_write_length(data.len())?;
+    link.transfer(&spi_target_load(ident, data), expect_ok)
+        .await
+}
+
+pub async fn send_spi_target_poll<T>(link: &mut Link<T>, ident: &str) -> Result<Vec<Vec<u8>>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&spi_target_poll(ident), expect_frames).await
+}
+
+/// Execute `operations` on the target, see `bridge_host::io::send_batch`
+pub async fn send_batch<T>(link: &mut Link<T>, operations: &Batch) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // A reset lost what the earlier payloads did, so only the first one may be resent
+    for (index, payload) in operations.payloads()?.iter().enumerate() {
+        link.transfer_part(&batch(payload), expect_ok, index == 0)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn send_delay<T>(link: &mut Link<T>, us: u32) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&delay(us), expect_ok).await
+}
+
+pub async fn send_gpio_waveform<T>(link: &mut Link<T>, pins: &str, steps: &[u8]) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    check_write_length(steps.len())?;
+    link.transfer(&gpio_waveform(pins, steps), expect_ok).await
+}
+
+pub async fn send_capture_start<T>(
+    link: &mut Link<T>,
+    pins: &str,
+    rate: u32,
+    samples: u16,
+) -> Result<u32>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&capture_start(pins, rate, samples), expect_frequency)
+        .await
+}
+
+pub async fn send_capture_read<T>(link: &mut Link<T>, offset: u16) -> Result<Vec<u8>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&capture_read(offset), expect_bytes).await
+}
+
+pub async fn send_measure<T>(link: &mut Link<T>, pin: &str, timeout_ms: u16) -> Result<Measurement>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&measure(pin, timeout_ms), expect_measurement)
+        .await
+}
+
+pub async fn send_encoder_init<T>(
+    link: &mut Link<T>,
+    ident: &str,
+    a_pin: &str,
+    b_pin: &str,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&encoder_init(ident, a_pin, b_pin), expect_ok)
+        .await
+}
+
+pub async fn send_encoder_read<T>(link: &mut Link<T>, ident: &str) -> Result<Counter>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&encoder_read(ident), expect_counter).await
+}
+
+pub async fn send_encoder_reset<T>(link: &mut Link<T>, ident: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&encoder_reset(ident), expect_ok).await
+}
+
+pub async fn send_onewire_reset<T>(link: &mut Link<T>, pin: &str) -> Result<bool>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&onewire_reset(pin), expect_presence).await
+}
+
+pub async fn send_onewire_write<T>(link: &mut Link<T>, pin: &str, data: &[u8]) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    for chunk in data.chunks(MAX_WRITE_LENGTH) {
+        link.transfer(&onewire_write(pin, chunk), expect_ok).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn send_onewire_read<T>(link: &mut Link<T>, pin: &str, buffer: &mut [u8]) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    for chunk in buffer.chunks_mut(MAX_READ_LENGTH) {
+        link.transfer(&onewire_read(pin, chunk.len() as u8), |reply| {
+            expect_data(reply, chunk)
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn send_onewire_bit<T>(link: &mut Link<T>, pin: &str, bit: bool) -> Result<bool>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut level = [0];
+    link.transfer(&onewire_bit(pin, bit), |reply| {
+        expect_data(reply, &mut level)
+    })
+    .await?;
+
+    Ok(level[0] != 0)
+}
+
+pub async fn send_onewire_search<T>(
+    link: &mut Link<T>,
+    pin: &str,
+    rom: u64,
+    discrepancy: u8,
+) -> Result<(u64, u8)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    link.transfer(&onewire_search(pin, rom, discrepancy), expect_rom)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{send_gpio_toggle, send_i2c_read, Link};
+    use bridge_common::encoding::{Reply, Request, VERSION};
+    use heapless::consts::U64;
+    use postcard::{from_bytes, to_vec};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::sleep;
+
+    fn encode_reply(reply: &Reply) -> Vec<u8> {
+        to_vec::<U64, _>(reply).unwrap().to_vec()
+    }
+
+    /// Read the next request sent by the host on the target side of the port
+    async fn receive(port: &mut DuplexStream) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while from_bytes::<Request>(&buf).is_err() {
+            buf.push(port.read_u8().await.unwrap());
+        }
+        buf
+    }
+
+    /// Answer requests like the firmware until `until` was answered, returns the requests
+    async fn serve(port: &mut DuplexStream, until: &Request<'_>) -> Vec<String> {
+        let mut requests = Vec::new();
+        loop {
+            let buf = receive(port).await;
+            let request = from_bytes::<Request>(&buf).unwrap();
+            let reply = match request {
+                Request::Version => Reply::Version { version: VERSION },
+                Request::Session { id } => Reply::Session { previous: id },
+                _ => Reply::Ok,
+            };
+            port.write_all(&encode_reply(&reply)).await.unwrap();
+
+            requests.push(format!("{:?}", request));
+            if request == *until {
+                return requests;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn abandoned_request_resyncs_link() {
+        let (host, mut target) = duplex(256);
+        let mut link = Link::new(host);
+        let reply = encode_reply(&Reply::Data {
+            data: &[1, 2, 3, 4],
+        });
+
+        // Give up on a read after the target has sent only part of its reply
+        let mut buffer = [0; 4];
+        {
+            let read = send_i2c_read(&mut link, "i2c1", 0x50u8.into(), &mut buffer);
+            tokio::pin!(read);
+
+            tokio::select! {
+                _ = &mut read => panic!("read completed without reply"),
+                request = receive(&mut target) => {
+                    assert!(matches!(from_bytes(&request), Ok(Request::I2CRead { .. })));
+                }
+            }
+            target.write_all(&reply[..3]).await.unwrap();
+
+            tokio::select! {
+                _ = &mut read => panic!("read completed with partial reply"),
+                _ = sleep(Duration::from_millis(10)) => {}
+            }
+        }
+
+        // The rest of the abandoned reply arrives late and must not be taken for the next one
+        target.write_all(&reply[3..]).await.unwrap();
+
+        let toggle = Request::GpioToggle { pin: "a5" };
+        let (res, requests) = tokio::join!(
+            send_gpio_toggle(&mut link, "a5"),
+            serve(&mut target, &toggle)
+        );
+
+        assert!(res.is_ok());
+        assert_eq!(requests[..2], ["Clear", "Version"]);
+        assert!(requests[2].starts_with("Session"));
+        assert_eq!(requests[3..], [format!("{:?}", toggle)]);
+    }
+
+    #[tokio::test]
+    async fn completed_request_needs_no_resync() {
+        let (host, mut target) = duplex(256);
+        let mut link = Link::new(host);
+
+        let toggle = Request::GpioToggle { pin: "a5" };
+        for _ in 0..2 {
+            let (res, requests) = tokio::join!(
+                send_gpio_toggle(&mut link, "a5"),
+                serve(&mut target, &toggle)
+            );
+
+            assert!(res.is_ok());
+            assert_eq!(requests, [format!("{:?}", toggle)]);
+        }
+    }
+}
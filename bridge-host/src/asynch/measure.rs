@@ -0,0 +1,32 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+
+use crate::asynch::io::{send_measure, Link};
+use crate::io::Error;
+use crate::measure::{timeout_ms, Measurement};
+
+/// Measurements of the signal at a pin, see `bridge_host::measure::FrequencyCounter`
+pub struct FrequencyCounter<T> {
+    pin: String,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> FrequencyCounter<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(pin: String, channel: Arc<Mutex<Link<T>>>) -> Self {
+        FrequencyCounter { pin, channel }
+    }
+
+    pub async fn measure(&mut self, timeout: Duration) -> Result<Measurement, Error> {
+        send_measure(
+            &mut *self.channel.lock().await,
+            &self.pin,
+            timeout_ms(timeout),
+        )
+        .await
+    }
+}
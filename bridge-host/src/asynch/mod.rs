@@ -0,0 +1,14 @@
+//! Asynchronous counterparts of the blocking handles for use on a tokio runtime
+//!
+//! Each bridge is driven through its own `Link`, so any number of them can be used concurrently
+//! from a single runtime.
+
+pub mod capture;
+pub mod delay;
+pub mod encoder;
+pub mod gpio;
+pub mod i2c;
+pub mod io;
+pub mod measure;
+pub mod onewire;
+pub mod spi;
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::asynch::io::{
+    send_onewire_bit, send_onewire_read, send_onewire_reset, send_onewire_search,
+    send_onewire_write, Link,
+};
+use crate::io::Error;
+use crate::onewire::{self, decode_temperature, Rom, MATCH_ROM, SKIP_ROM};
+
+/// A 1-Wire bus driven by the target, see `bridge_host::onewire::OneWire`
+pub struct OneWire<T> {
+    pin: String,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> OneWire<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(pin: String, channel: Arc<Mutex<Link<T>>>) -> Self {
+        OneWire { pin, channel }
+    }
+
+    pub async fn reset(&mut self) -> Result<bool, Error> {
+        send_onewire_reset(&mut *self.channel.lock().await, &self.pin).await
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        send_onewire_write(&mut *self.channel.lock().await, &self.pin, data).await
+    }
+
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        send_onewire_read(&mut *self.channel.lock().await, &self.pin, buffer).await
+    }
+
+    pub async fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        send_onewire_bit(&mut *self.channel.lock().await, &self.pin, bit)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn read_bit(&mut self) -> Result<bool, Error> {
+        send_onewire_bit(&mut *self.channel.lock().await, &self.pin, true).await
+    }
+
+    pub async fn select(&mut self, rom: Option<Rom>) -> Result<(), Error> {
+        select(&mut *self.channel.lock().await, &self.pin, rom).await
+    }
+
+    pub async fn search(&mut self) -> Result<Vec<Rom>, Error> {
+        let mut link = self.channel.lock().await;
+        if !send_onewire_reset(&mut link, &self.pin).await? {
+            return Ok(Vec::new());
+        }
+
+        let mut roms = Vec::new();
+        let (mut rom, mut discrepancy) = (0, 0);
+        loop {
+            let (next, branch) =
+                send_onewire_search(&mut link, &self.pin, rom, discrepancy).await?;
+            rom = next;
+            discrepancy = branch;
+
+            if !Rom(rom).is_valid() {
+                return Err(Error::Crc);
+            }
+            roms.push(Rom(rom));
+
+            if discrepancy == 0 {
+                return Ok(roms);
+            }
+        }
+    }
+}
+
+/// Select the device `rom` or all devices on the bus at `pin`, see `bridge_host::onewire`
+async fn select<T>(link: &mut Link<T>, pin: &str, rom: Option<Rom>) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if !send_onewire_reset(link, pin).await? {
+        return Err(Error::Nack);
+    }
+
+    match rom {
+        Some(rom) => {
+            let mut command = vec![MATCH_ROM];
+            command.extend_from_slice(&rom.0.to_le_bytes());
+            send_onewire_write(link, pin, &command).await
+        }
+        None => send_onewire_write(link, pin, &[SKIP_ROM]).await,
+    }
+}
+
+/// A DS18B20 temperature sensor, see `bridge_host::onewire::Ds18b20`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ds18b20 {
+    pub rom: Rom,
+}
+
+impl Ds18b20 {
+    pub async fn find<T>(bus: &mut OneWire<T>) -> Result<Vec<Ds18b20>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(bus
+            .search()
+            .await?
+            .into_iter()
+            .filter(|rom| rom.family() == onewire::Ds18b20::FAMILY)
+            .map(|rom| Ds18b20 { rom })
+            .collect())
+    }
+
+    pub async fn convert_all<T>(bus: &mut OneWire<T>) -> Result<(), Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut link = bus.channel.lock().await;
+        select(&mut link, &bus.pin, None).await?;
+        send_onewire_write(&mut link, &bus.pin, &[onewire::CONVERT_T]).await
+    }
+
+    pub async fn convert<T>(&self, bus: &mut OneWire<T>) -> Result<(), Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut link = bus.channel.lock().await;
+        select(&mut link, &bus.pin, Some(self.rom)).await?;
+        send_onewire_write(&mut link, &bus.pin, &[onewire::CONVERT_T]).await
+    }
+
+    pub async fn read_temperature<T>(&self, bus: &mut OneWire<T>) -> Result<f32, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut scratchpad = [0; 9];
+        let mut link = bus.channel.lock().await;
+        select(&mut link, &bus.pin, Some(self.rom)).await?;
+        send_onewire_write(&mut link, &bus.pin, &[onewire::READ_SCRATCHPAD]).await?;
+        send_onewire_read(&mut link, &bus.pin, &mut scratchpad).await?;
+
+        decode_temperature(&scratchpad)
+    }
+
+    pub async fn temperature<T>(&self, bus: &mut OneWire<T>) -> Result<f32, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.convert(bus).await?;
+        sleep(onewire::Ds18b20::CONVERSION_TIME).await;
+        self.read_temperature(bus).await
+    }
+}
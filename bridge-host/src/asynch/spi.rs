@@ -0,0 +1,175 @@
+use embedded_hal::spi::Mode;
+use embedded_hal_async::spi::{self, ErrorType, Operation};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+
+use crate::asynch::io::{
+    send_spi_device_init, send_spi_init, send_spi_target_init, send_spi_target_load,
+    send_spi_target_poll, send_spi_transaction, send_spi_transfer, send_spi_write, Link,
+};
+use crate::io::Error;
+use crate::spi::{gather, mode_number, padded_transfer, scatter};
+use crate::time::Hertz;
+
+pub struct SPI<T> {
+    ident: String,
+    frequency: Hertz,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> SPI<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn new(
+        ident: String,
+        sck: String,
+        miso: String,
+        mosi: String,
+        speed: Hertz,
+        channel: Arc<Mutex<Link<T>>>,
+    ) -> Result<Self, Error> {
+        let hz = send_spi_init(
+            &mut *channel.lock().await,
+            &ident,
+            &sck,
+            &miso,
+            &mosi,
+            speed.0,
+        )
+        .await?;
+
+        Ok(SPI {
+            ident,
+            frequency: Hertz(hz),
+            channel,
+        })
+    }
+
+    /// The SCK frequency the target actually runs the bus at
+    pub fn frequency(&self) -> Hertz {
+        self.frequency
+    }
+
+    /// Attach a device selected by `cs`, see `bridge_host::spi::SPI::device`
+    pub async fn device(&self, cs: String, mode: Mode) -> Result<SPIDevice<T>, Error> {
+        send_spi_device_init(&mut *self.channel.lock().await, &self.ident, &cs).await?;
+
+        Ok(SPIDevice {
+            ident: self.ident.clone(),
+            cs,
+            mode: mode_number(mode),
+            channel: self.channel.clone(),
+        })
+    }
+}
+
+impl<T> ErrorType for SPI<T> {
+    type Error = Error;
+}
+
+impl<T> spi::SpiBus<u8> for SPI<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        words.iter_mut().for_each(|word| *word = 0);
+        send_spi_transfer(&mut *self.channel.lock().await, &self.ident, words).await
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        send_spi_write(&mut *self.channel.lock().await, &self.ident, words).await
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let mut words = padded_transfer(read, write);
+        send_spi_transfer(&mut *self.channel.lock().await, &self.ident, &mut words).await?;
+        read.copy_from_slice(&words[..read.len()]);
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        send_spi_transfer(&mut *self.channel.lock().await, &self.ident, words).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// An SPI bus on which the bridge acts as target, see `bridge_host::spi::SPITarget`
+pub struct SPITarget<T> {
+    ident: String,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> SPITarget<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn new(
+        ident: String,
+        sck: String,
+        miso: String,
+        mosi: String,
+        cs: String,
+        mode: Mode,
+        channel: Arc<Mutex<Link<T>>>,
+    ) -> Result<Self, Error> {
+        send_spi_target_init(
+            &mut *channel.lock().await,
+            &ident,
+            &sck,
+            &miso,
+            &mosi,
+            &cs,
+            mode_number(mode),
+        )
+        .await?;
+
+        Ok(SPITarget { ident, channel })
+    }
+
+    /// Answer the following frames with `data` of up to `MAX_WRITE_LENGTH` bytes
+    pub async fn load(&mut self, data: &[u8]) -> Result<(), Error> {
+        send_spi_target_load(&mut *self.channel.lock().await, &self.ident, data).await
+    }
+
+    /// Fetch the frames received since the last call
+    pub async fn frames(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        send_spi_target_poll(&mut *self.channel.lock().await, &self.ident).await
+    }
+}
+
+/// A device on a shared SPI bus with its own chip select and mode, see `SPI::device`
+pub struct SPIDevice<T> {
+    ident: String,
+    cs: String,
+    mode: u8,
+    channel: Arc<Mutex<Link<T>>>,
+}
+
+impl<T> ErrorType for SPIDevice<T> {
+    type Error = Error;
+}
+
+/// All operations are executed as a single transfer, so delays other than 0 are not supported
+impl<T> spi::SpiDevice<u8> for SPIDevice<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        let mut words = gather(operations)?;
+        send_spi_transaction(
+            &mut *self.channel.lock().await,
+            &self.ident,
+            &self.cs,
+            self.mode,
+            &mut words,
+        )
+        .await?;
+        scatter(operations, &words);
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use bridge_common::encoding::{
+    delay, gpio_sethigh, gpio_setlow, gpio_toggle, i2c_write, i2c_write_more, spi_write,
+    I2CAddress, Request, MAX_BATCH_LENGTH, MAX_DELAY_US, MAX_WRITE_LENGTH,
+};
+
+use crate::io::{encode, encoded_len, Error};
+
+/// A sequence of operations executed back-to-back by the target, saving a round trip each
+///
+/// Operations are collected with the builder methods and submitted with `io::send_batch`. As
+/// many operations as fit are sent in a single request, a long batch is split into several
+/// ones with the usual delay of a round trip in between. The target stops at the first failing
+/// operation, whose error is returned.
+///
+/// Operations are checked when they are added, an operation too large to be sent makes
+/// submitting the whole batch fail.
+#[derive(Debug, Default)]
+pub struct Batch {
+    /// The encoded requests, split into the payloads of `Batch` requests
+    payloads: Vec<Vec<u8>>,
+    /// The encoded length of the first operation too large to be part of a batch
+    oversized: Option<usize>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    pub fn set_high(&mut self, pin: &str) -> &mut Self {
+        self.push(&gpio_sethigh(pin))
+    }
+
+    pub fn set_low(&mut self, pin: &str) -> &mut Self {
+        self.push(&gpio_setlow(pin))
+    }
+
+    pub fn toggle(&mut self, pin: &str) -> &mut Self {
+        self.push(&gpio_toggle(pin))
+    }
+
+    /// Write `data` of any length to the device at `addr` on the I2C bus `ident`
+    pub fn i2c_write<A: Into<I2CAddress>>(
+        &mut self,
+        ident: &str,
+        addr: A,
+        data: &[u8],
+    ) -> &mut Self {
+        let addr = addr.into();
+        let mut chunks = data.chunks(MAX_WRITE_LENGTH);
+        let last = chunks.next_back().unwrap_or_default();
+
+        for chunk in chunks {
+            self.push(&i2c_write_more(ident, addr, chunk));
+        }
+
+        self.push(&i2c_write(ident, addr, last))
+    }
+
+    /// Clock out `data` of any length on the SPI bus `ident`
+    pub fn spi_write(&mut self, ident: &str, data: &[u8]) -> &mut Self {
+        for chunk in data.chunks(MAX_WRITE_LENGTH) {
+            self.push(&spi_write(ident, chunk));
+        }
+
+        self
+    }
+
+    /// Wait for `us` microseconds before executing the following operations
+    pub fn delay_us(&mut self, mut us: u32) -> &mut Self {
+        while us > MAX_DELAY_US {
+            self.push(&delay(MAX_DELAY_US));
+            us -= MAX_DELAY_US;
+        }
+
+        self.push(&delay(us))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payloads.is_empty()
+    }
+
+    /// The payloads of the `Batch` requests to send
+    pub(crate) fn payloads(&self) -> Result<&[Vec<u8>], Error> {
+        if let Some(len) = self.oversized {
+            return Err(Error::TooLarge {
+                len,
+                limit: MAX_BATCH_LENGTH,
+            });
+        }
+
+        Ok(&self.payloads)
+    }
+
+    fn push(&mut self, request: &Request) -> &mut Self {
+        let encoded = match encode(request) {
+            Ok(encoded) if encoded.len() <= MAX_BATCH_LENGTH => encoded,
+            _ => {
+                self.oversized.get_or_insert_with(|| encoded_len(request));
+                return self;
+            }
+        };
+
+        match self.payloads.last_mut() {
+            Some(payload) if payload.len() + encoded.len() <= MAX_BATCH_LENGTH => {
+                payload.extend_from_slice(&encoded)
+            }
+            _ => self.payloads.push(encoded.to_vec()),
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+    use crate::io::{encode, Error};
+    use bridge_common::encoding::{gpio_toggle, i2c_write, i2c_write_more, MAX_BATCH_LENGTH};
+
+    #[test]
+    fn push_splits_at_batch_length() {
+        let toggle = encode(&gpio_toggle("a5")).unwrap();
+        let count = MAX_BATCH_LENGTH / toggle.len() * 2 + 1;
+
+        let mut batch = Batch::new();
+        for _ in 0..count {
+            batch.toggle("a5");
+        }
+
+        let payloads = batch.payloads().unwrap();
+        assert_eq!(payloads.len(), 3);
+        for payload in payloads {
+            assert!(payload.len() <= MAX_BATCH_LENGTH);
+            assert!(payload
+                .chunks(toggle.len())
+                .all(|request| request == &toggle[..]));
+        }
+        assert_eq!(payloads.concat(), toggle.repeat(count));
+    }
+
+    #[test]
+    fn push_chunks_long_writes() {
+        let data = [0x55; 100];
+        let mut batch = Batch::new();
+        batch.i2c_write("i2c1", 0x50u8, &data);
+
+        let expected = [
+            encode(&i2c_write_more("i2c1", 0x50u8.into(), &data[..48])).unwrap(),
+            encode(&i2c_write_more("i2c1", 0x50u8.into(), &data[48..96])).unwrap(),
+            encode(&i2c_write("i2c1", 0x50u8.into(), &data[96..])).unwrap(),
+        ];
+        // A full chunk leaves no room for another request in its payload
+        assert_eq!(
+            batch.payloads().unwrap(),
+            expected.map(|request| request.to_vec())
+        );
+    }
+
+    #[test]
+    fn push_rejects_oversized_requests() {
+        let mut batch = Batch::new();
+        batch
+            .toggle("a5")
+            .i2c_write("an-unusually-long-identifier", 0x50u8, &[0; 48]);
+
+        assert!(matches!(
+            batch.payloads(),
+            Err(Error::TooLarge { len, limit: MAX_BATCH_LENGTH }) if len > MAX_BATCH_LENGTH
+        ));
+    }
+}
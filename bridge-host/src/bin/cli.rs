@@ -1,4 +1,5 @@
 use std::env;
+use std::ffi::OsStr;
 use std::io::{self};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -10,17 +11,105 @@ use rustyline::Editor;
 use embedded_hal::digital::v2::OutputPin;
 
 use simplelog::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use bridge_common::encoding::I2C_SCAN_ADDRESSES;
+use bridge_host::i2c::BusManager;
+use bridge_host::measure::{FrequencyCounter, MAX_TIMEOUT};
+use bridge_host::smbus::SMBus;
+use bridge_host::time::U32Ext;
 
 fn usage() {
     println!("Overview of implemented commands:");
     println!("  gpio: Control individual IO pins");
     println!("    init <pin>: Initialiase remote GPIO pin identified by <pin> into push pull mode");
     println!("    set <pin> (low|high): Set the signal level of the remote GPIO pin identified by <pin> low or high");
+    println!("    pulse <pin> (low|high) <us>: Drive the initialised GPIO pin <pin> low or high for exactly <us> microseconds and back");
+    println!("  i2c: Talk to devices on a remote I2C bus");
+    println!("    init <bus> <scl> <sda> <speed>: Initialise the remote I2C bus identified by <bus> on pins <scl> and <sda> running at <speed> kHz at most");
+    println!("    scan <bus>: Show the addresses of all devices responding on the initialised I2C bus <bus>");
+    println!("    recover <bus>: Clock the initialised I2C bus <bus> until a device holding SDA low releases it");
+    println!("  smbus: Talk to SMBus devices on an initialised I2C bus, numbers may be given in hex with 0x");
+    println!("    (read-byte|read-word|read-block) <bus> <addr> <cmd>: Read from the device at <addr> after sending the command code <cmd>");
+    println!("    (write-byte|write-word) <bus> <addr> <cmd> <value>: Write <value> to the device at <addr> after sending the command code <cmd>");
+    println!("    pec <bus> (on|off): Enable or disable packet error checking for all transactions on <bus>");
+    println!("  measure <pin>: Show frequency, period and duty cycle of the signal at <pin>");
     println!("  help: This help");
     println!("  quit (or exit): Exit this tool");
 }
 
+fn open_port(serial: &OsStr) -> io::Result<serial::SystemPort> {
+    let mut port = serial::open(serial)?;
+    port.reconfigure(&|settings| {
+        settings.set_baud_rate(serial::Baud115200)?;
+        settings.set_char_size(serial::Bits8);
+        settings.set_parity(serial::ParityNone);
+        settings.set_stop_bits(serial::Stop1);
+        settings.set_flow_control(serial::FlowNone);
+        Ok(())
+    })?;
+
+    port.set_timeout(Duration::from_millis(100))?;
+
+    Ok(port)
+}
+
+/// Parse a decimal number or a hexadecimal one prefixed by 0x
+fn parse_number(arg: &str) -> Option<u32> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// Execute the SMBus transaction `op` with the device at `addr` and print the outcome
+fn smbus_transaction<T: io::Read + io::Write>(
+    smbus: &mut SMBus<T>,
+    op: &str,
+    addr: u8,
+    cmd: u8,
+    value: Option<u16>,
+) {
+    let res = match (op, value) {
+        ("read-byte", None) => smbus
+            .read_byte(addr, cmd)
+            .map(|value| println!("0x{:02x}", value)),
+        ("read-word", None) => smbus
+            .read_word(addr, cmd)
+            .map(|value| println!("0x{:04x}", value)),
+        ("read-block", None) => smbus
+            .block_read(addr, cmd)
+            .map(|block| println!("{:02x?}", block)),
+        ("write-byte", Some(value)) if value <= 0xff => smbus.write_byte(addr, cmd, value as u8),
+        ("write-word", Some(value)) => smbus.write_word(addr, cmd, value),
+        _ => {
+            println!("Don't know SMBus transaction '{}' with these arguments", op);
+            return;
+        }
+    };
+
+    res.unwrap_or_else(|e| println!("SMBus transaction failed: {}", e));
+}
+
+/// Print the addresses found by an I2C bus scan in the style of i2cdetect
+fn print_i2c_scan(found: &[u8]) {
+    println!("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+
+    for row in (0..128_u8).step_by(16) {
+        print!("{:02x}:", row);
+        for addr in row..row + 16 {
+            if found.contains(&addr) {
+                print!(" {:02x}", addr);
+            } else if I2C_SCAN_ADDRESSES.contains(&addr) {
+                print!(" --");
+            } else {
+                print!("   ");
+            }
+        }
+        println!();
+    }
+}
+
 fn main() -> io::Result<()> {
     TermLogger::init(
         LevelFilter::Debug,
@@ -33,30 +122,23 @@ fn main() -> io::Result<()> {
 
     println!("Welcome to the embedded-bridge CLI tool");
 
-    let serial = &env::args_os().nth(1).unwrap();
+    let serial = env::args_os().nth(1).unwrap();
 
     println!(
         "Connecting to the device via (USB<->)serial port at {:?}",
         &serial
     );
 
-    let mut port = serial::open(&serial).unwrap();
-    port.reconfigure(&|settings| {
-        settings.set_baud_rate(serial::Baud115200)?;
-        settings.set_char_size(serial::Bits8);
-        settings.set_parity(serial::ParityNone);
-        settings.set_stop_bits(serial::Stop1);
-        settings.set_flow_control(serial::FlowNone);
-        Ok(())
-    })?;
+    let mut link = bridge_host::io::Link::new(open_port(&serial)?);
+    link.set_reconnect(bridge_host::io::DEFAULT_RECONNECT_TIMEOUT, move || {
+        open_port(&serial)
+    });
 
-    port.set_timeout(Duration::from_millis(2000))?;
-
-    let port = Arc::new(Mutex::new(Box::from(port)));
+    let port = Arc::new(Mutex::new(Box::from(link)));
 
     println!("Checking compatible firmware version...");
 
-    bridge_host::common::assert_version(port.clone());
+    bridge_host::common::assert_version(port.clone())?;
 
     let mut rl = Editor::<()>::new();
 
@@ -67,6 +149,10 @@ fn main() -> io::Result<()> {
     let mut gpios: HashMap<String, bridge_host::gpio::PushPullPin<serial::SystemPort>> =
         HashMap::new();
 
+    let mut i2cs: HashMap<String, BusManager<serial::SystemPort>> = HashMap::new();
+
+    let mut pec_buses: HashSet<String> = HashSet::new();
+
     loop {
         let prompt = format!("{} >> ", name);
         match rl.readline(&prompt) {
@@ -91,7 +177,7 @@ fn main() -> io::Result<()> {
                         },
                         3 => match rest[0] {
                             "set" => {
-                                if let Some(ref mut pin) = gpios.get_mut(&rest[1].to_string()) {
+                                if let Some(ref mut pin) = gpios.get_mut(rest[1]) {
                                     match rest[2] {
                                         "low" | "off" => pin
                                             .set_low()
@@ -107,9 +193,140 @@ fn main() -> io::Result<()> {
                             }
                             _ => println!("Expecting arguments"),
                         },
-                        4..=1000 => println!("Too many arguments for 'gpio'"),
+                        4 => match rest[0] {
+                            "pulse" => match (gpios.get_mut(rest[1]), rest[3].parse::<u16>()) {
+                                (None, _) => {
+                                    println!("No initialised GPIO {}", &rest[1].to_string())
+                                }
+                                (Some(_), Err(_)) => {
+                                    println!("Expecting the pulse width in microseconds")
+                                }
+                                (Some(pin), Ok(us)) => {
+                                    let high = match rest[2] {
+                                        "low" | "off" => false,
+                                        "high" | "on" => true,
+                                        _ => {
+                                            println!("Expecting low or high as signal state");
+                                            continue;
+                                        }
+                                    };
+
+                                    pin.pulse(high, us)
+                                        .unwrap_or_else(|e| println!("Couldn't pulse GPIO: {}", e));
+                                }
+                            },
+                            _ => println!("Expecting arguments"),
+                        },
+                        5..=1000 => println!("Too many arguments for 'gpio'"),
                         _ => println!("Too few arguments for 'gpio'"),
                     },
+                    Some((&"i2c", rest)) => match rest.len() {
+                        2 => match rest[0] {
+                            "scan" => {
+                                if let Some(i2c) = i2cs.get(rest[1]) {
+                                    match i2c.acquire().scan() {
+                                        Ok(found) => print_i2c_scan(&found),
+                                        Err(e) => println!("Couldn't scan I2C bus: {}", e),
+                                    }
+                                } else {
+                                    println!("No initialised I2C bus {}", &rest[1].to_string());
+                                }
+                            }
+                            "recover" => {
+                                if let Some(i2c) = i2cs.get(rest[1]) {
+                                    i2c.acquire().recover_bus().unwrap_or_else(|e| {
+                                        println!("Couldn't recover I2C bus: {}", e)
+                                    });
+                                } else {
+                                    println!("No initialised I2C bus {}", &rest[1].to_string());
+                                }
+                            }
+                            _ => println!("Expecting arguments"),
+                        },
+                        5 => match rest[0] {
+                            "init" => match rest[4].parse::<u32>() {
+                                Ok(khz) => match khz.checked_mul(1_000) {
+                                    Some(speed) => match bridge_host::i2c::I2C::new(
+                                        rest[1].to_string(),
+                                        rest[2].to_string(),
+                                        rest[3].to_string(),
+                                        speed.hz(),
+                                        port.clone(),
+                                    ) {
+                                        Ok(i2c) => {
+                                            println!("I2C bus running at {}", i2c.frequency());
+                                            i2cs.insert(rest[1].to_string(), BusManager::new(i2c));
+                                        }
+                                        Err(e) => println!("Could not initialise I2C bus: {}", e),
+                                    },
+                                    None => println!("Speed of {} kHz is out of range", khz),
+                                },
+                                Err(_) => println!("Expecting the speed in kHz"),
+                            },
+                            _ => println!("Expecting arguments"),
+                        },
+                        6..=1000 => println!("Too many arguments for 'i2c'"),
+                        _ => println!("Too few arguments for 'i2c'"),
+                    },
+                    Some((&"smbus", rest)) => match rest.len() {
+                        3 => match (rest[0], rest[2]) {
+                            ("pec", "on") => {
+                                pec_buses.insert(rest[1].to_string());
+                            }
+                            ("pec", "off") => {
+                                pec_buses.remove(rest[1]);
+                            }
+                            ("pec", _) => println!("Expecting on or off"),
+                            _ => println!("Expecting arguments"),
+                        },
+                        4 | 5 => {
+                            let numbers: Option<Vec<u32>> =
+                                rest[2..].iter().map(|arg| parse_number(arg)).collect();
+
+                            match (i2cs.get(rest[1]), numbers.as_deref()) {
+                                (None, _) => {
+                                    println!("No initialised I2C bus {}", &rest[1].to_string())
+                                }
+                                (Some(i2c), Some([addr, cmd, value @ ..]))
+                                    if *addr <= 0x7f
+                                        && *cmd <= 0xff
+                                        && value.iter().all(|v| *v <= 0xffff) =>
+                                {
+                                    let mut smbus = SMBus::new(i2c.acquire());
+                                    smbus.set_pec(pec_buses.contains(rest[1]));
+                                    smbus_transaction(
+                                        &mut smbus,
+                                        rest[0],
+                                        *addr as u8,
+                                        *cmd as u8,
+                                        value.first().map(|v| *v as u16),
+                                    );
+                                }
+                                _ => println!(
+                                    "Expecting an address, a command code and a value in range"
+                                ),
+                            }
+                        }
+                        6..=1000 => println!("Too many arguments for 'smbus'"),
+                        _ => println!("Too few arguments for 'smbus'"),
+                    },
+                    Some((&"measure", rest)) => match rest {
+                        [pin] => {
+                            let mut counter = FrequencyCounter::new(pin.to_string(), port.clone());
+                            match counter.measure(MAX_TIMEOUT) {
+                                Ok(m) => println!(
+                                    "{:.3} Hz, period {:?}, high for {:?}, duty cycle {:.1}%",
+                                    m.frequency(),
+                                    m.period(),
+                                    m.high_time(),
+                                    m.duty_cycle() * 100.0
+                                ),
+                                Err(e) => println!("Couldn't measure signal: {}", e),
+                            }
+                        }
+                        [] => println!("Expecting a pin"),
+                        _ => println!("Too many arguments for 'measure'"),
+                    },
                     Some((&"exit", _)) | Some((&"quit", _)) => break,
                     Some((&"help", _)) | Some((&"h", _)) => usage(),
                     Some((&s, _)) => println!("Don't know what '{}' is, try 'h' for help", s),
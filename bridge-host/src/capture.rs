@@ -0,0 +1,221 @@
+//! Sampling pins of the target in the background like a logic analyser
+//!
+//! A `Capture` can be written as Value Change Dump or as raw samples. Sigrok session files
+//! (`.sr`) are not supported, raw samples have to be imported with their rate and number of pins
+//! given explicitly, e.g. with `sigrok-cli -I binary`.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::io::{send_capture_read, send_capture_start, Error, Link};
+use crate::time::Hertz;
+
+/// How long to wait for further samples when the target has none to return
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A logic analyser sampling up to 8 pins of the same port
+///
+/// The target samples in the background, so the link stays free to e.g. drive a bus while its
+/// pins are captured. Up to 1024 samples are taken at a rate of up to 1 MHz.
+pub struct Analyser<T> {
+    pins: Vec<String>,
+    /// Rate and number of samples of the capture in progress
+    running: Option<(Hertz, u16)>,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> Analyser<T>
+where
+    T: Read + Write,
+{
+    pub fn new(pins: &[String], channel: Arc<Mutex<Box<Link<T>>>>) -> Self {
+        Analyser {
+            pins: pins.to_vec(),
+            running: None,
+            channel,
+        }
+    }
+
+    /// Start taking `samples` samples at `rate` at most, returns the rate actually used
+    ///
+    /// A capture still in progress is discarded.
+    pub fn start(&mut self, rate: Hertz, samples: u16) -> Result<Hertz, Error> {
+        let hz = send_capture_start(
+            &mut *self.channel.lock().unwrap(),
+            &self.pins.join(","),
+            rate.0,
+            samples,
+        )?;
+
+        self.running = Some((Hertz(hz), samples));
+        Ok(Hertz(hz))
+    }
+
+    /// Wait for the capture started last to complete and return its samples
+    ///
+    /// Fails with `Error::NotStarted` unless a capture was started since the last call.
+    pub fn finish(&mut self) -> Result<Capture, Error> {
+        let (rate, count) = self.running.take().ok_or(Error::NotStarted)?;
+        let mut samples = Vec::with_capacity(usize::from(count));
+
+        while samples.len() < usize::from(count) {
+            let data = send_capture_read(&mut *self.channel.lock().unwrap(), samples.len() as u16)?;
+            if data.is_empty() {
+                thread::sleep(POLL_INTERVAL);
+            }
+            samples.extend_from_slice(&data);
+        }
+
+        Ok(Capture {
+            pins: self.pins.clone(),
+            rate,
+            samples,
+        })
+    }
+}
+
+/// The samples taken by a logic analyser
+#[derive(Clone, Debug)]
+pub struct Capture {
+    /// The sampled pins, pin `n` is found in bit `n` of each sample
+    pub pins: Vec<String>,
+    pub rate: Hertz,
+    pub samples: Vec<u8>,
+}
+
+impl Capture {
+    /// Write the capture as Value Change Dump, e.g. to be viewed with GTKWave or PulseView
+    pub fn write_vcd<W: io::Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "$timescale 1 ns $end")?;
+        writeln!(out, "$scope module bridge $end")?;
+        for (bit, pin) in self.pins.iter().enumerate() {
+            writeln!(out, "$var wire 1 {} {} $end", identifier(bit), pin)?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        // Bits beyond the sampled pins must not show up as changes
+        let mask = (1u16 << self.pins.len()).wrapping_sub(1) as u8;
+        let mut previous = None;
+        for (i, &sample) in self.samples.iter().enumerate() {
+            let sample = sample & mask;
+            if previous == Some(sample) {
+                continue;
+            }
+
+            writeln!(out, "#{}", self.timestamp(i))?;
+            if previous.is_none() {
+                writeln!(out, "$dumpvars")?;
+            }
+            for bit in 0..self.pins.len() {
+                let level = (sample >> bit) & 1;
+                if previous.is_none_or(|previous| (previous >> bit) & 1 != level) {
+                    writeln!(out, "{}{}", level, identifier(bit))?;
+                }
+            }
+            if previous.is_none() {
+                writeln!(out, "$end")?;
+            }
+
+            previous = Some(sample);
+        }
+
+        // Mark the end of the capture, the levels are unchanged since the last change
+        writeln!(out, "#{}", self.timestamp(self.samples.len()))
+    }
+
+    /// Write the samples as raw bytes with pin `n` in bit `n`, without any header
+    ///
+    /// This is not a sigrok session file, the pin names and the rate have to be given when
+    /// importing the data, e.g. with `sigrok-cli -I binary:numchannels=<pins>:samplerate=<rate>`
+    /// or PulseView's "Raw binary logic data" import.
+    pub fn write_raw<W: io::Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(&self.samples)
+    }
+
+    /// Time of the sample `index` in nanoseconds since the start of the capture
+    fn timestamp(&self, index: usize) -> u64 {
+        index as u64 * 1_000_000_000 / u64::from(self.rate.0.max(1))
+    }
+}
+
+/// The VCD identifier of pin `bit`, using the printable characters from `!` on
+fn identifier(bit: usize) -> char {
+    char::from(b'!' + bit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Analyser, Capture};
+    use crate::io::tests::{firmware, link};
+    use crate::io::Error;
+    use crate::time::Hertz;
+    use std::sync::{Arc, Mutex};
+
+    const HEADER: &str = "$timescale 1 ns $end
+$scope module bridge $end
+$var wire 1 ! a0 $end
+$var wire 1 \" a1 $end
+$upscope $end
+$enddefinitions $end
+";
+
+    fn vcd(samples: &[u8]) -> String {
+        let capture = Capture {
+            pins: vec!["a0".to_string(), "a1".to_string()],
+            rate: Hertz(1_000_000),
+            samples: samples.to_vec(),
+        };
+
+        let mut out = Vec::new();
+        capture.write_vcd(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_vcd_dumps_changes_only() {
+        let expected = "#0
+$dumpvars
+1!
+0\"
+$end
+#2000
+1\"
+#3000
+0!
+#4000
+";
+        assert_eq!(
+            vcd(&[0b01, 0b01, 0b11, 0b10]),
+            HEADER.to_string() + expected
+        );
+    }
+
+    #[test]
+    fn write_vcd_ignores_unsampled_bits() {
+        let expected = "#0
+$dumpvars
+0!
+0\"
+$end
+#3000
+";
+        assert_eq!(vcd(&[0b100, 0b000, 0b100]), HEADER.to_string() + expected);
+    }
+
+    #[test]
+    fn write_vcd_of_empty_capture() {
+        assert_eq!(vcd(&[]), HEADER.to_string() + "#0\n");
+    }
+
+    #[test]
+    fn finish_requires_started_capture() {
+        let channel = Arc::new(Mutex::new(Box::new(link(firmware))));
+        let mut analyser = Analyser::new(&["a0".to_string()], channel.clone());
+
+        assert!(matches!(analyser.finish(), Err(Error::NotStarted)));
+        assert!(channel.lock().unwrap().get_mut().requests.is_empty());
+    }
+}
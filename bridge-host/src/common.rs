@@ -1,24 +1,17 @@
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 
-use crate::io::{send_clear, send_version};
+use crate::io::{Error, Link};
 
-pub fn assert_version<T: Read + Write>(channel: Arc<Mutex<Box<T>>>) {
-    send_clear(&mut *channel.lock().unwrap()).ok();
+/// Make sure the target is reachable and runs firmware speaking the protocol of this crate
+///
+/// Fails with `Error::VersionMismatch` if the firmware is incompatible.
+pub fn assert_version<T: Read + Write>(channel: Arc<Mutex<Box<Link<T>>>>) -> Result<(), Error> {
+    channel.lock().unwrap().resync()?;
+    log::info!(
+        "Bridge protocol version {}",
+        bridge_common::encoding::VERSION
+    );
 
-    let target_version =
-        send_version(&mut *channel.lock().unwrap()).expect("Could not get target version");
-
-    if target_version != bridge_common::encoding::VERSION {
-        panic!(
-            "Protocol versions locally and on the target differ, there: {}, here: {}",
-            target_version,
-            bridge_common::encoding::VERSION
-        );
-    } else {
-        log::info!(
-            "Bridge protocol version {}",
-            bridge_common::encoding::VERSION
-        );
-    }
+    Ok(())
 }
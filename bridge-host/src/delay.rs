@@ -0,0 +1,77 @@
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use std::thread;
+use std::time::Duration;
+
+/// Delays for drivers of devices attached to the bridge, slept on the host
+///
+/// Requests are executed one after the other, so a delay between two of them is at least as
+/// long on the target as it is on the host. It easily takes longer though, by a round trip and
+/// whatever the host scheduler adds. Operations which need short or precise delays between them
+/// can be put into a `Batch` with `Batch::delay_us`, executed by the target instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Delay;
+
+impl Delay {
+    pub fn new() -> Self {
+        Delay
+    }
+
+    fn wait_us(&mut self, us: u32) {
+        thread::sleep(Duration::from_micros(u64::from(us)));
+    }
+
+    fn wait_ms(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(u64::from(ms)));
+    }
+}
+
+impl DelayUs<u32> for Delay {
+    fn delay_us(&mut self, us: u32) {
+        self.wait_us(us);
+    }
+}
+
+impl DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        self.wait_us(u32::from(us));
+    }
+}
+
+impl DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        self.wait_us(u32::from(us));
+    }
+}
+
+impl DelayMs<u32> for Delay {
+    fn delay_ms(&mut self, ms: u32) {
+        self.wait_ms(ms);
+    }
+}
+
+impl DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        self.wait_ms(u32::from(ms));
+    }
+}
+
+impl DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        self.wait_ms(u32::from(ms));
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        thread::sleep(Duration::from_nanos(u64::from(ns)));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.wait_us(us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.wait_ms(ms);
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::io::{send_encoder_init, send_encoder_read, send_encoder_reset, Error, Link};
+
+/// The direction an encoder last moved in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Signal A leading B
+    Up,
+    /// Signal B leading A
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Position {
+    /// Edges counted since the encoder was set up or reset, four per cycle of the signals
+    pub count: i64,
+    pub direction: Direction,
+}
+
+/// The counter of an encoder as read from the target
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Counter {
+    /// The counter, which wraps around after `bits` bits
+    pub value: u32,
+    pub bits: u8,
+    pub direction: Direction,
+}
+
+impl Counter {
+    /// Edges counted since the counter was at `previous`, assuming it wrapped around by less
+    /// than half of its range in between
+    pub fn since(&self, previous: u32) -> i64 {
+        let shift = 32 - u32::from(self.bits.clamp(1, 32));
+        i64::from((self.value.wrapping_sub(previous) << shift) as i32 >> shift)
+    }
+}
+
+/// An incremental quadrature encoder decoded by a timer of the target
+///
+/// The target counts without any further requests, so no edges are missed however fast the
+/// encoder turns. Signal A has to be connected to the first and signal B to the second channel
+/// of the timer: a0 or a5 and a1 or b3 for "tim2", a6 or b4 and a7 or b5 for "tim3".
+///
+/// The count is extended to 64 bits by adding up the changes of the counter of the target
+/// between reads. The 16 bit counter of "tim3" therefore has to be read before the encoder
+/// moved by 32768 edges, that of "tim2" within 2147483648 edges.
+///
+/// Measuring a signal with `bridge_host::measure` on a pin of the same timer stops the encoder,
+/// as does a reset of the target. Reading the position fails afterwards.
+pub struct Encoder<T> {
+    ident: String,
+    /// The counter of the target at the last read
+    last: u32,
+    count: i64,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> Encoder<T>
+where
+    T: Read + Write,
+{
+    /// Set up the timer `ident` to decode the signals at `a_pin` and `b_pin`, starting at zero
+    pub fn new(
+        ident: String,
+        a_pin: String,
+        b_pin: String,
+        channel: Arc<Mutex<Box<Link<T>>>>,
+    ) -> Result<Self, Error> {
+        send_encoder_init(&mut *channel.lock().unwrap(), &ident, &a_pin, &b_pin)?;
+
+        Ok(Encoder {
+            ident,
+            last: 0,
+            count: 0,
+            channel,
+        })
+    }
+
+    pub fn position(&mut self) -> Result<Position, Error> {
+        let counter = send_encoder_read(&mut *self.channel.lock().unwrap(), &self.ident)?;
+        self.count += counter.since(self.last);
+        self.last = counter.value;
+
+        Ok(Position {
+            count: self.count,
+            direction: counter.direction,
+        })
+    }
+
+    pub fn count(&mut self) -> Result<i64, Error> {
+        Ok(self.position()?.count)
+    }
+
+    /// Set the count back to zero
+    pub fn reset(&mut self) -> Result<(), Error> {
+        send_encoder_reset(&mut *self.channel.lock().unwrap(), &self.ident)?;
+        self.last = 0;
+        self.count = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, Direction};
+
+    fn counter(value: u32, bits: u8) -> Counter {
+        Counter {
+            value,
+            bits,
+            direction: Direction::Up,
+        }
+    }
+
+    #[test]
+    fn since_counts_across_wrap_around() {
+        assert_eq!(counter(5, 16).since(0xfffe), 7);
+        assert_eq!(counter(0xfffe, 16).since(5), -7);
+        assert_eq!(counter(3, 32).since(0xffff_fff0), 19);
+        assert_eq!(counter(0xffff_fff0, 32).since(3), -19);
+    }
+
+    #[test]
+    fn since_ignores_bits_beyond_counter() {
+        assert_eq!(counter(0x0001_0010, 16).since(0x10), 0);
+        assert_eq!(counter(0x7fff, 16).since(0), 32767);
+        assert_eq!(counter(0x8000, 16).since(0), -32768);
+    }
+}
@@ -2,22 +2,97 @@ use embedded_hal::digital::v2::OutputPin;
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 
-use crate::io::{send_clear, send_gpio_high, send_gpio_init_pp, send_gpio_low};
+use crate::io::{
+    send_gpio_high, send_gpio_init_pp, send_gpio_low, send_gpio_waveform, Error, Link,
+};
+
+/// A step of a waveform, see `Waveform::play`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Step {
+    /// The level of pin `n` of the waveform in bit `n`
+    pub levels: u8,
+    /// How long to hold the levels in microseconds
+    pub us: u16,
+}
+
+/// Encode `steps` as expected by `Request::GpioWaveform`
+pub(crate) fn encode_steps(steps: &[Step]) -> Vec<u8> {
+    steps
+        .iter()
+        .flat_map(|step| {
+            let [low, high] = step.us.to_le_bytes();
+            vec![step.levels, low, high]
+        })
+        .collect()
+}
 
 pub struct PushPullPin<T> {
     pinname: String,
-    channel: Arc<Mutex<Box<T>>>,
+    channel: Arc<Mutex<Box<Link<T>>>>,
 }
 
 impl<T> PushPullPin<T>
 where
     T: Read + Write,
 {
-    pub fn new(pinname: String, channel: Arc<Mutex<Box<T>>>) -> Result<Self, ()> {
-        send_clear(&mut *channel.lock().unwrap()).ok();
-        let res = send_gpio_init_pp(&mut *channel.lock().unwrap(), &pinname);
-        res.map(|_| PushPullPin { channel, pinname })
-            .map_err(|_| ())
+    pub fn new(pinname: String, channel: Arc<Mutex<Box<Link<T>>>>) -> Result<Self, Error> {
+        send_gpio_init_pp(&mut *channel.lock().unwrap(), &pinname)?;
+        Ok(PushPullPin { channel, pinname })
+    }
+
+    /// Drive the pin to `high` for exactly `us` microseconds and to the opposite level afterwards
+    pub fn pulse(&mut self, high: bool, us: u16) -> Result<(), Error> {
+        let steps = [
+            Step {
+                levels: u8::from(high),
+                us,
+            },
+            Step {
+                levels: u8::from(!high),
+                us: 0,
+            },
+        ];
+
+        send_gpio_waveform(
+            &mut *self.channel.lock().unwrap(),
+            &self.pinname,
+            &encode_steps(&steps),
+        )
+    }
+}
+
+/// Up to 8 pins driven through waveforms timed by the target with microsecond resolution
+///
+/// Timing precise waveforms, e.g. bit patterns or reset pulses, cannot be generated with
+/// separate requests to set the pins. A whole waveform has to fit into a single request
+/// though, which limits it to 16 steps.
+pub struct Waveform<T> {
+    pins: String,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> Waveform<T>
+where
+    T: Read + Write,
+{
+    /// Pin `n` of `pins` follows bit `n` of the levels of the steps, the pins are turned into
+    /// push pull outputs when playing the first waveform
+    pub fn new(pins: &[String], channel: Arc<Mutex<Box<Link<T>>>>) -> Self {
+        Waveform {
+            pins: pins.join(","),
+            channel,
+        }
+    }
+
+    /// Drive the pins through `steps`, they keep the levels of the last step afterwards
+    ///
+    /// Fails with `Error::TooLarge` if the steps don't fit into a single request.
+    pub fn play(&mut self, steps: &[Step]) -> Result<(), Error> {
+        send_gpio_waveform(
+            &mut *self.channel.lock().unwrap(),
+            &self.pins,
+            &encode_steps(steps),
+        )
     }
 }
 
@@ -25,13 +100,32 @@ impl<T> OutputPin for PushPullPin<T>
 where
     T: Read + Write,
 {
-    type Error = ();
+    type Error = Error;
 
-    fn set_high(&mut self) -> Result<(), ()> {
-        send_gpio_high(&mut *self.channel.lock().unwrap(), &self.pinname).map_err(|_| ())
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        send_gpio_high(&mut *self.channel.lock().unwrap(), &self.pinname)
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        send_gpio_low(&mut *self.channel.lock().unwrap(), &self.pinname)
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<T> embedded_hal_1::digital::ErrorType for PushPullPin<T> {
+    type Error = Error;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<T> embedded_hal_1::digital::OutputPin for PushPullPin<T>
+where
+    T: Read + Write,
+{
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        send_gpio_high(&mut *self.channel.lock().unwrap(), &self.pinname)
     }
 
-    fn set_low(&mut self) -> Result<(), ()> {
-        send_gpio_low(&mut *self.channel.lock().unwrap(), &self.pinname).map_err(|_| ())
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        send_gpio_low(&mut *self.channel.lock().unwrap(), &self.pinname)
     }
 }
@@ -1,39 +1,357 @@
+use bridge_common::encoding::{I2CAddress, MAX_WRITE_LENGTH};
 use embedded_hal::blocking::i2c;
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 
-use crate::io::{send_clear, send_i2c_init, send_i2c_write};
+use crate::io::{
+    send_i2c_init, send_i2c_read, send_i2c_recover, send_i2c_scan, send_i2c_target_init,
+    send_i2c_target_poll, send_i2c_target_set, send_i2c_write, send_i2c_write_read, Error, Link,
+};
+use crate::time::Hertz;
 
 pub struct I2C<T> {
     ident: String,
-    channel: Arc<Mutex<Box<T>>>,
+    frequency: Hertz,
+    channel: Arc<Mutex<Box<Link<T>>>>,
 }
 
 impl<T> I2C<T>
 where
     T: Read + Write,
 {
+    /// Set up the bus `ident` on pins `scl` and `sda` with an SCL frequency of at most `speed`
+    ///
+    /// Fails if the target does not support `speed` on this bus.
     pub fn new(
         ident: String,
         scl: String,
         sda: String,
-        speed: u32,
-        channel: Arc<Mutex<Box<T>>>,
-    ) -> Self {
-        send_clear(&mut *channel.lock().unwrap()).ok();
-        send_i2c_init(&mut *channel.lock().unwrap(), &ident, &scl, &sda, speed).ok();
+        speed: Hertz,
+        channel: Arc<Mutex<Box<Link<T>>>>,
+    ) -> Result<Self, Error> {
+        let hz = send_i2c_init(&mut *channel.lock().unwrap(), &ident, &scl, &sda, speed.0)?;
 
-        I2C { ident, channel }
+        Ok(I2C {
+            ident,
+            frequency: Hertz(hz),
+            channel,
+        })
     }
+
+    /// The SCL frequency the target actually runs the bus at
+    pub fn frequency(&self) -> Hertz {
+        self.frequency
+    }
+
+    /// Find out which addresses on the bus are acknowledged by a device
+    ///
+    /// Reserved addresses are skipped, so only 0x08 to 0x77 are probed.
+    pub fn scan(&mut self) -> Result<Vec<u8>, Error> {
+        send_i2c_scan(&mut *self.channel.lock().unwrap(), &self.ident)
+    }
+
+    /// Free the bus from a device holding SDA low, e.g. after a transfer was aborted
+    ///
+    /// The target clocks SCL until SDA is released, issues a STOP condition and sets up the
+    /// bus afresh. Fails if SDA is still held low afterwards.
+    pub fn recover_bus(&mut self) -> Result<(), Error> {
+        send_i2c_recover(&mut *self.channel.lock().unwrap(), &self.ident)
+    }
+}
+
+/// Hands out handles to a single bridged I2C bus so several drivers can use it at the same time
+///
+/// Every request sent by a handle is a complete bus transaction and the channel to the target
+/// only executes one request at a time, so transactions of different drivers never interleave.
+pub struct BusManager<T> {
+    ident: String,
+    frequency: Hertz,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> BusManager<T>
+where
+    T: Read + Write,
+{
+    pub fn new(bus: I2C<T>) -> Self {
+        BusManager {
+            ident: bus.ident,
+            frequency: bus.frequency,
+            channel: bus.channel,
+        }
+    }
+
+    /// Get another handle to the bus, e.g. to hand it to a driver which takes ownership of it
+    pub fn acquire(&self) -> I2C<T> {
+        I2C {
+            ident: self.ident.clone(),
+            frequency: self.frequency,
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+/// Number of registers sent to an emulated device in a single request
+///
+/// Like a bus write selecting a register, the request carries the register number next to the
+/// data, so that byte is taken off the bytes a bus write can have.
+pub(crate) const TARGET_CHUNK: usize = MAX_WRITE_LENGTH - 1;
+
+/// Split `data` to be set from `register` on into the chunks sent, each with its first register
+pub(crate) fn target_chunks(register: u8, data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    data.chunks(TARGET_CHUNK)
+        .enumerate()
+        .map(move |(i, chunk)| {
+            let offset = (usize::from(register) + i * TARGET_CHUNK) % 256;
+            (offset as u8, chunk)
+        })
+}
+
+/// A write of the bus controller to an emulated device
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TargetWrite {
+    /// The register selected by the first byte of the write
+    pub register: u8,
+    /// The bytes written to `register` and the following ones
+    pub data: Vec<u8>,
 }
 
-impl<T> i2c::Write for I2C<T>
+/// An I2C bus on which the bridge impersonates a device, e.g. to test a bus controller
+///
+/// The emulated device has a file of 256 registers. The first byte written by the controller
+/// selects a register, further bytes are written to it and the following registers, and reads
+/// return the contents starting at the selected register. The register file is cleared if the
+/// target is reset.
+pub struct I2CTarget<T> {
+    ident: String,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> I2CTarget<T>
+where
+    T: Read + Write,
+{
+    /// Respond to the 7-bit `address` on the bus `ident` connected to pins `scl` and `sda`
+    pub fn new(
+        ident: String,
+        scl: String,
+        sda: String,
+        address: u8,
+        channel: Arc<Mutex<Box<Link<T>>>>,
+    ) -> Result<Self, Error> {
+        send_i2c_target_init(&mut *channel.lock().unwrap(), &ident, &scl, &sda, address)?;
+
+        Ok(I2CTarget { ident, channel })
+    }
+
+    /// Set the registers starting at `register`, wrapping around after the last one
+    pub fn set_registers(&mut self, register: u8, data: &[u8]) -> Result<(), Error> {
+        let link = &mut *self.channel.lock().unwrap();
+
+        for (offset, chunk) in target_chunks(register, data) {
+            send_i2c_target_set(link, &self.ident, offset, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the writes of the controller since the last call
+    ///
+    /// The target records only as many writes as fit into a single reply, so this should be
+    /// called regularly. Further bytes are still written to the registers.
+    pub fn writes(&mut self) -> Result<Vec<TargetWrite>, Error> {
+        send_i2c_target_poll(&mut *self.channel.lock().unwrap(), &self.ident)
+    }
+}
+
+/// Both 7-bit and 10-bit addresses are supported, the latter given as `u16`
+impl<T, A> i2c::Write<A> for I2C<T>
 where
     T: Read + Write,
+    A: i2c::AddressMode + Into<I2CAddress>,
 {
-    type Error = std::io::Error;
+    type Error = Error;
+
+    fn write(&mut self, addr: A, bytes: &[u8]) -> Result<(), Self::Error> {
+        send_i2c_write(
+            &mut *self.channel.lock().unwrap(),
+            &self.ident,
+            addr.into(),
+            bytes,
+        )
+    }
+}
+
+impl<T, A> i2c::Read<A> for I2C<T>
+where
+    T: Read + Write,
+    A: i2c::AddressMode + Into<I2CAddress>,
+{
+    type Error = Error;
+
+    fn read(&mut self, addr: A, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        send_i2c_read(
+            &mut *self.channel.lock().unwrap(),
+            &self.ident,
+            addr.into(),
+            buffer,
+        )
+    }
+}
+
+impl<T, A> i2c::WriteRead<A> for I2C<T>
+where
+    T: Read + Write,
+    A: i2c::AddressMode + Into<I2CAddress>,
+{
+    type Error = Error;
+
+    fn write_read(&mut self, addr: A, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        send_i2c_write_read(
+            &mut *self.channel.lock().unwrap(),
+            &self.ident,
+            addr.into(),
+            bytes,
+            buffer,
+        )
+    }
+}
+
+/// Split a transaction into the data of its leading writes and an optional final read
+///
+/// The bridge can only execute a write, a read or a write followed by a read in one go, so
+/// adjacent writes are merged and all other sequences of operations are rejected.
+#[cfg(feature = "embedded-hal-1")]
+pub(crate) fn split_transaction<'a>(
+    operations: &'a mut [embedded_hal_1::i2c::Operation<'_>],
+) -> Result<(Vec<u8>, Option<&'a mut [u8]>), Error> {
+    use embedded_hal_1::i2c::Operation;
+
+    let mut data = Vec::new();
+    let mut operations = operations.iter_mut();
+
+    for operation in &mut operations {
+        match operation {
+            Operation::Write(bytes) => data.extend_from_slice(bytes),
+            Operation::Read(buffer) => {
+                return match operations.next() {
+                    None => Ok((data, Some(&mut **buffer))),
+                    Some(_) => Err(Error::NotImplemented),
+                }
+            }
+        }
+    }
+
+    Ok((data, None))
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<T> embedded_hal_1::i2c::ErrorType for I2C<T> {
+    type Error = Error;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<T, A> embedded_hal_1::i2c::I2c<A> for I2C<T>
+where
+    T: Read + Write,
+    A: embedded_hal_1::i2c::AddressMode + Into<I2CAddress>,
+{
+    fn transaction(
+        &mut self,
+        address: A,
+        operations: &mut [embedded_hal_1::i2c::Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let link = &mut *self.channel.lock().unwrap();
+        let address = address.into();
+
+        match split_transaction(operations)? {
+            (data, None) => send_i2c_write(link, &self.ident, address, &data),
+            (data, Some(buffer)) if data.is_empty() => {
+                send_i2c_read(link, &self.ident, address, buffer)
+            }
+            (data, Some(buffer)) => send_i2c_write_read(link, &self.ident, address, &data, buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{target_chunks, TARGET_CHUNK};
+    use crate::io::encode;
+    use bridge_common::encoding::i2c_target_set;
+    #[cfg(feature = "embedded-hal-1")]
+    use {super::split_transaction, crate::io::Error, embedded_hal_1::i2c::Operation};
+
+    #[test]
+    fn target_chunks_fit_into_a_request() {
+        let data: Vec<u8> = (0..=255).collect();
+        let chunks: Vec<_> = target_chunks(0xf0, &data).collect();
+
+        assert_eq!(chunks.len(), 6);
+        for (register, chunk) in &chunks {
+            assert!(chunk.len() <= TARGET_CHUNK);
+            assert!(encode(&i2c_target_set("i2c1", *register, chunk)).is_ok());
+        }
+        // The registers wrap around after the last one
+        assert_eq!(chunks[0].0, 0xf0);
+        assert_eq!(chunks[1].0, (0xf0 + TARGET_CHUNK - 256) as u8);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|(_, chunk)| *chunk)
+                .collect::<Vec<_>>()
+                .concat(),
+            data
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "embedded-hal-1")]
+    fn split_transaction_merges_writes() {
+        let mut operations = [
+            Operation::Write(&[1, 2]),
+            Operation::Write(&[]),
+            Operation::Write(&[3]),
+        ];
+        let (data, read) = split_transaction(&mut operations).unwrap();
+
+        assert_eq!(data, [1, 2, 3]);
+        assert!(read.is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "embedded-hal-1")]
+    fn split_transaction_ends_with_read() {
+        let mut buffer = [0; 4];
+        let mut operations = [Operation::Write(&[0x10]), Operation::Read(&mut buffer)];
+        let (data, read) = split_transaction(&mut operations).unwrap();
+
+        assert_eq!(data, [0x10]);
+        assert_eq!(read.map(|buffer| buffer.len()), Some(4));
+
+        let mut buffer = [0; 2];
+        let mut operations = [Operation::Read(&mut buffer)];
+        let (data, read) = split_transaction(&mut operations).unwrap();
+
+        assert!(data.is_empty());
+        assert_eq!(read.map(|buffer| buffer.len()), Some(2));
+    }
+
+    #[test]
+    #[cfg(feature = "embedded-hal-1")]
+    fn split_transaction_rejects_operations_after_read() {
+        let (mut first, mut second) = ([0; 1], [0; 1]);
+        let mut operations = [Operation::Read(&mut first), Operation::Read(&mut second)];
+        assert!(matches!(
+            split_transaction(&mut operations),
+            Err(Error::NotImplemented)
+        ));
 
-    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Self::Error> {
-        send_i2c_write(&mut *self.channel.lock().unwrap(), &self.ident, addr, bytes)
+        let mut buffer = [0; 1];
+        let mut operations = [Operation::Read(&mut buffer), Operation::Write(&[1])];
+        assert!(matches!(
+            split_transaction(&mut operations),
+            Err(Error::NotImplemented)
+        ));
     }
 }
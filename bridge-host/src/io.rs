@@ -1,275 +1,1450 @@
 use bridge_common::encoding::{
-    clear, gpio_init_pp, gpio_sethigh, gpio_setlow, gpio_toggle, i2c_init, i2c_write, reset,
-    spi_init, spi_write, version, Reply, Request,
+    batch, capture_read, capture_start, clear, delay, encoder_init, encoder_read, encoder_reset,
+    gpio_init_pp, gpio_sethigh, gpio_setlow, gpio_toggle, gpio_waveform, i2c_init, i2c_read,
+    i2c_recover, i2c_scan, i2c_target_init, i2c_target_poll, i2c_target_set, i2c_write,
+    i2c_write_more, i2c_write_read, measure, onewire_bit, onewire_read, onewire_reset,
+    onewire_search, onewire_write, reset, spi_device_init, spi_init, spi_target_init,
+    spi_target_load, spi_target_poll, spi_transaction, spi_transfer, spi_write, version,
+    I2CAddress, Reply, Request, I2C_SCAN_ADDRESSES, MAX_READ_LENGTH, MAX_WRITE_LENGTH, VERSION,
 };
 use heapless::{consts::*, Vec};
-use postcard::{from_bytes, to_vec};
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use postcard::flavors::SerFlavor;
+use postcard::{from_bytes, serialize_with_flavor, to_vec};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::batch::Batch;
+use crate::encoder::{Counter, Direction};
+use crate::i2c::TargetWrite;
+use crate::measure::Measurement;
+use crate::protocol::{is_complete, Outcome, Protocol, Resync, Step};
+use crate::time::Hertz;
 
 type BufferLength = U64;
 
-fn setup_receive_buffer() -> Vec<u8, BufferLength> {
-    let mut buf: Vec<u8, BufferLength> = Vec::new();
-    buf.resize_default(buf.capacity()).ok();
-    buf
+/// An encoded request as sent to the target
+pub(crate) type Frame = Vec<u8, BufferLength>;
+
+/// Time to wait for a complete reply if not configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// How often an idempotent request is resent if not configured otherwise
+pub const DEFAULT_RETRIES: u8 = 2;
+
+/// Time to keep trying to reopen a vanished port if not configured otherwise
+pub const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_millis(10000);
+
+/// Pause before reading again from a port which had no data
+const IDLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Pause between attempts to reopen a vanished port
+pub(crate) const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the port failed
+    Io(io::Error),
+    /// No complete reply arrived before the deadline
+    Timeout,
+    /// The request could not be encoded
+    Encode(postcard::Error),
+    /// The received bytes do not form a valid reply
+    Decode(postcard::Error),
+    /// The target could not receive the request, e.g. due to a full buffer
+    ReceiveErr { bytes: u8 },
+    /// The target does not support the request or its parameters
+    NotImplemented,
+    /// The target failed to execute the request
+    Target(String),
+    /// The target answered with a reply not fitting the request
+    UnexpectedReply,
+    /// The target speaks a different protocol version
+    VersionMismatch { version: u8 },
+    /// The target was reset and has not executed the request
+    Rebooted,
+    /// The addressed I2C device did not acknowledge
+    Nack,
+    /// More bytes were requested than the target can transfer at once
+    TooLarge { len: usize, limit: usize },
+    /// The packet error code received from an SMBus device does not match the data
+    Pec,
+    /// An SMBus block is longer than allowed
+    BlockTooLong { len: usize, limit: usize },
+    /// The CRC received from a 1-Wire device does not match the data
+    Crc,
+    /// The results of an operation were requested before it was started
+    NotStarted,
 }
 
-pub fn send_version<T: Read + Write>(port: &mut T) -> Result<u8> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&version()).unwrap();
+pub type Result<T> = std::result::Result<T, Error>;
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Timeout => write!(f, "Timed out waiting for reply"),
+            Error::Encode(err) => write!(f, "Could not encode request: {}", err),
+            Error::Decode(err) => write!(f, "Could not decode reply: {}", err),
+            Error::ReceiveErr { bytes } => {
+                write!(f, "Target failed to receive request after {} bytes", bytes)
+            }
+            Error::NotImplemented => write!(f, "Request not implemented by target"),
+            Error::Target(err) => write!(f, "Target error: {}", err),
+            Error::UnexpectedReply => write!(f, "Unexpected reply"),
+            Error::VersionMismatch { version } => write!(
+                f,
+                "Protocol versions locally and on the target differ, there: {}, here: {}",
+                version, VERSION
+            ),
+            Error::Rebooted => write!(f, "Target was reset"),
+            Error::Nack => write!(f, "Device did not acknowledge"),
+            Error::TooLarge { len, limit } => write!(
+                f,
+                "Cannot transfer {} bytes at once, the limit is {}",
+                len, limit
+            ),
+            Error::Pec => write!(f, "Packet error code mismatch"),
+            Error::BlockTooLong { len, limit } => write!(
+                f,
+                "SMBus block of {} bytes exceeds the limit of {}",
+                len, limit
+            ),
+            Error::Crc => write!(f, "CRC mismatch"),
+            Error::NotStarted => write!(f, "Operation not started"),
+        }
+    }
+}
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf);
-    let res = from_bytes::<Reply>(&buf);
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
 
-    match res.unwrap() {
-        Reply::Version { version } => Ok(version),
-        _ => Err(Error::from(ErrorKind::InvalidData)),
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::Timeout => io::Error::new(ErrorKind::TimedOut, err),
+            Error::Decode(_) | Error::UnexpectedReply => {
+                io::Error::new(ErrorKind::InvalidData, err)
+            }
+            _ => io::Error::other(err),
+        }
     }
 }
 
-pub fn send_clear<T: Read + Write>(port: &mut T) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&clear()).unwrap();
+impl Error {
+    /// Whether the error indicates that host and target are out of step
+    pub(crate) fn is_link_error(&self) -> bool {
+        matches!(
+            self,
+            Error::Timeout | Error::Decode(_) | Error::ReceiveErr { .. } | Error::UnexpectedReply
+        )
+    }
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::digital::Error for Error {
+    fn kind(&self) -> embedded_hal_1::digital::ErrorKind {
+        embedded_hal_1::digital::ErrorKind::Other
+    }
+}
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::i2c::Error for Error {
+    fn kind(&self) -> embedded_hal_1::i2c::ErrorKind {
+        use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource};
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+        match self {
+            Error::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            _ => ErrorKind::Other,
+        }
+    }
+}
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::spi::Error for Error {
+    fn kind(&self) -> embedded_hal_1::spi::ErrorKind {
+        embedded_hal_1::spi::ErrorKind::Other
     }
 }
 
-pub fn send_reset<T: Read + Write>(port: &mut T) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&reset()).unwrap();
+impl<'a> From<Reply<'a>> for Error {
+    /// Turn a reply which does not signal success into the matching error
+    fn from(reply: Reply<'a>) -> Self {
+        match reply {
+            Reply::NotImplemented => Error::NotImplemented,
+            Reply::ReceiveErr { bytes } => Error::ReceiveErr { bytes },
+            Reply::VerboseErr { err } => Error::Target(err.into()),
+            Reply::Err { bytes } => Error::Target(format!("failed after {} bytes", bytes)),
+            Reply::Rebooted => Error::Rebooted,
+            Reply::Nack => Error::Nack,
+            _ => Error::UnexpectedReply,
+        }
+    }
+}
+
+/// A connection to the bridge firmware over a byte oriented port, e.g. a (USB<->)serial port
+///
+/// Replies are accumulated from as many reads as necessary until they can be decoded or the
+/// configured timeout expires, so the read timeout of the port itself should be considerably
+/// shorter than the timeout used here.
+///
+/// If an exchange fails because host and target got out of step, the link is resynchronised
+/// automatically before the error is returned or an idempotent request is resent.
+///
+/// All successful configuration requests are recorded and replayed when the target turns out
+/// to have been reset, so pins and buses set up earlier keep working afterwards.
+pub struct Link<T> {
+    port: T,
+    protocol: Protocol,
+    reopen: Option<Box<dyn FnMut() -> io::Result<T> + Send>>,
+    reconnect_timeout: Duration,
+}
+
+impl<T> Link<T>
+where
+    T: Read + Write,
+{
+    pub fn new(port: T) -> Self {
+        Link {
+            port,
+            protocol: Protocol::new(),
+            reopen: None,
+            reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+        }
+    }
+
+    /// Set the time to wait for a complete reply to a request
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.protocol.timeout = timeout;
+    }
+
+    /// Set how often idempotent requests are resent after a timeout or a garbled reply
+    pub fn set_retries(&mut self, retries: u8) {
+        self.protocol.retries = retries;
+    }
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+    /// Set up reopening the port if it vanishes, e.g. because the USB cable was unplugged
+    ///
+    /// `open` is called repeatedly until it succeeds or the `timeout` expires.
+    pub fn set_reconnect<F>(&mut self, timeout: Duration, open: F)
+    where
+        F: FnMut() -> io::Result<T> + Send + 'static,
+    {
+        self.reconnect_timeout = timeout;
+        self.reopen = Some(Box::new(open));
+        self.protocol.reconnect = true;
+    }
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.port
+    }
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Send a request and hand the decoded reply to `handle`
+    pub fn transfer<R, F>(&mut self, request: &Request, handle: F) -> Result<R>
+    where
+        F: FnMut(Reply) -> Result<R>,
+    {
+        self.transfer_part(request, handle, true)
+    }
+
+    /// Send a request forming part of a longer operation, e.g. a chunk of a long write
+    ///
+    /// Only the `first` part is resent after the target was reset. The following parts rely on
+    /// the state left by the earlier ones which was lost, so the operation fails with
+    /// `Error::Rebooted` instead.
+    pub fn transfer_part<R, F>(&mut self, request: &Request, handle: F, first: bool) -> Result<R>
+    where
+        F: FnMut(Reply) -> Result<R>,
+    {
+        let mut transfer = self.protocol.transfer(request, first)?;
+        self.drive(|protocol, outcome| transfer.step(protocol, outcome), handle)?
+            .ok_or(Error::UnexpectedReply)
+    }
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+    /// Bring host and target back into a known state
+    ///
+    /// Waits for the target to drop any partially received request, flushes stale data from the
+    /// port, clears the target buffers and checks the protocol version. If the target has been
+    /// reset in the meantime, the recorded configuration requests are replayed.
+    pub fn resync(&mut self) -> Result<()> {
+        let mut resync = Resync::new();
+        self.drive(
+            |protocol, outcome| resync.step(protocol, outcome),
+            |_| Ok(()),
+        )
+        .map(|_| ())
+    }
+
+    /// Carry out the steps of a transfer or resynchronisation until it is done
+    ///
+    /// Returns what `handle` made of the reply to the request of a transfer.
+    fn drive<R, S, F>(&mut self, mut step: S, mut handle: F) -> Result<Option<R>>
+    where
+        S: FnMut(&mut Protocol, Outcome) -> Step,
+        F: FnMut(Reply) -> Result<R>,
+    {
+        let mut output = None;
+        let mut outcome = Ok(None);
+
+        loop {
+            outcome = match step(&mut self.protocol, outcome) {
+                Step::Request(req) => self
+                    .exchange(&req)
+                    .and_then(|buf| handle(decode(&buf)?))
+                    .map(|res| output = Some(res))
+                    .map(|()| None),
+                Step::Exchange(req) => self.exchange(&req).map(Some),
+                Step::Sleep(duration) => {
+                    std::thread::sleep(duration);
+                    Ok(None)
+                }
+                Step::Flush => self.flush_input().map(|()| None),
+                Step::Reopen => self.reopen().map(|()| None),
+                Step::Done(res) => return res.map(|()| output),
+            };
+        }
+    }
+
+    /// Reopen the port after it vanished
+    fn reopen(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.reconnect_timeout;
+
+        if let Some(open) = self.reopen.as_mut() {
+            log::warn!("Lost connection to target, trying to reconnect");
+
+            loop {
+                match open() {
+                    Ok(port) => {
+                        self.port = port;
+                        break;
+                    }
+                    Err(err) if Instant::now() < deadline => {
+                        log::debug!("Could not reopen port: {}", err);
+                        std::thread::sleep(RECONNECT_INTERVAL);
+                    }
+                    Err(err) => return Err(Error::Io(err)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop everything received so far until the port runs dry
+    fn flush_input(&mut self) -> Result<()> {
+        let mut chunk = [0; 64];
+
+        loop {
+            match self.port.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(bytes) => log::debug!("Dropping {} stale bytes: {:?}", bytes, &chunk[..bytes]),
+                Err(ref err)
+                    if err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::WouldBlock =>
+                {
+                    return Ok(())
+                }
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(Error::Io(err)),
+            }
+        }
+    }
+
+    fn exchange(&mut self, req: &[u8]) -> Result<std::vec::Vec<u8>> {
+        self.port.write_all(req)?;
+        self.port.flush()?;
+        self.receive()
+    }
+
+    /// Read until the received bytes form a complete reply
+    fn receive(&mut self) -> Result<std::vec::Vec<u8>> {
+        let deadline = Instant::now() + self.protocol.timeout;
+        let mut buf = std::vec::Vec::new();
+        let mut chunk = [0; 64];
+
+        loop {
+            match self.port.read(&mut chunk) {
+                Ok(bytes) if bytes > 0 => {
+                    buf.extend_from_slice(&chunk[..bytes]);
+
+                    if is_complete(&buf)? {
+                        return Ok(buf);
+                    }
+                }
+                // A port without a read timeout of its own returns right away
+                Ok(_) => std::thread::sleep(IDLE_INTERVAL),
+                Err(ref err)
+                    if err.kind() == ErrorKind::TimedOut
+                        || err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(Error::Io(err)),
+            }
+
+            if Instant::now() >= deadline {
+                log::debug!("Timed out with {} bytes received: {:?}", buf.len(), buf);
+                return Err(Error::Timeout);
+            }
+        }
     }
 }
 
-pub fn send_gpio_init_pp<T: Read + Write>(port: &mut T, pin: &str) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&gpio_init_pp(pin)).unwrap();
+pub(crate) fn encode(request: &Request) -> Result<Frame> {
+    to_vec(request).map_err(Error::Encode)
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+/// The number of bytes `request` takes up when encoded, even if it does not fit into a frame
+pub(crate) fn encoded_len(request: &Request) -> usize {
+    serialize_with_flavor(request, Size(0)).unwrap_or_default()
+}
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+/// A flavor counting the encoded bytes instead of storing them
+struct Size(usize);
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+impl SerFlavor for Size {
+    type Output = usize;
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+    fn try_push(&mut self, _: u8) -> std::result::Result<(), ()> {
+        self.0 += 1;
+        Ok(())
+    }
+
+    fn release(self) -> std::result::Result<usize, ()> {
+        Ok(self.0)
     }
 }
 
-pub fn send_gpio_toggle<T: Read + Write>(port: &mut T, pin: &str) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&gpio_toggle(pin)).unwrap();
+pub(crate) fn decode<'a>(buf: &'a [u8]) -> Result<Reply<'a>> {
+    from_bytes::<Reply>(buf).map_err(Error::Decode)
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+/// Pick a session identifier which is unlikely to be used by another host process
+pub(crate) fn new_session_id() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos())
+        .unwrap_or_default();
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+    /* The target starts out with a session identifier of 0 so make sure we never use it */
+    (std::process::id() ^ nanos) | 1
+}
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+pub(crate) fn expect_ok(reply: Reply) -> Result<()> {
+    match reply {
+        Reply::Ok => Ok(()),
+        reply => Err(Error::from(reply)),
+    }
+}
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+/// Extract the clock frequency a bus was set up with
+pub(crate) fn expect_frequency(reply: Reply) -> Result<u32> {
+    match reply {
+        Reply::Frequency { hz } => Ok(hz),
+        reply => Err(Error::from(reply)),
     }
 }
 
-pub fn send_gpio_high<T: Read + Write>(port: &mut T, pin: &str) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&gpio_sethigh(pin)).unwrap();
+/// Accept the reply to any configuration request
+pub(crate) fn expect_configured(reply: Reply) -> Result<()> {
+    match reply {
+        Reply::Frequency { .. } => Ok(()),
+        reply => expect_ok(reply),
+    }
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+/// Copy the bytes of a `Data` reply into `buffer` which has to match in length
+pub(crate) fn expect_data(reply: Reply, buffer: &mut [u8]) -> Result<()> {
+    match reply {
+        Reply::Ok if buffer.is_empty() => Ok(()),
+        Reply::Data { data } if data.len() == buffer.len() => {
+            buffer.copy_from_slice(data);
+            Ok(())
+        }
+        reply => Err(Error::from(reply)),
+    }
+}
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+pub(crate) fn expect_measurement(reply: Reply) -> Result<Measurement> {
+    match reply {
+        Reply::Measurement {
+            clock,
+            period,
+            high,
+        } => Ok(Measurement {
+            clock: Hertz(clock),
+            period,
+            high,
+        }),
+        reply => Err(Error::from(reply)),
+    }
+}
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+pub(crate) fn expect_counter(reply: Reply) -> Result<Counter> {
+    match reply {
+        Reply::Position { count, bits, down } => Ok(Counter {
+            value: count,
+            bits,
+            direction: if down { Direction::Down } else { Direction::Up },
+        }),
+        reply => Err(Error::from(reply)),
+    }
+}
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+/// Tell whether a device answered a 1-Wire reset with a presence pulse
+pub(crate) fn expect_presence(reply: Reply) -> Result<bool> {
+    match reply {
+        Reply::Ok => Ok(true),
+        Reply::Nack => Ok(false),
+        reply => Err(Error::from(reply)),
     }
 }
 
-pub fn send_gpio_low<T: Read + Write>(port: &mut T, pin: &str) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&gpio_setlow(pin)).unwrap();
+pub(crate) fn expect_rom(reply: Reply) -> Result<(u64, u8)> {
+    match reply {
+        Reply::Rom { rom, discrepancy } => Ok((rom, discrepancy)),
+        reply => Err(Error::from(reply)),
+    }
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+/// Extract the bytes of a `Data` reply of any length
+pub(crate) fn expect_bytes(reply: Reply) -> Result<std::vec::Vec<u8>> {
+    match reply {
+        Reply::Data { data } => Ok(data.to_vec()),
+        reply => Err(Error::from(reply)),
+    }
+}
+
+/// Make sure the target can return `len` bytes in a single reply
+pub(crate) fn check_read_length(len: usize) -> Result<u8> {
+    if len <= MAX_READ_LENGTH {
+        Ok(len as u8)
+    } else {
+        Err(Error::TooLarge {
+            len,
+            limit: MAX_READ_LENGTH,
+        })
+    }
+}
+
+/// Make sure `len` bytes can be sent to the target in a single request
+pub(crate) fn check_write_length(len: usize) -> Result<()> {
+    if len <= MAX_WRITE_LENGTH {
+        Ok(())
+    } else {
+        Err(Error::TooLarge {
+            len,
+            limit: MAX_WRITE_LENGTH,
+        })
+    }
+}
+
+/// Turn the bitmap in the reply to an `I2CScan` request into the acknowledging addresses
+pub(crate) fn expect_scan(reply: Reply) -> Result<std::vec::Vec<u8>> {
+    let mut found = [0; 16];
+    expect_data(reply, &mut found)?;
+
+    Ok(I2C_SCAN_ADDRESSES
+        .filter(|addr| found[usize::from(addr / 8)] & 1 << (addr % 8) != 0)
+        .collect())
+}
+
+/// Split the reply to an `I2CTargetPoll` request into the recorded writes
+pub(crate) fn expect_target_writes(reply: Reply) -> Result<std::vec::Vec<TargetWrite>> {
+    let mut records = match reply {
+        Reply::Data { data } => data,
+        reply => return Err(Error::from(reply)),
+    };
+
+    let mut writes = std::vec::Vec::new();
+    while let [register, len, rest @ ..] = records {
+        let len = usize::from(*len);
+        if rest.len() < len {
+            break;
+        }
+
+        writes.push(TargetWrite {
+            register: *register,
+            data: rest[..len].to_vec(),
+        });
+        records = &rest[len..];
+    }
+
+    if records.is_empty() {
+        Ok(writes)
+    } else {
+        Err(Error::UnexpectedReply)
+    }
+}
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+/// Split the reply to an `SPITargetPoll` request into the received frames
+pub(crate) fn expect_frames(reply: Reply) -> Result<std::vec::Vec<std::vec::Vec<u8>>> {
+    let mut records = match reply {
+        Reply::Data { data } => data,
+        reply => return Err(Error::from(reply)),
+    };
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+    let mut frames = std::vec::Vec::new();
+    while let [len, rest @ ..] = records {
+        let len = usize::from(*len);
+        if rest.len() < len {
+            return Err(Error::UnexpectedReply);
+        }
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+        frames.push(rest[..len].to_vec());
+        records = &rest[len..];
     }
+
+    Ok(frames)
+}
+
+pub(crate) fn expect_version(reply: Reply) -> Result<()> {
+    match reply {
+        Reply::Version { version } if version == VERSION => Ok(()),
+        Reply::Version { version } => Err(Error::VersionMismatch { version }),
+        reply => Err(Error::from(reply)),
+    }
+}
+
+/// Check the reply to a `Session` request, returns `false` if the target lost its session
+pub(crate) fn expect_session(reply: Reply, session: u32) -> Result<bool> {
+    match reply {
+        Reply::Session { previous } => Ok(previous == session),
+        reply => Err(Error::from(reply)),
+    }
+}
+
+pub fn send_version<T: Read + Write>(link: &mut Link<T>) -> Result<u8> {
+    link.transfer(&version(), |reply| match reply {
+        Reply::Version { version } => Ok(version),
+        reply => Err(Error::from(reply)),
+    })
+}
+
+pub fn send_clear<T: Read + Write>(link: &mut Link<T>) -> Result<()> {
+    link.transfer(&clear(), expect_ok)
+}
+
+pub fn send_reset<T: Read + Write>(link: &mut Link<T>) -> Result<()> {
+    link.transfer(&reset(), expect_ok)
+}
+
+pub fn send_gpio_init_pp<T: Read + Write>(link: &mut Link<T>, pin: &str) -> Result<()> {
+    link.transfer(&gpio_init_pp(pin), expect_ok)
+}
+
+pub fn send_gpio_toggle<T: Read + Write>(link: &mut Link<T>, pin: &str) -> Result<()> {
+    link.transfer(&gpio_toggle(pin), expect_ok)
+}
+
+pub fn send_gpio_high<T: Read + Write>(link: &mut Link<T>, pin: &str) -> Result<()> {
+    link.transfer(&gpio_sethigh(pin), expect_ok)
+}
+
+pub fn send_gpio_low<T: Read + Write>(link: &mut Link<T>, pin: &str) -> Result<()> {
+    link.transfer(&gpio_setlow(pin), expect_ok)
 }
 
 pub fn send_i2c_init<T: Read + Write>(
-    port: &mut T,
+    link: &mut Link<T>,
     ident: &str,
     scl_pin: &str,
     sda_pin: &str,
     speed: u32,
+) -> Result<u32> {
+    link.transfer(&i2c_init(ident, scl_pin, sda_pin, speed), expect_frequency)
+}
+
+/// Write `data` to the device at `addr` in a single I2C write of any length
+///
+/// The data is sent in chunks of up to `MAX_WRITE_LENGTH` bytes, the target keeps the write
+/// open until the last one arrived.
+pub fn send_i2c_write<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    addr: I2CAddress,
+    data: &[u8],
 ) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&i2c_init(scl_pin, sda_pin, speed)).unwrap();
+    let mut chunks = data.chunks(MAX_WRITE_LENGTH);
+    let last = chunks.next_back().unwrap_or_default();
+    let mut first = true;
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+    // The device has seen the earlier chunks, so only the first one may be resent after a reset
+    for chunk in chunks {
+        link.transfer_part(&i2c_write_more(ident, addr, chunk), expect_ok, first)?;
+        first = false;
+    }
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+    link.transfer_part(&i2c_write(ident, addr, last), expect_ok, first)
+}
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+pub fn send_spi_init<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    sck_pin: &str,
+    miso_pin: &str,
+    mosi_pin: &str,
+    speed: u32,
+) -> Result<u32> {
+    link.transfer(
+        &spi_init(ident, sck_pin, miso_pin, mosi_pin, speed),
+        expect_frequency,
+    )
+}
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+/// Clock out `data` of any length, split into chunks of up to `MAX_WRITE_LENGTH` bytes
+pub fn send_spi_write<T: Read + Write>(link: &mut Link<T>, ident: &str, data: &[u8]) -> Result<()> {
+    // A reset lost the earlier chunks, so only the first one may be resent
+    for (i, chunk) in data.chunks(MAX_WRITE_LENGTH).enumerate() {
+        link.transfer_part(&spi_write(ident, chunk), expect_ok, i == 0)?;
     }
+
+    Ok(())
 }
 
-pub fn send_i2c_write<T: Read + Write>(
-    port: &mut T,
+pub fn send_i2c_read<T: Read + Write>(
+    link: &mut Link<T>,
     ident: &str,
-    addr: u8,
+    addr: I2CAddress,
+    buffer: &mut [u8],
+) -> Result<()> {
+    let len = check_read_length(buffer.len())?;
+    link.transfer(&i2c_read(ident, addr, len), |reply| {
+        expect_data(reply, buffer)
+    })
+}
+
+pub fn send_i2c_write_read<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    addr: I2CAddress,
     data: &[u8],
+    buffer: &mut [u8],
 ) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&i2c_write(ident, addr, data)).unwrap();
+    check_write_length(data.len())?;
+    let len = check_read_length(buffer.len())?;
+    link.transfer(&i2c_write_read(ident, addr, data, len), |reply| {
+        expect_data(reply, buffer)
+    })
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+/// Exchange `words` of any length, split into chunks of up to `MAX_WRITE_LENGTH` bytes
+pub fn send_spi_transfer<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    words: &mut [u8],
+) -> Result<()> {
+    // A reset lost the earlier chunks, so only the first one may be resent
+    for (i, chunk) in words.chunks_mut(MAX_WRITE_LENGTH).enumerate() {
+        let data = chunk.to_vec();
+        link.transfer_part(
+            &spi_transfer(ident, &data),
+            |reply| expect_data(reply, chunk),
+            i == 0,
+        )?;
+    }
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+    Ok(())
+}
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+pub fn send_spi_device_init<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    cs_pin: &str,
+) -> Result<()> {
+    link.transfer(&spi_device_init(ident, cs_pin), expect_ok)
+}
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
-    }
+pub fn send_spi_transaction<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    cs_pin: &str,
+    mode: u8,
+    words: &mut [u8],
+) -> Result<()> {
+    check_write_length(words.len())?;
+    let data = words.to_vec();
+    link.transfer(&spi_transaction(ident, cs_pin, mode, &data), |reply| {
+        expect_data(reply, words)
+    })
 }
 
-pub fn send_spi_init<T: Read + Write>(
-    port: &mut T,
+/// Probe all addresses of the I2C bus `ident` and return the ones a device responded to
+pub fn send_i2c_scan<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+) -> Result<std::vec::Vec<u8>> {
+    link.transfer(&i2c_scan(ident), expect_scan)
+}
+
+pub fn send_i2c_recover<T: Read + Write>(link: &mut Link<T>, ident: &str) -> Result<()> {
+    link.transfer(&i2c_recover(ident), expect_ok)
+}
+
+pub fn send_i2c_target_init<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    scl_pin: &str,
+    sda_pin: &str,
+    address: u8,
+) -> Result<()> {
+    link.transfer(
+        &i2c_target_init(ident, scl_pin, sda_pin, address),
+        expect_ok,
+    )
+}
+
+pub fn send_i2c_target_set<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    register: u8,
+    data: &[u8],
+) -> Result<()> {
+    link.transfer(&i2c_target_set(ident, register, data), expect_ok)
+}
+
+pub fn send_i2c_target_poll<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+) -> Result<std::vec::Vec<TargetWrite>> {
+    link.transfer(&i2c_target_poll(ident), expect_target_writes)
+}
+
+pub fn send_spi_target_init<T: Read + Write>(
+    link: &mut Link<T>,
     ident: &str,
     sck_pin: &str,
     miso_pin: &str,
     mosi_pin: &str,
-    speed: u32,
+    cs_pin: &str,
+    mode: u8,
 ) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&spi_init(sck_pin, miso_pin, mosi_pin, speed)).unwrap();
+    link.transfer(
+        &spi_target_init(ident, sck_pin, miso_pin, mosi_pin, cs_pin, mode),
+        expect_ok,
+    )
+}
+
+pub fn send_spi_target_load<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    data: &[u8],
+) -> Result<()> {
+    check_write_length(data.len())?;
+    link.transfer(&spi_target_load(ident, data), expect_ok)
+}
+
+pub fn send_spi_target_poll<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+) -> Result<std::vec::Vec<std::vec::Vec<u8>>> {
+    link.transfer(&spi_target_poll(ident), expect_frames)
+}
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+/// Execute `operations` on the target
+pub fn send_batch<T: Read + Write>(link: &mut Link<T>, operations: &Batch) -> Result<()> {
+    // A reset lost what the earlier payloads did, so only the first one may be resent
+    for (index, payload) in operations.payloads()?.iter().enumerate() {
+        link.transfer_part(&batch(payload), expect_ok, index == 0)?;
+    }
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+    Ok(())
+}
+
+/// Let the target wait for `us` microseconds, at most `MAX_DELAY_US`
+pub fn send_delay<T: Read + Write>(link: &mut Link<T>, us: u32) -> Result<()> {
+    link.transfer(&delay(us), expect_ok)
+}
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+/// Drive the comma separated `pins` through the encoded `steps`, see `Request::GpioWaveform`
+///
+/// A waveform cannot be split, so `steps` are limited to `MAX_WRITE_LENGTH` bytes.
+pub fn send_gpio_waveform<T: Read + Write>(
+    link: &mut Link<T>,
+    pins: &str,
+    steps: &[u8],
+) -> Result<()> {
+    check_write_length(steps.len())?;
+    link.transfer(&gpio_waveform(pins, steps), expect_ok)
+}
+
+/// Start sampling `pins` in the background, returns the actual sample rate in Hz
+pub fn send_capture_start<T: Read + Write>(
+    link: &mut Link<T>,
+    pins: &str,
+    rate: u32,
+    samples: u16,
+) -> Result<u32> {
+    link.transfer(&capture_start(pins, rate, samples), expect_frequency)
+}
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+/// Fetch the samples taken so far from `offset` on, as many as fit into a reply
+pub fn send_capture_read<T: Read + Write>(
+    link: &mut Link<T>,
+    offset: u16,
+) -> Result<std::vec::Vec<u8>> {
+    link.transfer(&capture_read(offset), expect_bytes)
+}
+
+/// Measure the signal at `pin`, waiting up to `timeout_ms` for a full period
+pub fn send_measure<T: Read + Write>(
+    link: &mut Link<T>,
+    pin: &str,
+    timeout_ms: u16,
+) -> Result<Measurement> {
+    link.transfer(&measure(pin, timeout_ms), expect_measurement)
+}
+
+/// Decode the quadrature encoder at `a_pin` and `b_pin` with the timer `ident`
+pub fn send_encoder_init<T: Read + Write>(
+    link: &mut Link<T>,
+    ident: &str,
+    a_pin: &str,
+    b_pin: &str,
+) -> Result<()> {
+    link.transfer(&encoder_init(ident, a_pin, b_pin), expect_ok)
+}
+
+pub fn send_encoder_read<T: Read + Write>(link: &mut Link<T>, ident: &str) -> Result<Counter> {
+    link.transfer(&encoder_read(ident), expect_counter)
+}
+
+pub fn send_encoder_reset<T: Read + Write>(link: &mut Link<T>, ident: &str) -> Result<()> {
+    link.transfer(&encoder_reset(ident), expect_ok)
+}
+
+/// Send a reset pulse on the 1-Wire bus at `pin`, returns whether any device is present
+pub fn send_onewire_reset<T: Read + Write>(link: &mut Link<T>, pin: &str) -> Result<bool> {
+    link.transfer(&onewire_reset(pin), expect_presence)
+}
+
+/// Write `data` of any length to the 1-Wire bus at `pin`
+pub fn send_onewire_write<T: Read + Write>(
+    link: &mut Link<T>,
+    pin: &str,
+    data: &[u8],
+) -> Result<()> {
+    for chunk in data.chunks(MAX_WRITE_LENGTH) {
+        link.transfer(&onewire_write(pin, chunk), expect_ok)?;
+    }
+
+    Ok(())
+}
+
+/// Fill `buffer` of any length with bytes read from the 1-Wire bus at `pin`
+pub fn send_onewire_read<T: Read + Write>(
+    link: &mut Link<T>,
+    pin: &str,
+    buffer: &mut [u8],
+) -> Result<()> {
+    for chunk in buffer.chunks_mut(MAX_READ_LENGTH) {
+        link.transfer(&onewire_read(pin, chunk.len() as u8), |reply| {
+            expect_data(reply, chunk)
+        })?;
     }
+
+    Ok(())
+}
+
+/// Run a time slot writing `bit` on the 1-Wire bus at `pin`, returns the level read back
+pub fn send_onewire_bit<T: Read + Write>(link: &mut Link<T>, pin: &str, bit: bool) -> Result<bool> {
+    let mut level = [0];
+    link.transfer(&onewire_bit(pin, bit), |reply| {
+        expect_data(reply, &mut level)
+    })?;
+
+    Ok(level[0] != 0)
+}
+
+/// Find the ROM code following `rom` on the 1-Wire bus at `pin`, see `Request::OneWireSearch`
+pub fn send_onewire_search<T: Read + Write>(
+    link: &mut Link<T>,
+    pin: &str,
+    rom: u64,
+    discrepancy: u8,
+) -> Result<(u64, u8)> {
+    link.transfer(&onewire_search(pin, rom, discrepancy), expect_rom)
 }
 
-pub fn send_spi_write<T: Read + Write>(port: &mut T, ident: &str, data: &[u8]) -> Result<()> {
-    let mut buf = setup_receive_buffer();
-    let req: Vec<u8, BufferLength> = to_vec(&spi_write(ident, data)).unwrap();
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        expect_frames, expect_target_writes, send_batch, send_gpio_high, send_gpio_init_pp,
+        send_gpio_toggle, send_gpio_waveform, send_i2c_init, send_i2c_read, send_i2c_write_read,
+        send_spi_write, Error, Link,
+    };
+    use crate::batch::Batch;
+    use crate::gpio::{encode_steps, Step};
+    use crate::i2c::TargetWrite;
+    use bridge_common::encoding::{gpio_toggle, Reply, Request, INTERFRAME_TIMEOUT_MS, VERSION};
+    use bridge_common::framing::RequestBuffer;
+    use heapless::consts::U64;
+    use postcard::{from_bytes, to_vec};
+    use std::collections::VecDeque;
+    use std::convert::TryFrom;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::time::{Duration, Instant};
+
+    /// The results of the reads answering a request
+    pub(crate) type Reads = Vec<io::Result<Vec<u8>>>;
+
+    /// A port answering each complete request with the reads returned by `respond`
+    ///
+    /// Requests are received like the firmware does, so a partial request is dropped after the
+    /// inter-frame timeout. Reads beyond the answers time out like a serial port without data.
+    pub(crate) struct MockPort<F> {
+        respond: F,
+        received: RequestBuffer,
+        last_write: Instant,
+        reads: VecDeque<io::Result<Vec<u8>>>,
+        /// The names of the requests received so far
+        pub(crate) requests: Vec<String>,
+    }
+
+    impl<F> Read for MockPort<F> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(bytes)) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                Some(Err(err)) => Err(err),
+                None => Err(ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    impl<F: FnMut(&Request) -> Reads> Write for MockPort<F> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let idle = self.last_write.elapsed().as_millis();
+            self.received.idle(u32::try_from(idle).unwrap_or(u32::MAX));
+            self.last_write = Instant::now();
+
+            for &byte in buf {
+                if let Err(bytes) = self.received.push(byte) {
+                    self.reads.extend(reply(&Reply::ReceiveErr { bytes }));
+                    continue;
+                }
+
+                match from_bytes::<Request>(self.received.bytes()) {
+                    Ok(request) => {
+                        self.reads.extend((self.respond)(&request));
+
+                        let name = format!("{:?}", request);
+                        let name = name.split([' ', '{']).next().unwrap_or_default();
+                        self.requests.push(name.to_string());
+                    }
+                    Err(postcard::Error::DeserializeUnexpectedEnd) => continue,
+                    Err(_) => {
+                        let err = "some error";
+                        self.reads.extend(reply(&Reply::VerboseErr { err }));
+                    }
+                }
+                self.received.clear();
+            }
 
-    log::debug!(
-        "Will send {} bytes containing {:?}",
-        req.len(),
-        from_bytes::<Request>(&req).unwrap()
-    );
+            Ok(buf.len())
+        }
 
-    port.write_all(&req)?;
-    let bytes = port.read(&mut buf)?;
-    let res = from_bytes::<Reply>(&buf);
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn link<F: FnMut(&Request) -> Reads>(respond: F) -> Link<MockPort<F>> {
+        let mut link = Link::new(MockPort {
+            respond,
+            received: RequestBuffer::new(),
+            last_write: Instant::now(),
+            reads: VecDeque::new(),
+            requests: Vec::new(),
+        });
+        link.set_timeout(Duration::from_millis(20));
+        link
+    }
+
+    pub(crate) fn reply(reply: &Reply) -> Reads {
+        vec![Ok(to_vec::<U64, _>(reply).unwrap().to_vec())]
+    }
+
+    /// Answer like a target which was not reset
+    pub(crate) fn firmware(request: &Request) -> Reads {
+        match request {
+            Request::Version => reply(&Reply::Version { version: VERSION }),
+            Request::Session { id } => reply(&Reply::Session { previous: *id }),
+            _ => reply(&Reply::Ok),
+        }
+    }
+
+    /// Answer `Data` split into reads of `sizes` bytes, with empty reads in between
+    fn data_in_parts(data: &[u8], sizes: &[usize]) -> Reads {
+        let bytes = reply(&Reply::Data { data }).remove(0).unwrap();
+        let mut reads = Vec::new();
+        let mut rest = &bytes[..];
+        for &size in sizes {
+            let (part, tail) = rest.split_at(size.min(rest.len()));
+            reads.push(Ok(part.to_vec()));
+            reads.push(Ok(Vec::new()));
+            rest = tail;
+        }
+        reads.push(Ok(rest.to_vec()));
+        reads
+    }
+
+    fn write(register: u8, data: &[u8]) -> TargetWrite {
+        TargetWrite {
+            register,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn expect_target_writes_splits_records() {
+        let data = [0x10, 2, 0xaa, 0xbb, 0x20, 0, 0x30, 1, 0xcc];
+        let writes = expect_target_writes(Reply::Data { data: &data }).unwrap();
+        assert_eq!(
+            writes,
+            [
+                write(0x10, &[0xaa, 0xbb]),
+                write(0x20, &[]),
+                write(0x30, &[0xcc])
+            ]
+        );
+
+        assert!(expect_target_writes(Reply::Data { data: &[] })
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn expect_target_writes_rejects_truncated_records() {
+        for data in [&[0x10, 3, 0xaa, 0xbb][..], &[0x10, 1, 0xaa, 0x20]] {
+            assert!(matches!(
+                expect_target_writes(Reply::Data { data }),
+                Err(Error::UnexpectedReply)
+            ));
+        }
+
+        assert!(matches!(
+            expect_target_writes(Reply::NotImplemented {}),
+            Err(Error::NotImplemented)
+        ));
+    }
+
+    #[test]
+    fn expect_frames_splits_records() {
+        let data = [2, 0xaa, 0xbb, 1, 0xcc];
+        let frames = expect_frames(Reply::Data { data: &data }).unwrap();
+        assert_eq!(frames, [vec![0xaa, 0xbb], vec![0xcc]]);
+
+        assert!(expect_frames(Reply::Data { data: &[] }).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expect_frames_rejects_truncated_records() {
+        assert!(matches!(
+            expect_frames(Reply::Data {
+                data: &[2, 0xaa, 3, 0xbb]
+            }),
+            Err(Error::UnexpectedReply)
+        ));
+        assert!(matches!(
+            expect_frames(Reply::NotImplemented {}),
+            Err(Error::NotImplemented)
+        ));
+    }
+
+    #[test]
+    fn receive_accumulates_reply_from_several_reads() {
+        let mut link = link(|_: &Request| data_in_parts(&[1, 2, 3, 4, 5], &[1, 3]));
+
+        let mut buffer = [0; 5];
+        send_i2c_read(&mut link, "i2c1", 0x50u8.into(), &mut buffer).unwrap();
+
+        assert_eq!(buffer, [1, 2, 3, 4, 5]);
+        assert_eq!(link.get_mut().requests, ["I2CRead"]);
+    }
+
+    #[test]
+    fn receive_waits_through_empty_and_interrupted_reads() {
+        let mut link = link(|_: &Request| {
+            let mut reads = vec![Ok(Vec::new()), Err(ErrorKind::Interrupted.into())];
+            reads.extend(data_in_parts(&[7, 8], &[2]));
+            reads
+        });
+
+        let mut buffer = [0; 2];
+        send_i2c_read(&mut link, "i2c1", 0x50u8.into(), &mut buffer).unwrap();
+
+        assert_eq!(buffer, [7, 8]);
+    }
+
+    #[test]
+    fn missing_reply_times_out() {
+        let mut link = link(|request: &Request| match request {
+            Request::GpioToggle { .. } => Vec::new(),
+            request => firmware(request),
+        });
+
+        assert!(matches!(
+            send_gpio_toggle(&mut link, "a5"),
+            Err(Error::Timeout)
+        ));
+        // Toggling is not idempotent, so the request is not sent again after resynchronising
+        assert_eq!(
+            link.get_mut().requests,
+            ["GpioToggle", "Clear", "Version", "Session"]
+        );
+    }
+
+    #[test]
+    fn idempotent_request_is_resent_after_timeout() {
+        let mut lost = 1;
+        let mut link = link(move |request: &Request| match request {
+            Request::GpioSetHigh { .. } if lost > 0 => {
+                lost -= 1;
+                Vec::new()
+            }
+            request => firmware(request),
+        });
+
+        send_gpio_high(&mut link, "a5").unwrap();
+
+        assert_eq!(
+            link.get_mut().requests,
+            ["GpioSetHigh", "Clear", "Version", "Session", "GpioSetHigh"]
+        );
+    }
+
+    #[test]
+    fn idempotent_request_is_resent_limited_times() {
+        let mut link = link(|request: &Request| match request {
+            Request::GpioSetHigh { .. } => Vec::new(),
+            request => firmware(request),
+        });
+        link.set_retries(1);
+
+        assert!(matches!(
+            send_gpio_high(&mut link, "a5"),
+            Err(Error::Timeout)
+        ));
+        let requests = &link.get_mut().requests;
+        assert_eq!(requests.iter().filter(|r| *r == "GpioSetHigh").count(), 2);
+    }
+
+    #[test]
+    fn timeout_resyncs_link_for_next_request() {
+        let mut lost = 1;
+        let mut link = link(move |request: &Request| match request {
+            Request::GpioToggle { .. } if lost > 0 => {
+                lost -= 1;
+                Vec::new()
+            }
+            request => firmware(request),
+        });
+
+        assert!(matches!(
+            send_gpio_toggle(&mut link, "a5"),
+            Err(Error::Timeout)
+        ));
+        send_gpio_toggle(&mut link, "a5").unwrap();
+
+        assert_eq!(
+            link.get_mut().requests,
+            ["GpioToggle", "Clear", "Version", "Session", "GpioToggle"]
+        );
+    }
+
+    #[test]
+    fn undecodable_reply_resyncs_link_for_next_request() {
+        let mut garbled = 1;
+        let mut link = link(move |request: &Request| match request {
+            Request::GpioToggle { .. } if garbled > 0 => {
+                garbled -= 1;
+                vec![Ok(vec![0x7f])]
+            }
+            request => firmware(request),
+        });
+
+        assert!(matches!(
+            send_gpio_toggle(&mut link, "a5"),
+            Err(Error::Decode(_))
+        ));
+        send_gpio_toggle(&mut link, "a5").unwrap();
+
+        assert_eq!(
+            link.get_mut().requests,
+            ["GpioToggle", "Clear", "Version", "Session", "GpioToggle"]
+        );
+    }
+
+    #[test]
+    fn resync_waits_for_target_to_drop_partial_request() {
+        let mut link = link(firmware);
+
+        // A request the host stopped sending half way
+        let frame = to_vec::<U64, _>(&gpio_toggle("a5")).unwrap();
+        link.get_mut().write_all(&frame[..frame.len() - 1]).unwrap();
+
+        link.resync().unwrap();
+        send_gpio_toggle(&mut link, "a5").unwrap();
+
+        assert_eq!(
+            link.get_mut().requests,
+            ["Clear", "Version", "Session", "GpioToggle"]
+        );
+    }
+
+    #[test]
+    fn target_drops_partial_request_after_interframe_timeout() {
+        let mut port = link(firmware).into_inner();
+        let frame = to_vec::<U64, _>(&gpio_toggle("a5")).unwrap();
+
+        // Without a pause the rest of the request completes it
+        port.write_all(&frame[..2]).unwrap();
+        port.write_all(&frame[2..]).unwrap();
+        assert_eq!(port.requests, ["GpioToggle"]);
+
+        // After one the rest is taken for the start of the next request
+        port.write_all(&frame[..2]).unwrap();
+        std::thread::sleep(Duration::from_millis(u64::from(INTERFRAME_TIMEOUT_MS)));
+        port.write_all(&frame[2..]).unwrap();
+        assert_eq!(port.requests, ["GpioToggle"]);
+    }
+
+    #[test]
+    fn configuration_is_restored_after_reset() {
+        let (mut resets, mut reset) = (1, false);
+        let mut link = link(move |request: &Request| match request {
+            Request::GpioToggle { .. } if resets > 0 => {
+                resets -= 1;
+                reset = true;
+                reply(&Reply::Rebooted)
+            }
+            Request::Session { .. } if reset => {
+                reset = false;
+                reply(&Reply::Session { previous: 0 })
+            }
+            Request::I2CInit { speed, .. } => reply(&Reply::Frequency { hz: *speed }),
+            request => firmware(request),
+        });
+
+        send_gpio_init_pp(&mut link, "a5").unwrap();
+        send_i2c_init(&mut link, "i2c1", "b6", "b7", 100_000).unwrap();
+        send_gpio_toggle(&mut link, "a5").unwrap();
+
+        // The rejected request is sent again once the target is configured like before
+        assert_eq!(
+            link.get_mut().requests,
+            [
+                "GpioInitPP",
+                "I2CInit",
+                "GpioToggle",
+                "Clear",
+                "Version",
+                "Session",
+                "GpioInitPP",
+                "I2CInit",
+                "GpioToggle"
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_is_not_resent_after_reset_half_way() {
+        let mut batches = 0;
+        let mut link = link(move |request: &Request| match request {
+            Request::Batch { .. } => {
+                batches += 1;
+                match batches {
+                    2 => reply(&Reply::Rebooted),
+                    _ => reply(&Reply::Ok),
+                }
+            }
+            request => firmware(request),
+        });
+
+        let mut batch = Batch::new();
+        batch.spi_write("spi1", &[0; 100]);
+
+        // The target lost what the first payload did, so the rest must not run on its own
+        assert!(matches!(
+            send_batch(&mut link, &batch),
+            Err(Error::Rebooted)
+        ));
+        assert_eq!(
+            link.get_mut().requests,
+            ["Batch", "Batch", "Clear", "Version", "Session"]
+        );
+    }
+
+    #[test]
+    fn waveform_too_long_for_a_request_is_rejected() {
+        let mut link = link(firmware);
+
+        let steps = encode_steps(&[Step { levels: 1, us: 10 }; 17]);
+        assert!(matches!(
+            send_gpio_waveform(&mut link, "a5", &steps),
+            Err(Error::TooLarge { len: 51, .. })
+        ));
+        assert!(link.get_mut().requests.is_empty());
+
+        send_gpio_waveform(&mut link, "a5", &steps[..48]).unwrap();
+        assert_eq!(link.get_mut().requests, ["GpioWaveform"]);
+    }
+
+    #[test]
+    fn long_spi_write_is_not_resent_after_reset_half_way() {
+        let mut writes = 0;
+        let mut link = link(move |request: &Request| match request {
+            Request::SPIWrite { .. } => {
+                writes += 1;
+                match writes {
+                    1 => reply(&Reply::Rebooted),
+                    3 => reply(&Reply::Rebooted),
+                    _ => reply(&Reply::Ok),
+                }
+            }
+            request => firmware(request),
+        });
+
+        // The first chunk may be sent again, the second one continues a write the target forgot
+        assert!(matches!(
+            send_spi_write(&mut link, "spi1", &[0; 60]),
+            Err(Error::Rebooted)
+        ));
+        assert_eq!(
+            link.get_mut().requests,
+            [
+                "SPIWrite", "Clear", "Version", "Session", "SPIWrite", "SPIWrite", "Clear",
+                "Version", "Session"
+            ]
+        );
+    }
 
-    log::debug!("Received {:?} bytes containing {:?}", bytes, res);
+    #[test]
+    fn write_read_too_long_for_a_request_is_rejected() {
+        let mut link = link(firmware);
 
-    match res {
-        Ok(Reply::Ok) => Ok(()),
-        _ => Err(Error::new(ErrorKind::Other, "Hardware error")),
+        let mut buffer = [0; 1];
+        assert!(matches!(
+            send_i2c_write_read(&mut link, "i2c1", 0x50u8.into(), &[0; 49], &mut buffer),
+            Err(Error::TooLarge { len: 49, .. })
+        ));
+        assert!(link.get_mut().requests.is_empty());
     }
 }
@@ -1,5 +1,16 @@
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod batch;
+pub mod capture;
 pub mod common;
+pub mod delay;
+pub mod encoder;
 pub mod gpio;
 pub mod i2c;
 pub mod io;
+pub mod measure;
+pub mod onewire;
+mod protocol;
+pub mod smbus;
 pub mod spi;
+pub mod time;
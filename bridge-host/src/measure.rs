@@ -0,0 +1,81 @@
+use bridge_common::encoding::MAX_MEASURE_TIMEOUT_MS;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::io::{send_measure, Error, Link};
+use crate::time::Hertz;
+
+/// A period of a signal measured by the target, in ticks of its timer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Measurement {
+    /// The rate at which the timer counted
+    pub clock: Hertz,
+    /// Ticks from one rising edge to the next
+    pub period: u32,
+    /// Ticks from the rising edge to the falling edge
+    pub high: u32,
+}
+
+impl Measurement {
+    pub fn frequency(&self) -> f64 {
+        f64::from(self.clock.0) / f64::from(self.period.max(1))
+    }
+
+    pub fn period(&self) -> Duration {
+        self.ticks(self.period)
+    }
+
+    /// How long the signal stays high in each period
+    pub fn high_time(&self) -> Duration {
+        self.ticks(self.high)
+    }
+
+    /// The share of each period the signal stays high, between 0 and 1
+    pub fn duty_cycle(&self) -> f64 {
+        f64::from(self.high) / f64::from(self.period.max(1))
+    }
+
+    fn ticks(&self, ticks: u32) -> Duration {
+        Duration::from_nanos(u64::from(ticks) * 1_000_000_000 / u64::from(self.clock.0.max(1)))
+    }
+}
+
+/// Longest wait for a signal to complete a period
+pub const MAX_TIMEOUT: Duration = Duration::from_millis(MAX_MEASURE_TIMEOUT_MS as u64);
+
+/// Frequency, period and duty cycle measurements of the signal at a pin
+///
+/// The target measures with a timer in input capture mode, so only the pins routed to the first
+/// two channels of TIM2 (a0, a1, a5, b3) or TIM3 (a6, a7, b4, b5) are supported. Signals from
+/// a few Hz to several MHz can be measured, with a resolution of a tick of the 48 MHz timer
+/// clock on TIM2 and on TIM3 for signals faster than about 730 Hz.
+pub struct FrequencyCounter<T> {
+    pin: String,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> FrequencyCounter<T>
+where
+    T: Read + Write,
+{
+    pub fn new(pin: String, channel: Arc<Mutex<Box<Link<T>>>>) -> Self {
+        FrequencyCounter { pin, channel }
+    }
+
+    /// Measure a single period of the signal, waiting `timeout` for it at most
+    ///
+    /// Timeouts longer than `MAX_TIMEOUT` are shortened to it. Fails if no full period is seen
+    /// in time, e.g. if the signal is constant.
+    pub fn measure(&mut self, timeout: Duration) -> Result<Measurement, Error> {
+        send_measure(
+            &mut *self.channel.lock().unwrap(),
+            &self.pin,
+            timeout_ms(timeout),
+        )
+    }
+}
+
+pub(crate) fn timeout_ms(timeout: Duration) -> u16 {
+    timeout.min(MAX_TIMEOUT).as_millis() as u16
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::io::{
+    send_onewire_bit, send_onewire_read, send_onewire_reset, send_onewire_search,
+    send_onewire_write, Error, Link,
+};
+
+/// Command addressing the single device with the ROM code following it
+pub const MATCH_ROM: u8 = 0x55;
+/// Command addressing all devices on the bus at once
+pub const SKIP_ROM: u8 = 0xcc;
+
+/// The unique ROM code of a 1-Wire device
+///
+/// Made up of the family code in the least significant byte, a 48 bit serial number and a CRC
+/// in the most significant byte, which is the order the bytes are sent in on the bus.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Rom(pub u64);
+
+impl Rom {
+    /// The family code identifying the kind of device, e.g. 0x28 for a DS18B20
+    pub fn family(&self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Whether the CRC matches the family code and serial number
+    pub fn is_valid(&self) -> bool {
+        let bytes = self.0.to_le_bytes();
+        crc8(&bytes[..7]) == bytes[7]
+    }
+}
+
+/// Formatted like the ROM codes in the Linux w1 subsystem, e.g. "28-0316a2798f3c"
+impl fmt::Display for Rom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}-{:012x}",
+            self.family(),
+            (self.0 >> 8) & 0xffff_ffff_ffff
+        )
+    }
+}
+
+/// The CRC used by 1-Wire devices for ROM codes and data, 0 over data including its CRC
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8c
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// A 1-Wire bus driven by the target at a single pin
+///
+/// The target bit-bangs the bus at standard speed, it needs an external pull up resistor of
+/// about 4.7 kOhm. Devices have to be powered externally, parasitic power is not supported.
+pub struct OneWire<T> {
+    pin: String,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> OneWire<T>
+where
+    T: Read + Write,
+{
+    pub fn new(pin: String, channel: Arc<Mutex<Box<Link<T>>>>) -> Self {
+        OneWire { pin, channel }
+    }
+
+    /// Send a reset pulse starting a transaction, returns whether any device is present
+    pub fn reset(&mut self) -> Result<bool, Error> {
+        send_onewire_reset(&mut *self.channel.lock().unwrap(), &self.pin)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        send_onewire_write(&mut *self.channel.lock().unwrap(), &self.pin, data)
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        send_onewire_read(&mut *self.channel.lock().unwrap(), &self.pin, buffer)
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        send_onewire_bit(&mut *self.channel.lock().unwrap(), &self.pin, bit).map(|_| ())
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, Error> {
+        send_onewire_bit(&mut *self.channel.lock().unwrap(), &self.pin, true)
+    }
+
+    /// Start a transaction with the device `rom` or with all devices if `None`
+    ///
+    /// Fails with `Error::Nack` if no device is present.
+    pub fn select(&mut self, rom: Option<Rom>) -> Result<(), Error> {
+        select(&mut self.channel.lock().unwrap(), &self.pin, rom)
+    }
+
+    /// Find the ROM codes of all devices on the bus
+    ///
+    /// Each device found takes a request.
+    pub fn search(&mut self) -> Result<Vec<Rom>, Error> {
+        let mut link = self.channel.lock().unwrap();
+        if !send_onewire_reset(&mut link, &self.pin)? {
+            return Ok(Vec::new());
+        }
+
+        let mut roms = Vec::new();
+        let (mut rom, mut discrepancy) = (0, 0);
+        loop {
+            let (next, branch) = send_onewire_search(&mut link, &self.pin, rom, discrepancy)?;
+            rom = next;
+            discrepancy = branch;
+
+            if !Rom(rom).is_valid() {
+                return Err(Error::Crc);
+            }
+            roms.push(Rom(rom));
+
+            if discrepancy == 0 {
+                return Ok(roms);
+            }
+        }
+    }
+}
+
+/// Select the device `rom` or all devices on the bus at `pin`, see `OneWire::select`
+///
+/// Callers sending more after the selection keep `link` locked until they are done, so no
+/// other user of the link can start a transaction on the bus in between.
+fn select<T: Read + Write>(link: &mut Link<T>, pin: &str, rom: Option<Rom>) -> Result<(), Error> {
+    if !send_onewire_reset(link, pin)? {
+        return Err(Error::Nack);
+    }
+
+    match rom {
+        Some(rom) => {
+            let mut command = vec![MATCH_ROM];
+            command.extend_from_slice(&rom.0.to_le_bytes());
+            send_onewire_write(link, pin, &command)
+        }
+        None => send_onewire_write(link, pin, &[SKIP_ROM]),
+    }
+}
+
+/// Commands of the DS18B20 and compatible temperature sensors
+pub(crate) const CONVERT_T: u8 = 0x44;
+pub(crate) const READ_SCRATCHPAD: u8 = 0xbe;
+
+/// A DS18B20 temperature sensor on a 1-Wire bus
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ds18b20 {
+    pub rom: Rom,
+}
+
+impl Ds18b20 {
+    pub const FAMILY: u8 = 0x28;
+
+    /// Time a conversion takes at the default resolution of 12 bits
+    pub const CONVERSION_TIME: Duration = Duration::from_millis(750);
+
+    /// Find all sensors on `bus`
+    pub fn find<T: Read + Write>(bus: &mut OneWire<T>) -> Result<Vec<Ds18b20>, Error> {
+        Ok(bus
+            .search()?
+            .into_iter()
+            .filter(|rom| rom.family() == Self::FAMILY)
+            .map(|rom| Ds18b20 { rom })
+            .collect())
+    }
+
+    /// Start a conversion on all sensors on `bus` at once
+    pub fn convert_all<T: Read + Write>(bus: &mut OneWire<T>) -> Result<(), Error> {
+        let mut link = bus.channel.lock().unwrap();
+        select(&mut link, &bus.pin, None)?;
+        send_onewire_write(&mut link, &bus.pin, &[CONVERT_T])
+    }
+
+    /// Start a conversion, the result can be read after `CONVERSION_TIME`
+    pub fn convert<T: Read + Write>(&self, bus: &mut OneWire<T>) -> Result<(), Error> {
+        let mut link = bus.channel.lock().unwrap();
+        select(&mut link, &bus.pin, Some(self.rom))?;
+        send_onewire_write(&mut link, &bus.pin, &[CONVERT_T])
+    }
+
+    /// The temperature in °C measured by the last conversion
+    pub fn read_temperature<T: Read + Write>(&self, bus: &mut OneWire<T>) -> Result<f32, Error> {
+        let mut scratchpad = [0; 9];
+        let mut link = bus.channel.lock().unwrap();
+        select(&mut link, &bus.pin, Some(self.rom))?;
+        send_onewire_write(&mut link, &bus.pin, &[READ_SCRATCHPAD])?;
+        send_onewire_read(&mut link, &bus.pin, &mut scratchpad)?;
+
+        decode_temperature(&scratchpad)
+    }
+
+    /// Run a conversion and return the temperature in °C
+    pub fn temperature<T: Read + Write>(&self, bus: &mut OneWire<T>) -> Result<f32, Error> {
+        self.convert(bus)?;
+        thread::sleep(Self::CONVERSION_TIME);
+        self.read_temperature(bus)
+    }
+}
+
+/// Extract the temperature in °C from the scratchpad of a DS18B20
+pub(crate) fn decode_temperature(scratchpad: &[u8; 9]) -> Result<f32, Error> {
+    // A bus held low reads as all zeros, which passes the CRC
+    if crc8(scratchpad) != 0 || scratchpad.iter().all(|&byte| byte == 0) {
+        return Err(Error::Crc);
+    }
+
+    Ok(f32::from(i16::from_le_bytes([scratchpad[0], scratchpad[1]])) / 16.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc8, decode_temperature, Rom};
+    use crate::io::Error;
+
+    /// The example ROM code from Maxim application note 27
+    const ROM: Rom = Rom(0xa200_0000_01b8_1c02);
+
+    #[test]
+    fn crc8_matches_example_rom() {
+        assert_eq!(crc8(&[0x02, 0x1c, 0xb8, 0x01, 0x00, 0x00, 0x00]), 0xa2);
+        assert_eq!(crc8(&ROM.0.to_le_bytes()), 0);
+    }
+
+    #[test]
+    fn rom_checks_crc() {
+        assert!(ROM.is_valid());
+        assert!(!Rom(ROM.0 ^ 1 << 20).is_valid());
+        assert!(!Rom(ROM.0 ^ 1 << 60).is_valid());
+
+        assert_eq!(ROM.family(), 0x02);
+        assert_eq!(ROM.to_string(), "02-00000001b81c");
+    }
+
+    #[test]
+    fn decode_temperature_of_scratchpad() {
+        // The power-on value of 85 °C
+        let scratchpad = [0x50, 0x05, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10, 0x1c];
+        assert_eq!(decode_temperature(&scratchpad).unwrap(), 85.0);
+
+        let scratchpad = [0x5e, 0xff, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10, 0x6a];
+        assert_eq!(decode_temperature(&scratchpad).unwrap(), -10.125);
+
+        let scratchpad = [0x91, 0x01, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10, 0x70];
+        assert_eq!(decode_temperature(&scratchpad).unwrap(), 25.0625);
+    }
+
+    #[test]
+    fn decode_temperature_rejects_corrupt_scratchpad() {
+        let scratchpad = [0x91, 0x01, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10, 0x71];
+        assert!(matches!(decode_temperature(&scratchpad), Err(Error::Crc)));
+        assert!(matches!(decode_temperature(&[0; 9]), Err(Error::Crc)));
+    }
+}
@@ -0,0 +1,325 @@
+//! The part of a link independent of how bytes are moved, shared by the blocking and the
+//! asynchronous `Link`
+//!
+//! A `Transfer` or `Resync` is advanced one `Step` at a time: the link carries out the step on
+//! its port and reports the outcome back, until the machine is `Done`. All decisions about
+//! retries, resynchronisation and replaying the configuration after a reset are made here.
+
+use bridge_common::encoding::{clear, session, version, Request, INTERFRAME_TIMEOUT_MS};
+use postcard::from_bytes;
+use std::time::Duration;
+
+use crate::io::{
+    decode, encode, expect_configured, expect_ok, expect_session, expect_version, new_session_id,
+    Error, Frame, Result, DEFAULT_RETRIES, DEFAULT_TIMEOUT,
+};
+
+/// The outcome of a step as reported by the link, with the reply received by an `Exchange`
+pub(crate) type Outcome = Result<Option<Vec<u8>>>;
+
+/// What the link has to do next
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// Send the frame and hand the reply to the handler of the transfer, report `Ok(None)` if
+    /// the handler accepted it
+    Request(Frame),
+    /// Send the frame and report the complete reply
+    Exchange(Frame),
+    /// Wait for the given time, e.g. for the target to drop a partially received request
+    Sleep(Duration),
+    /// Drop everything received so far until the port runs dry
+    Flush,
+    /// Reopen the port after it vanished
+    Reopen,
+    /// The transfer or resynchronisation is complete
+    Done(Result<()>),
+}
+
+/// The state of a link kept across transfers
+pub(crate) struct Protocol {
+    pub(crate) timeout: Duration,
+    pub(crate) retries: u8,
+    /// Whether the link can reopen its port
+    pub(crate) reconnect: bool,
+    session: u32,
+    setup: Vec<Frame>,
+    /// Set while an exchange or a resynchronisation is under way
+    ///
+    /// A link still dirty when starting a transfer had one abandoned half way, e.g. because the
+    /// future driving it was dropped, so a request may have been sent partially or its reply
+    /// may still arrive.
+    dirty: bool,
+    /// Set while the configuration of a reset target has not been replayed completely
+    restoring: bool,
+}
+
+impl Protocol {
+    pub(crate) fn new() -> Self {
+        Protocol {
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            reconnect: false,
+            session: new_session_id(),
+            setup: Vec::new(),
+            dirty: false,
+            restoring: false,
+        }
+    }
+
+    /// Start sending `request`, see `io::Link::transfer_part`
+    pub(crate) fn transfer(&self, request: &Request, first: bool) -> Result<Transfer> {
+        let frame = encode(request)?;
+        log::debug!("Will send {} bytes containing {:?}", frame.len(), request);
+
+        let state = if self.dirty {
+            log::warn!("Previous exchange was abandoned");
+            TransferState::Prepare(Resync::new())
+        } else {
+            TransferState::Send
+        };
+
+        Ok(Transfer {
+            frame,
+            idempotent: request.is_idempotent(),
+            configuration: request.is_configuration(),
+            first,
+            attempt: 1,
+            state,
+        })
+    }
+}
+
+/// Sending a request, resending it or giving up after a failure
+pub(crate) struct Transfer {
+    frame: Frame,
+    idempotent: bool,
+    configuration: bool,
+    first: bool,
+    attempt: usize,
+    state: TransferState,
+}
+
+enum TransferState {
+    /// Resynchronising before the request is sent the first time
+    Prepare(Resync),
+    Send,
+    /// Waiting for the outcome of the `Request` step
+    Receive,
+    /// Waiting for the port to be reopened after the error
+    Reopen(Error),
+    /// Resynchronising after the error
+    Recover(Error, Resync),
+    Done,
+}
+
+impl Transfer {
+    pub(crate) fn step(&mut self, protocol: &mut Protocol, outcome: Outcome) -> Step {
+        match std::mem::replace(&mut self.state, TransferState::Done) {
+            TransferState::Prepare(mut resync) => match resync.step(protocol, outcome) {
+                Step::Done(Ok(())) => self.send(protocol),
+                Step::Done(Err(err)) => Step::Done(Err(err)),
+                step => {
+                    self.state = TransferState::Prepare(resync);
+                    step
+                }
+            },
+            TransferState::Send => self.send(protocol),
+            TransferState::Receive => match outcome {
+                Ok(_) => {
+                    protocol.dirty = false;
+                    if self.configuration && !protocol.setup.contains(&self.frame) {
+                        protocol.setup.push(self.frame.clone());
+                    }
+                    Step::Done(Ok(()))
+                }
+                Err(err) => self.failed(protocol, err),
+            },
+            TransferState::Reopen(err) => match outcome {
+                Ok(_) => self.recover(protocol, err),
+                Err(reopen_err) => {
+                    log::warn!("Could not recover link: {}", reopen_err);
+                    Step::Done(Err(err))
+                }
+            },
+            TransferState::Recover(err, mut resync) => match resync.step(protocol, outcome) {
+                Step::Done(Ok(())) => {
+                    /* A rejected request has not been executed so it's safe to send it again */
+                    let resend = self.idempotent || (self.first && matches!(err, Error::Rebooted));
+                    if resend && self.attempt < self.attempts(protocol) {
+                        self.attempt += 1;
+                        self.send(protocol)
+                    } else {
+                        Step::Done(Err(err))
+                    }
+                }
+                Step::Done(Err(resync_err)) => {
+                    log::warn!("Could not recover link: {}", resync_err);
+                    Step::Done(Err(err))
+                }
+                step => {
+                    self.state = TransferState::Recover(err, resync);
+                    step
+                }
+            },
+            TransferState::Done => Step::Done(Err(Error::UnexpectedReply)),
+        }
+    }
+
+    fn attempts(&self, protocol: &Protocol) -> usize {
+        usize::from(protocol.retries) + 1
+    }
+
+    fn send(&mut self, protocol: &mut Protocol) -> Step {
+        protocol.dirty = true;
+        self.state = TransferState::Receive;
+        Step::Request(self.frame.clone())
+    }
+
+    /// Decide how to go on after the request failed with `err`
+    fn failed(&mut self, protocol: &mut Protocol, err: Error) -> Step {
+        match err {
+            Error::Io(_) if protocol.reconnect => {}
+            Error::Rebooted => {}
+            ref err if err.is_link_error() => {}
+            // The port failed, it's unknown how much of the exchange took place
+            Error::Io(_) => return Step::Done(Err(err)),
+            // A complete reply arrived, it just does not signal success
+            err => {
+                protocol.dirty = false;
+                return Step::Done(Err(err));
+            }
+        }
+
+        log::warn!(
+            "Attempt {} of {} failed: {}",
+            self.attempt,
+            self.attempts(protocol),
+            err
+        );
+
+        if let Error::Io(_) = err {
+            self.state = TransferState::Reopen(err);
+            Step::Reopen
+        } else {
+            self.recover(protocol, err)
+        }
+    }
+
+    fn recover(&mut self, protocol: &mut Protocol, err: Error) -> Step {
+        let mut resync = Resync::new();
+        let step = resync.step(protocol, Ok(None));
+        self.state = TransferState::Recover(err, resync);
+        step
+    }
+}
+
+/// Bringing host and target back into a known state, see `io::Link::resync`
+pub(crate) struct Resync {
+    state: ResyncState,
+}
+
+#[derive(Clone, Copy)]
+enum ResyncState {
+    Start,
+    /// Waited for the target to drop a partially received request
+    Settled,
+    /// Dropped stale data, the target buffers are cleared next
+    Flushed,
+    /// Waiting for the replies to the requests checking the target
+    Clear,
+    Version,
+    Session,
+    /// Waiting for the reply to the recorded configuration request with the given index
+    Restore(usize),
+    Done,
+}
+
+impl Resync {
+    pub(crate) fn new() -> Self {
+        Resync {
+            state: ResyncState::Start,
+        }
+    }
+
+    pub(crate) fn step(&mut self, protocol: &mut Protocol, outcome: Outcome) -> Step {
+        match self.advance(protocol, outcome) {
+            Ok(step) => step,
+            Err(err) => {
+                self.state = ResyncState::Done;
+                Step::Done(Err(err))
+            }
+        }
+    }
+
+    fn advance(&mut self, protocol: &mut Protocol, outcome: Outcome) -> Result<Step> {
+        let received = outcome?;
+        let reply = || decode(received.as_deref().unwrap_or_default());
+
+        let (state, step) = match self.state {
+            ResyncState::Start => {
+                log::info!("Resynchronising link to target");
+                protocol.dirty = true;
+
+                let settle = Duration::from_millis(u64::from(INTERFRAME_TIMEOUT_MS) * 2);
+                (ResyncState::Settled, Step::Sleep(settle))
+            }
+            ResyncState::Settled => (ResyncState::Flushed, Step::Flush),
+            ResyncState::Flushed => (ResyncState::Clear, Step::Exchange(encode(&clear())?)),
+            ResyncState::Clear => {
+                expect_ok(reply()?)?;
+                (ResyncState::Version, Step::Exchange(encode(&version())?))
+            }
+            ResyncState::Version => {
+                expect_version(reply()?)?;
+                let req = encode(&session(protocol.session))?;
+                (ResyncState::Session, Step::Exchange(req))
+            }
+            ResyncState::Session => {
+                if !expect_session(reply()?, protocol.session)? || protocol.restoring {
+                    if !protocol.setup.is_empty() {
+                        log::warn!(
+                            "Target was reset, restoring {} configuration requests",
+                            protocol.setup.len()
+                        );
+                    }
+                    protocol.restoring = true;
+                }
+                self.restore(protocol, 0)
+            }
+            ResyncState::Restore(index) => {
+                expect_configured(reply()?)?;
+                self.restore(protocol, index + 1)
+            }
+            ResyncState::Done => (ResyncState::Done, Step::Done(Err(Error::UnexpectedReply))),
+        };
+
+        self.state = state;
+        Ok(step)
+    }
+
+    /// Replay the recorded configuration request `index` if the target was reset
+    fn restore(&self, protocol: &mut Protocol, index: usize) -> (ResyncState, Step) {
+        match protocol.setup.get(index) {
+            Some(req) if protocol.restoring => {
+                (ResyncState::Restore(index), Step::Exchange(req.clone()))
+            }
+            _ => {
+                protocol.restoring = false;
+                protocol.dirty = false;
+                (ResyncState::Done, Step::Done(Ok(())))
+            }
+        }
+    }
+}
+
+/// Check whether the bytes received so far contain a complete reply
+pub(crate) fn is_complete(buf: &[u8]) -> Result<bool> {
+    match from_bytes::<bridge_common::encoding::Reply>(buf) {
+        Ok(reply) => {
+            log::debug!("Received {} bytes containing {:?}", buf.len(), reply);
+            Ok(true)
+        }
+        Err(postcard::Error::DeserializeUnexpectedEnd) => Ok(false),
+        Err(err) => Err(Error::Decode(err)),
+    }
+}
@@ -0,0 +1,243 @@
+use embedded_hal::blocking::i2c;
+use std::io::{Read, Write};
+
+use crate::i2c::I2C;
+use crate::io::Error;
+
+/// Maximum number of data bytes in a block read or write
+pub const MAX_BLOCK_LENGTH: usize = 32;
+
+/// Calculate the packet error code, a CRC-8 with polynomial x^8 + x^2 + x + 1, over `bytes`
+///
+/// The address bytes of the transaction, including the read/write bit, are part of the data
+/// the code is calculated over.
+pub fn pec(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// SMBus transactions with devices like battery gauges or power management ICs
+///
+/// All transactions are built from the plain I2C requests of the bridge. With packet error
+/// checking enabled a PEC byte is appended to every write and expected after every read.
+pub struct SMBus<T> {
+    bus: I2C<T>,
+    pec: bool,
+}
+
+impl<T> SMBus<T>
+where
+    T: Read + Write,
+{
+    pub fn new(bus: I2C<T>) -> Self {
+        SMBus { bus, pec: false }
+    }
+
+    /// Enable or disable packet error checking
+    pub fn set_pec(&mut self, pec: bool) {
+        self.pec = pec;
+    }
+
+    pub fn into_inner(self) -> I2C<T> {
+        self.bus
+    }
+
+    /// Send just the address with the read/write bit set to `read`
+    pub fn quick_command(&mut self, addr: u8, read: bool) -> Result<(), Error> {
+        if read {
+            i2c::Read::read(&mut self.bus, addr, &mut [])
+        } else {
+            i2c::Write::write(&mut self.bus, addr, &[])
+        }
+    }
+
+    pub fn send_byte(&mut self, addr: u8, value: u8) -> Result<(), Error> {
+        self.write(addr, &[value])
+    }
+
+    pub fn receive_byte(&mut self, addr: u8) -> Result<u8, Error> {
+        let mut buffer = [0; 2];
+        let len = 1 + usize::from(self.pec);
+        i2c::Read::read(&mut self.bus, addr, &mut buffer[..len])?;
+
+        check(self.pec, &[addr << 1 | 1], &buffer[..len])?;
+        Ok(buffer[0])
+    }
+
+    pub fn write_byte(&mut self, addr: u8, cmd: u8, value: u8) -> Result<(), Error> {
+        self.write(addr, &[cmd, value])
+    }
+
+    pub fn read_byte(&mut self, addr: u8, cmd: u8) -> Result<u8, Error> {
+        let mut value = [0; 1];
+        self.read(addr, cmd, &mut value)?;
+
+        Ok(value[0])
+    }
+
+    /// Write a word, which is sent with the low byte first
+    pub fn write_word(&mut self, addr: u8, cmd: u8, value: u16) -> Result<(), Error> {
+        let [low, high] = value.to_le_bytes();
+        self.write(addr, &[cmd, low, high])
+    }
+
+    /// Read a word, which is sent with the low byte first
+    pub fn read_word(&mut self, addr: u8, cmd: u8) -> Result<u16, Error> {
+        let mut value = [0; 2];
+        self.read(addr, cmd, &mut value)?;
+
+        Ok(u16::from_le_bytes(value))
+    }
+
+    pub fn block_write(&mut self, addr: u8, cmd: u8, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MAX_BLOCK_LENGTH {
+            return Err(Error::BlockTooLong {
+                len: data.len(),
+                limit: MAX_BLOCK_LENGTH,
+            });
+        }
+
+        let mut bytes = vec![cmd, data.len() as u8];
+        bytes.extend_from_slice(data);
+        self.write(addr, &bytes)
+    }
+
+    /// Read a block of up to `MAX_BLOCK_LENGTH` bytes
+    ///
+    /// The length of the block is only known once its first byte has been received, so the
+    /// longest possible block is read and the surplus bytes are dropped.
+    pub fn block_read(&mut self, addr: u8, cmd: u8) -> Result<Vec<u8>, Error> {
+        let mut buffer = [0; 1 + MAX_BLOCK_LENGTH + 1];
+        i2c::WriteRead::write_read(&mut self.bus, addr, &[cmd], &mut buffer)?;
+
+        parse_block(self.pec, &[addr << 1, cmd, addr << 1 | 1], &buffer)
+    }
+
+    /// Write `bytes` followed by their PEC if enabled
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        let mut data = bytes.to_vec();
+        if self.pec {
+            data.push(pec(&[&[addr << 1], bytes].concat()));
+        }
+
+        i2c::Write::write(&mut self.bus, addr, &data)
+    }
+
+    /// Read `buffer.len()` bytes after sending the command code `cmd`
+    fn read(&mut self, addr: u8, cmd: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut received = vec![0; buffer.len() + usize::from(self.pec)];
+        i2c::WriteRead::write_read(&mut self.bus, addr, &[cmd], &mut received)?;
+
+        check(self.pec, &[addr << 1, cmd, addr << 1 | 1], &received)?;
+        buffer.copy_from_slice(&received[..buffer.len()]);
+
+        Ok(())
+    }
+}
+
+/// Verify the PEC in the last byte of `received` if `pec` is enabled
+///
+/// `header` holds the bytes of the transaction preceding the received ones.
+fn check(pec_enabled: bool, header: &[u8], received: &[u8]) -> Result<(), Error> {
+    if !pec_enabled {
+        return Ok(());
+    }
+
+    let (code, data) = received.split_last().ok_or(Error::UnexpectedReply)?;
+    if pec(&[header, data].concat()) == *code {
+        Ok(())
+    } else {
+        Err(Error::Pec)
+    }
+}
+
+/// Extract the data of a block from `buffer`, which starts with the length of the block
+fn parse_block(pec_enabled: bool, header: &[u8], buffer: &[u8]) -> Result<Vec<u8>, Error> {
+    let len = usize::from(*buffer.first().ok_or(Error::UnexpectedReply)?);
+    if len > MAX_BLOCK_LENGTH {
+        return Err(Error::BlockTooLong {
+            len,
+            limit: MAX_BLOCK_LENGTH,
+        });
+    }
+
+    let received = buffer
+        .get(..1 + len + usize::from(pec_enabled))
+        .ok_or(Error::UnexpectedReply)?;
+    check(pec_enabled, header, received)?;
+
+    Ok(received[1..1 + len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, parse_block, pec, MAX_BLOCK_LENGTH};
+    use crate::io::Error;
+
+    /// Header of a read of command `cmd` from a smart battery at address 0x0b
+    fn battery(cmd: u8) -> [u8; 3] {
+        [0x0b << 1, cmd, 0x0b << 1 | 1]
+    }
+
+    #[test]
+    fn pec_matches_crc8_smbus() {
+        assert_eq!(pec(&[]), 0);
+        assert_eq!(pec(b"123456789"), 0xf4);
+        // Voltage() of a smart battery reading 12340 mV
+        assert_eq!(pec(&[0x16, 0x09, 0x17, 0x34, 0x30]), 0x56);
+    }
+
+    #[test]
+    fn check_verifies_last_byte() {
+        assert!(check(true, &battery(0x09), &[0x34, 0x30, 0x56]).is_ok());
+        assert!(matches!(
+            check(true, &battery(0x09), &[0x34, 0x31, 0x56]),
+            Err(Error::Pec)
+        ));
+        assert!(matches!(
+            check(true, &battery(0x09), &[]),
+            Err(Error::UnexpectedReply)
+        ));
+        assert!(check(false, &battery(0x09), &[0x34, 0x31]).is_ok());
+    }
+
+    #[test]
+    fn parse_block_drops_surplus_bytes() {
+        // ManufacturerName() with the PEC and the padding of the longest possible block
+        let mut buffer = [0xff; 1 + MAX_BLOCK_LENGTH + 1];
+        buffer[..7].copy_from_slice(&[5, b'M', b'a', b'k', b'e', b'r', 0x0c]);
+
+        assert_eq!(
+            parse_block(true, &battery(0x20), &buffer).unwrap(),
+            b"Maker"
+        );
+        assert_eq!(
+            parse_block(false, &battery(0x20), &buffer).unwrap(),
+            b"Maker"
+        );
+
+        buffer[6] = 0x0d;
+        assert!(matches!(
+            parse_block(true, &battery(0x20), &buffer),
+            Err(Error::Pec)
+        ));
+    }
+
+    #[test]
+    fn parse_block_rejects_long_blocks() {
+        let mut buffer = [0; 1 + MAX_BLOCK_LENGTH + 1];
+        buffer[0] = MAX_BLOCK_LENGTH as u8 + 1;
+
+        assert!(matches!(
+            parse_block(false, &battery(0x20), &buffer),
+            Err(Error::BlockTooLong { len: 33, limit: 32 })
+        ));
+    }
+}
@@ -1,38 +1,69 @@
 use embedded_hal::blocking::spi;
+use embedded_hal::spi::{Mode, Phase, Polarity};
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 
-use crate::io::{send_clear, send_spi_init, send_spi_write};
+use crate::io::{
+    send_spi_device_init, send_spi_init, send_spi_target_init, send_spi_target_load,
+    send_spi_target_poll, send_spi_transaction, send_spi_transfer, send_spi_write, Error, Link,
+};
+use crate::time::Hertz;
 
 pub struct SPI<T> {
     ident: String,
-    channel: Arc<Mutex<Box<T>>>,
+    frequency: Hertz,
+    channel: Arc<Mutex<Box<Link<T>>>>,
 }
 
 impl<T> SPI<T>
 where
     T: Read + Write,
 {
+    /// Set up the bus `ident` on the given pins with an SCK frequency of at most `speed`
+    ///
+    /// Fails if the target does not support `speed` on this bus.
     pub fn new(
         ident: String,
         sck: String,
         miso: String,
         mosi: String,
-        speed: u32,
-        channel: Arc<Mutex<Box<T>>>,
-    ) -> Self {
-        send_clear(&mut *channel.lock().unwrap()).ok();
-        send_spi_init(
+        speed: Hertz,
+        channel: Arc<Mutex<Box<Link<T>>>>,
+    ) -> Result<Self, Error> {
+        let hz = send_spi_init(
             &mut *channel.lock().unwrap(),
             &ident,
             &sck,
             &miso,
             &mosi,
-            speed,
-        )
-        .ok();
+            speed.0,
+        )?;
+
+        Ok(SPI {
+            ident,
+            frequency: Hertz(hz),
+            channel,
+        })
+    }
+
+    /// The SCK frequency the target actually runs the bus at
+    pub fn frequency(&self) -> Hertz {
+        self.frequency
+    }
+
+    /// Attach a device selected by `cs` which expects the bus to be driven in `mode`
+    ///
+    /// Any number of devices can share the bus, each transaction is executed by the target with
+    /// the chip select asserted and the bus switched to the mode of the device.
+    pub fn device(&self, cs: String, mode: Mode) -> Result<SPIDevice<T>, Error> {
+        send_spi_device_init(&mut *self.channel.lock().unwrap(), &self.ident, &cs)?;
 
-        SPI { ident, channel }
+        Ok(SPIDevice {
+            ident: self.ident.clone(),
+            cs,
+            mode: mode_number(mode),
+            channel: self.channel.clone(),
+        })
     }
 }
 
@@ -40,9 +71,385 @@ impl<T> spi::Write<u8> for SPI<T>
 where
     T: Read + Write,
 {
-    type Error = std::io::Error;
+    type Error = Error;
 
     fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
         send_spi_write(&mut *self.channel.lock().unwrap(), &self.ident, bytes)
     }
 }
+
+impl<T> spi::Transfer<u8> for SPI<T>
+where
+    T: Read + Write,
+{
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        send_spi_transfer(&mut *self.channel.lock().unwrap(), &self.ident, words)?;
+        Ok(words)
+    }
+}
+
+/// A device on a shared SPI bus with its own chip select and mode, see `SPI::device`
+pub struct SPIDevice<T> {
+    ident: String,
+    cs: String,
+    mode: u8,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> SPIDevice<T>
+where
+    T: Read + Write,
+{
+    /// Clock out `words` with the device selected and replace them by the words clocked in
+    ///
+    /// The target asserts the chip select only for a single request, so a transaction is
+    /// limited to `MAX_WRITE_LENGTH` words and fails with `Error::TooLarge` otherwise.
+    pub fn transaction(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        send_spi_transaction(
+            &mut *self.channel.lock().unwrap(),
+            &self.ident,
+            &self.cs,
+            self.mode,
+            words,
+        )
+    }
+}
+
+impl<T> spi::Write<u8> for SPIDevice<T>
+where
+    T: Read + Write,
+{
+    type Error = Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.transaction(&mut bytes.to_vec())
+    }
+}
+
+impl<T> spi::Transfer<u8> for SPIDevice<T>
+where
+    T: Read + Write,
+{
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.transaction(words)?;
+        Ok(words)
+    }
+}
+
+/// An SPI bus on which the bridge acts as target, e.g. to test a bus controller
+///
+/// The target records the bytes received in every frame, i.e. while the chip select is
+/// asserted, and answers each frame with the bytes loaded by the host followed by 0xff. It is
+/// serviced by interrupts of the firmware, so the controller has to use a moderate clock
+/// frequency.
+pub struct SPITarget<T> {
+    ident: String,
+    channel: Arc<Mutex<Box<Link<T>>>>,
+}
+
+impl<T> SPITarget<T>
+where
+    T: Read + Write,
+{
+    /// Act as target selected by `cs` on the bus `ident`, expecting the controller to use `mode`
+    pub fn new(
+        ident: String,
+        sck: String,
+        miso: String,
+        mosi: String,
+        cs: String,
+        mode: Mode,
+        channel: Arc<Mutex<Box<Link<T>>>>,
+    ) -> Result<Self, Error> {
+        send_spi_target_init(
+            &mut *channel.lock().unwrap(),
+            &ident,
+            &sck,
+            &miso,
+            &mosi,
+            &cs,
+            mode_number(mode),
+        )?;
+
+        Ok(SPITarget { ident, channel })
+    }
+
+    /// Answer the following frames with `data` of up to `MAX_WRITE_LENGTH` bytes
+    pub fn load(&mut self, data: &[u8]) -> Result<(), Error> {
+        send_spi_target_load(&mut *self.channel.lock().unwrap(), &self.ident, data)
+    }
+
+    /// Fetch the frames received since the last call
+    ///
+    /// The target records only as many frames as fit into a single reply, so this should be
+    /// called regularly.
+    pub fn frames(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        send_spi_target_poll(&mut *self.channel.lock().unwrap(), &self.ident)
+    }
+}
+
+/// The number of an SPI mode as used on the wire
+pub(crate) fn mode_number(mode: Mode) -> u8 {
+    let cpol = mode.polarity == Polarity::IdleHigh;
+    let cpha = mode.phase == Phase::CaptureOnSecondTransition;
+    (cpol as u8) << 1 | cpha as u8
+}
+
+/// The words to clock out for a transfer into `read`, `write` padded with zeros as necessary
+#[cfg(feature = "embedded-hal-1")]
+pub(crate) fn padded_transfer(read: &mut [u8], write: &[u8]) -> Vec<u8> {
+    let mut words = write.to_vec();
+    words.resize(read.len().max(write.len()), 0);
+    words
+}
+
+/// Concatenate the words of all operations of a transaction into a single transfer
+///
+/// Delays cannot be executed by the target in the middle of a transfer and are rejected.
+#[cfg(feature = "embedded-hal-1")]
+pub(crate) fn gather(
+    operations: &[embedded_hal_1::spi::Operation<'_, u8>],
+) -> Result<Vec<u8>, Error> {
+    use embedded_hal_1::spi::Operation;
+
+    let mut words = Vec::new();
+
+    for operation in operations {
+        match operation {
+            Operation::Read(read) => words.resize(words.len() + read.len(), 0),
+            Operation::Write(write) => words.extend_from_slice(write),
+            Operation::TransferInPlace(write) => words.extend_from_slice(write),
+            Operation::Transfer(read, write) => {
+                words.extend_from_slice(write);
+                words.resize(words.len() + read.len().saturating_sub(write.len()), 0);
+            }
+            Operation::DelayNs(0) => (),
+            Operation::DelayNs(_) => return Err(Error::NotImplemented),
+        }
+    }
+
+    Ok(words)
+}
+
+/// Hand the words received during a transfer built by `gather` to the operations
+#[cfg(feature = "embedded-hal-1")]
+pub(crate) fn scatter(operations: &mut [embedded_hal_1::spi::Operation<'_, u8>], received: &[u8]) {
+    use embedded_hal_1::spi::Operation;
+
+    let mut received = received;
+
+    for operation in operations {
+        let (len, read) = match operation {
+            Operation::Read(read) | Operation::TransferInPlace(read) => (read.len(), Some(read)),
+            Operation::Write(write) => (write.len(), None),
+            Operation::Transfer(read, write) => (read.len().max(write.len()), Some(read)),
+            Operation::DelayNs(_) => (0, None),
+        };
+
+        let (words, rest) = received.split_at(len);
+        if let Some(read) = read {
+            read.copy_from_slice(&words[..read.len()]);
+        }
+        received = rest;
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+mod hal1 {
+    use embedded_hal_1::spi::{self, ErrorType, Operation, SpiBus};
+    use std::io::{Read, Write};
+
+    use super::{gather, padded_transfer, scatter, SPIDevice, SPI};
+    use crate::io::{send_spi_transfer, send_spi_write, Error};
+
+    impl<T> ErrorType for SPI<T> {
+        type Error = Error;
+    }
+
+    impl<T> SpiBus<u8> for SPI<T>
+    where
+        T: Read + Write,
+    {
+        fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+            words.iter_mut().for_each(|word| *word = 0);
+            send_spi_transfer(&mut *self.channel.lock().unwrap(), &self.ident, words)
+        }
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            send_spi_write(&mut *self.channel.lock().unwrap(), &self.ident, words)
+        }
+
+        fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+            let mut words = padded_transfer(read, write);
+            send_spi_transfer(&mut *self.channel.lock().unwrap(), &self.ident, &mut words)?;
+            read.copy_from_slice(&words[..read.len()]);
+            Ok(())
+        }
+
+        fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+            send_spi_transfer(&mut *self.channel.lock().unwrap(), &self.ident, words)
+        }
+
+        /// Every request completes on the target before it is acknowledged
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl<T> ErrorType for SPIDevice<T> {
+        type Error = Error;
+    }
+
+    /// All operations are executed as a single transfer, so delays other than 0 are not supported
+    impl<T> spi::SpiDevice<u8> for SPIDevice<T>
+    where
+        T: Read + Write,
+    {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+            let mut words = gather(operations)?;
+            SPIDevice::transaction(self, &mut words)?;
+            scatter(operations, &words);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "embedded-hal-1"))]
+mod tests {
+    use super::{gather, padded_transfer, scatter, SPI};
+    use crate::io::tests::{firmware, link, reply, MockPort, Reads};
+    use crate::io::Error;
+    use crate::time::Hertz;
+    use bridge_common::encoding::{Reply, Request};
+    use embedded_hal::spi::MODE_0;
+    use embedded_hal_1::spi::{Operation, SpiBus, SpiDevice};
+    use std::sync::{Arc, Mutex};
+
+    /// Answer transfers with the complement of the words clocked out
+    fn inverting(request: &Request) -> Reads {
+        match request {
+            Request::SPITransfer { data, .. } | Request::SPITransaction { data, .. } => {
+                let data: Vec<u8> = data.iter().map(|word| !word).collect();
+                reply(&Reply::Data { data: &data })
+            }
+            Request::SPIInit { speed, .. } => reply(&Reply::Frequency { hz: *speed }),
+            request => firmware(request),
+        }
+    }
+
+    type Responder = fn(&Request) -> Reads;
+
+    fn bus() -> SPI<MockPort<Responder>> {
+        let link = link(inverting as Responder);
+        let channel = Arc::new(Mutex::new(Box::new(link)));
+        let pins = ["a5", "a6", "a7"].map(String::from);
+        let [sck, miso, mosi] = pins;
+        SPI::new("spi1".into(), sck, miso, mosi, Hertz(1_000_000), channel).unwrap()
+    }
+
+    #[test]
+    fn gather_concatenates_operations() {
+        let (mut read, mut transfer, mut in_place) = ([0; 2], [0; 3], [5, 6]);
+        let operations = [
+            Operation::Read(&mut read),
+            Operation::Write(&[1, 2]),
+            Operation::Transfer(&mut transfer, &[3]),
+            Operation::DelayNs(0),
+            Operation::TransferInPlace(&mut in_place),
+        ];
+
+        assert_eq!(gather(&operations).unwrap(), [0, 0, 1, 2, 3, 0, 0, 5, 6]);
+    }
+
+    #[test]
+    fn gather_rejects_delays() {
+        let operations = [Operation::Write(&[1]), Operation::DelayNs(10)];
+        assert!(matches!(gather(&operations), Err(Error::NotImplemented)));
+    }
+
+    #[test]
+    fn scatter_hands_out_received_words() {
+        let (mut read, mut short, mut long, mut in_place) = ([0; 2], [0; 1], [0; 3], [0; 2]);
+        let mut operations = [
+            Operation::Read(&mut read),
+            Operation::Write(&[0; 2]),
+            Operation::Transfer(&mut short, &[0; 2]),
+            Operation::Transfer(&mut long, &[0]),
+            Operation::TransferInPlace(&mut in_place),
+        ];
+        let received: Vec<u8> = (1..=13).collect();
+
+        scatter(&mut operations, &received);
+
+        assert_eq!(read, [1, 2]);
+        assert_eq!(short, [5]);
+        assert_eq!(long, [7, 8, 9]);
+        assert_eq!(in_place, [10, 11]);
+    }
+
+    #[test]
+    fn padded_transfer_covers_longer_side() {
+        assert_eq!(padded_transfer(&mut [0; 3], &[1]), [1, 0, 0]);
+        assert_eq!(padded_transfer(&mut [0; 1], &[1, 2]), [1, 2]);
+    }
+
+    #[test]
+    fn bus_transfer_pads_and_truncates() {
+        let mut bus = bus();
+
+        let mut read = [0; 3];
+        SpiBus::transfer(&mut bus, &mut read, &[0x0f]).unwrap();
+        assert_eq!(read, [0xf0, 0xff, 0xff]);
+
+        let mut read = [0; 1];
+        SpiBus::transfer(&mut bus, &mut read, &[0x0f, 0x33]).unwrap();
+        assert_eq!(read, [0xf0]);
+
+        let mut read = [0x55; 2];
+        SpiBus::read(&mut bus, &mut read).unwrap();
+        assert_eq!(read, [0xff, 0xff]);
+    }
+
+    #[test]
+    fn device_transaction_is_a_single_request() {
+        let bus = bus();
+        let mut device = bus.device("b0".into(), MODE_0).unwrap();
+
+        let (mut id, mut status) = ([0; 2], [0x0f]);
+        SpiDevice::transaction(
+            &mut device,
+            &mut [
+                Operation::Write(&[0x9f]),
+                Operation::Read(&mut id),
+                Operation::TransferInPlace(&mut status),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(id, [0xff, 0xff]);
+        assert_eq!(status, [0xf0]);
+
+        let mut link = bus.channel.lock().unwrap();
+        assert_eq!(
+            link.get_mut().requests,
+            ["SPIInit", "SPIDeviceInit", "SPITransaction"]
+        );
+    }
+
+    #[test]
+    fn device_transaction_rejects_delays_before_sending() {
+        let bus = bus();
+        let mut device = bus.device("b0".into(), MODE_0).unwrap();
+
+        assert!(matches!(
+            SpiDevice::transaction(&mut device, &mut [Operation::DelayNs(10)]),
+            Err(Error::NotImplemented)
+        ));
+        let mut link = bus.channel.lock().unwrap();
+        assert_eq!(link.get_mut().requests, ["SPIInit", "SPIDeviceInit"]);
+    }
+}
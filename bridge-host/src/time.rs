@@ -0,0 +1,35 @@
+use std::fmt;
+
+/// A frequency in Hz, e.g. the clock speed of a bus
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Hertz(pub u32);
+
+impl fmt::Display for Hertz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz", self.0)
+    }
+}
+
+/// Extension trait to write frequencies like `400.khz()`
+///
+/// Meant for constants, the multiplication overflows for frequencies beyond `u32::MAX` Hz, so
+/// values read from user input are better converted with `checked_mul`.
+pub trait U32Ext {
+    fn hz(self) -> Hertz;
+    fn khz(self) -> Hertz;
+    fn mhz(self) -> Hertz;
+}
+
+impl U32Ext for u32 {
+    fn hz(self) -> Hertz {
+        Hertz(self)
+    }
+
+    fn khz(self) -> Hertz {
+        Hertz(self * 1_000)
+    }
+
+    fn mhz(self) -> Hertz {
+        Hertz(self * 1_000_000)
+    }
+}